@@ -43,90 +43,41 @@ static int audio_stream_write_callback(void *opaque, uint8_t *buf, int buf_size)
     return self->write_callback(self->write_opaque, buf, buf_size);
 }
 
-struct AudioStream *audio_stream_open(const struct AudioStreamOptions *options) {
-    int result;
-    char args[512];
-
-    struct AudioStream *self = malloc(sizeof(struct AudioStream));
-    memset(self, 0, sizeof(struct AudioStream));
-
-    // TODO track index
-
-    result = avformat_open_input(&self->in_ctx, options->path, NULL, NULL);
-    if (result < 0) {
-        lav_error("avformat_open_input", result);
-        goto fail;
-    }
-    
-    result = avformat_find_stream_info(self->in_ctx, NULL);
-    if (result < 0) {
-        lav_error("avformat_find_stream_info", result);
-        goto fail;
-    }
-
-    if (self->in_ctx->nb_streams <= (uint32_t)options->stream_index) {
-        lav_error("audio stream doesn't exist", 0);
-        goto fail;
-    }
-
-    self->in_stream = self->in_ctx->streams[options->stream_index];
+static int open_decoder(struct AudioStream *self) {
+    int result = 0;
 
     self->decoder = avcodec_find_decoder(self->in_stream->codecpar->codec_id);
     if (!self->decoder) {
         lav_error("avcodec_find_decoder", result);
-        goto fail;
+        return -1;
     }
 
     self->dec_ctx = avcodec_alloc_context3(self->decoder);
 
     if (avcodec_parameters_to_context(self->dec_ctx, self->in_stream->codecpar)) {
         lav_error("avcodec_parameters_to_context", result);
-        goto fail;
+        return -1;
     }
 
     if (avcodec_open2(self->dec_ctx, self->decoder, NULL)) {
         lav_error("avcodec_open2", result);
-        goto fail;
+        return -1;
     }
 
     if (!self->dec_ctx->channel_layout) {
         self->dec_ctx->channel_layout = av_get_default_channel_layout(self->dec_ctx->channels);
     }
 
-    if (options->start > 0) {
-        int64_t seek_pos = options->start / av_q2d(self->in_stream->time_base);
-        result = av_seek_frame(self->in_ctx, 0, seek_pos, 0);
-        if (result < 0) {
-            lav_error("av_seek_frame", result);
-            goto fail;
-        }
-    }
-
-    if (options->length > 0) {
-        self->end_pts = (options->start + options->length) / av_q2d(self->in_stream->time_base);
-    }
-
-    av_dump_format(self->in_ctx, 0, options->path, 0);
-
-    self->out_ctx = avformat_alloc_context();
-
-    self->out_ctx->oformat = av_guess_format(options->target_codec, NULL, NULL);
-    if (!self->out_ctx->oformat) {
-        lav_error("av_guess_format", 0);
-        goto fail;
-    }
-
-    self->out_stream = avformat_new_stream(self->out_ctx, NULL);
-    if (!self->out_stream) {
-        lav_error("avformat_new_stream", 0);
-    }
+    return 0;
+}
 
-    // TODO copy metadata
+static int open_encoder(struct AudioStream *self, const struct AudioStreamOptions *options) {
+    int result;
 
     self->encoder = avcodec_find_encoder(self->out_ctx->oformat->audio_codec);
     if (!self->encoder) {
         lav_error("avcodec_find_encoder", 0);
-        goto fail;
+        return -1;
     }
 
     self->enc_ctx = avcodec_alloc_context3(self->encoder);
@@ -135,36 +86,46 @@ struct AudioStream *audio_stream_open(const struct AudioStreamOptions *options)
         self->dec_ctx->sample_fmt,
         self->encoder->sample_fmts);
     self->enc_ctx->sample_rate = find_sample_rate(
-        self->dec_ctx->sample_rate,
+        options->sample_rate > 0 ? options->sample_rate : self->dec_ctx->sample_rate,
         self->encoder->supported_samplerates);
-    self->enc_ctx->channel_layout = self->dec_ctx->channel_layout;
+
+    if (options->channels > 0) {
+        // The aformat filter takes care of downmixing to this layout
+        self->enc_ctx->channel_layout = av_get_default_channel_layout(options->channels);
+    } else {
+        self->enc_ctx->channel_layout = self->dec_ctx->channel_layout;
+    }
+
     self->enc_ctx->channels = av_get_channel_layout_nb_channels(self->enc_ctx->channel_layout);
 
+    if (options->bitrate > 0) {
+        self->enc_ctx->bit_rate = options->bitrate;
+    }
+
+    if (self->out_ctx->oformat->flags & AVFMT_GLOBALHEADER) {
+        self->enc_ctx->flags |= AV_CODEC_FLAG_GLOBAL_HEADER;
+    }
+
     result = avcodec_open2(self->enc_ctx, self->encoder, NULL);
     if (result < 0) {
         lav_error("avcodec_open2", result);
-        goto fail;
+        return -1;
     }
 
     result = avcodec_parameters_from_context(self->out_stream->codecpar, self->enc_ctx);
     if (result < 0) {
         lav_error("avcodec_parameters_from_context", result);
-        goto fail;
+        return -1;
     }
 
     self->out_stream->time_base = self->enc_ctx->time_base;
 
-    uint8_t *out_iobuf = av_mallocz(4096);
-    self->out_ioctx = avio_alloc_context(
-        out_iobuf, 4096, 1, (void *)self, NULL, audio_stream_write_callback, NULL);
-    if (!self->out_ioctx) {
-        lav_error("avio_alloc_context", 0);
-        goto fail;
-    }
+    return 0;
+}
 
-    self->out_ctx->pb = self->out_ioctx;
-    
-    av_dump_format(self->out_ctx, 0, "", 1);
+static int open_filter_graph(struct AudioStream *self) {
+    int result;
+    char args[512];
 
     const AVFilter *abuffer = avfilter_get_by_name("abuffer");
     const AVFilter *aformat = avfilter_get_by_name("aformat");
@@ -172,17 +133,17 @@ struct AudioStream *audio_stream_open(const struct AudioStreamOptions *options)
 
     if (!abuffer) {
         lav_error("av filter abuffer not found", 0);
-        goto fail;
+        return -1;
     }
 
     if (!aformat) {
         lav_error("av filter aformat not found", 0);
-        goto fail;
+        return -1;
     }
 
     if (!abuffersink) {
         lav_error("av filter abuffersink not found", 0);
-        goto fail;
+        return -1;
     }
 
     self->filter_graph = avfilter_graph_alloc();
@@ -197,7 +158,7 @@ struct AudioStream *audio_stream_open(const struct AudioStreamOptions *options)
         &self->abuffer_ctx, abuffer, "in", args, NULL, self->filter_graph);
     if (result < 0) {
         lav_error("avfilter_graph_create_filter", result);
-        goto fail;
+        return -1;
     }
 
     snprintf(args,
@@ -211,36 +172,139 @@ struct AudioStream *audio_stream_open(const struct AudioStreamOptions *options)
         &self->aformat_ctx, aformat, NULL, args, NULL, self->filter_graph);
     if (result < 0) {
         lav_error("avfilter_graph_create_filter", result);
-        goto fail;
+        return -1;
     }
 
     result = avfilter_graph_create_filter(
         &self->abuffersink_ctx, abuffersink, "out", NULL, NULL, self->filter_graph);
     if (result < 0) {
         lav_error("avfilter_graph_create_filter", result);
-        goto fail;
+        return -1;
     }
 
     result = avfilter_link(self->abuffer_ctx, 0, self->aformat_ctx, 0);
     if (result < 0) {
         lav_error("avfilter_link", result);
-        goto fail;
+        return -1;
     }
 
     result = avfilter_link(self->aformat_ctx, 0, self->abuffersink_ctx, 0);
     if (result < 0) {
         lav_error("avfilter_link", result);
-        goto fail;
+        return -1;
     }
 
     result = avfilter_graph_config(self->filter_graph, NULL);
     if (result < 0) {
         lav_error("avfilter_graph_config", result);
-        goto fail;
+        return -1;
     }
 
     av_buffersink_set_frame_size(self->abuffersink_ctx, self->enc_ctx->frame_size);
 
+    return 0;
+}
+
+struct AudioStream *audio_stream_open(const struct AudioStreamOptions *options) {
+    int result;
+
+    struct AudioStream *self = malloc(sizeof(struct AudioStream));
+    memset(self, 0, sizeof(struct AudioStream));
+
+    self->copy = options->copy;
+    self->first_pts = AV_NOPTS_VALUE;
+    self->position = options->start;
+
+    // TODO track index
+
+    result = avformat_open_input(&self->in_ctx, options->path, NULL, NULL);
+    if (result < 0) {
+        lav_error("avformat_open_input", result);
+        goto fail;
+    }
+    
+    result = avformat_find_stream_info(self->in_ctx, NULL);
+    if (result < 0) {
+        lav_error("avformat_find_stream_info", result);
+        goto fail;
+    }
+
+    if (self->in_ctx->nb_streams <= (uint32_t)options->stream_index) {
+        lav_error("audio stream doesn't exist", 0);
+        goto fail;
+    }
+
+    self->in_stream = self->in_ctx->streams[options->stream_index];
+
+    // Packets are passed through as is when copying, no need to decode
+    if (!self->copy && open_decoder(self) < 0) {
+        goto fail;
+    }
+
+    if (options->start > 0) {
+        // Seek to the packet before the start, packets up to it are skipped so that streams
+        // split at the same position line up exactly
+        self->start_pts = options->start / av_q2d(self->in_stream->time_base);
+        result = av_seek_frame(
+            self->in_ctx, self->in_stream->index, self->start_pts, AVSEEK_FLAG_BACKWARD);
+        if (result < 0) {
+            lav_error("av_seek_frame", result);
+            goto fail;
+        }
+    }
+
+    if (options->length > 0) {
+        self->end_pts = (options->start + options->length) / av_q2d(self->in_stream->time_base);
+    }
+
+    av_dump_format(self->in_ctx, 0, options->path, 0);
+
+    self->out_ctx = avformat_alloc_context();
+
+    self->out_ctx->oformat = av_guess_format(options->target_format, NULL, NULL);
+    if (!self->out_ctx->oformat) {
+        lav_error("av_guess_format", 0);
+        goto fail;
+    }
+
+    self->out_stream = avformat_new_stream(self->out_ctx, NULL);
+    if (!self->out_stream) {
+        lav_error("avformat_new_stream", 0);
+        goto fail;
+    }
+
+    // TODO copy metadata
+
+    if (self->copy) {
+        result = avcodec_parameters_copy(self->out_stream->codecpar, self->in_stream->codecpar);
+        if (result < 0) {
+            lav_error("avcodec_parameters_copy", result);
+            goto fail;
+        }
+
+        // The tag is container specific, let the muxer choose
+        self->out_stream->codecpar->codec_tag = 0;
+        self->out_stream->time_base = self->in_stream->time_base;
+    } else if (open_encoder(self, options) < 0) {
+        goto fail;
+    }
+
+    uint8_t *out_iobuf = av_mallocz(4096);
+    self->out_ioctx = avio_alloc_context(
+        out_iobuf, 4096, 1, (void *)self, NULL, audio_stream_write_callback, NULL);
+    if (!self->out_ioctx) {
+        lav_error("avio_alloc_context", 0);
+        goto fail;
+    }
+
+    self->out_ctx->pb = self->out_ioctx;
+    
+    av_dump_format(self->out_ctx, 0, "", 1);
+
+    if (!self->copy && open_filter_graph(self) < 0) {
+        goto fail;
+    }
+
     return self;
 
 fail:
@@ -253,6 +317,13 @@ fail:
 #define STREAM_AGAIN 1
 #define STREAM_OK 2
 
+// Whether the packet is before the start position, seeking may land earlier
+static int skip_packet(struct AudioStream *self, AVPacket *in_packet) {
+    return self->start_pts > 0
+        && in_packet->pts != AV_NOPTS_VALUE
+        && in_packet->pts < self->start_pts;
+}
+
 static int demux_decode(struct AudioStream *self, AVPacket *in_packet) {
     int result = av_read_frame(self->in_ctx, in_packet);
 
@@ -267,13 +338,21 @@ static int demux_decode(struct AudioStream *self, AVPacket *in_packet) {
         return STREAM_AGAIN;
     }
 
-    av_packet_rescale_ts(in_packet, self->in_stream->time_base, self->dec_ctx->time_base);
-
-    if (self->end_pts > 0 && in_packet->pts > self->end_pts) {
+    if (self->end_pts > 0 && in_packet->pts >= self->end_pts) {
         // Reached track end
         goto eof;
     }
 
+    if (skip_packet(self, in_packet)) {
+        return STREAM_AGAIN;
+    }
+
+    av_packet_rescale_ts(in_packet, self->in_stream->time_base, self->dec_ctx->time_base);
+
+    if (in_packet->pts != AV_NOPTS_VALUE) {
+        self->position = in_packet->pts * av_q2d(self->dec_ctx->time_base);
+    }
+
     result = avcodec_send_packet(self->dec_ctx, in_packet);
     if (result < 0) {
         lav_error("avcodec_send_packet", result);
@@ -376,6 +455,75 @@ eof:
     return STREAM_EOF;
 }
 
+static int demux_remux(struct AudioStream *self, AVPacket *in_packet) {
+    int result = av_read_frame(self->in_ctx, in_packet);
+
+    if (result == AVERROR_EOF) {
+        return STREAM_EOF;
+    } else if (result < 0) {
+        lav_error("av_read_frame", result);
+        return STREAM_ERROR;
+    }
+
+    if (in_packet->stream_index != self->in_stream->index) {
+        return STREAM_AGAIN;
+    }
+
+    if (self->end_pts > 0 && in_packet->pts >= self->end_pts) {
+        // Reached track end
+        return STREAM_EOF;
+    }
+
+    if (skip_packet(self, in_packet)) {
+        return STREAM_AGAIN;
+    }
+
+    if (in_packet->pts != AV_NOPTS_VALUE) {
+        self->position = in_packet->pts * av_q2d(self->in_stream->time_base);
+    }
+
+    // Make the output start from zero when serving a range from the middle of the file
+    if (self->first_pts == AV_NOPTS_VALUE) {
+        self->first_pts = in_packet->pts != AV_NOPTS_VALUE ? in_packet->pts : 0;
+    }
+
+    if (in_packet->pts != AV_NOPTS_VALUE) {
+        in_packet->pts -= self->first_pts;
+    }
+
+    if (in_packet->dts != AV_NOPTS_VALUE) {
+        in_packet->dts -= self->first_pts;
+    }
+
+    in_packet->stream_index = self->out_stream->index;
+    in_packet->pos = -1;
+
+    av_packet_rescale_ts(in_packet, self->in_stream->time_base, self->out_stream->time_base);
+
+    result = av_interleaved_write_frame(self->out_ctx, in_packet);
+    if (result < 0) {
+        lav_error("av_interleaved_write_frame", result);
+        return STREAM_ERROR;
+    }
+
+    return STREAM_OK;
+}
+
+static int write_header(struct AudioStream *self) {
+    AVDictionary *opts = NULL;
+
+    // MP4 can only be streamed when fragmented, as the output isn't seekable
+    const char *name = self->out_ctx->oformat->name;
+    if (!strcmp(name, "mp4") || !strcmp(name, "ipod") || !strcmp(name, "mov")) {
+        av_dict_set(&opts, "movflags", "frag_keyframe+empty_moov+default_base_moof", 0);
+    }
+
+    int result = avformat_write_header(self->out_ctx, &opts);
+    av_dict_free(&opts);
+
+    return result;
+}
+
 static int internal_next(
     struct AudioStream *self,
     AVPacket *in_packet,
@@ -390,7 +538,7 @@ static int internal_next(
     }
 
     if (!self->started) {
-        result = avformat_write_header(self->out_ctx, NULL);
+        result = write_header(self);
         if (result < 0) {
             lav_error("avformat_write_header", result);
             return result;
@@ -401,6 +549,19 @@ static int internal_next(
         return STREAM_OK;
     }
 
+    if (self->copy) {
+        do {
+            result = demux_remux(self, in_packet);
+            av_packet_unref(in_packet);
+        } while (result == STREAM_AGAIN);
+
+        if (result == STREAM_OK) {
+            return STREAM_OK;
+        }
+
+        goto finish;
+    }
+
     while (1) {
         result = encode_mux(self, enc_packet);
         av_packet_unref(enc_packet);
@@ -482,6 +643,10 @@ int audio_stream_next(
     return result;
 }
 
+double audio_stream_position(struct AudioStream *self) {
+    return self->position;
+}
+
 void audio_stream_close(struct AudioStream *self) {
     avfilter_graph_free(&self->filter_graph);
     if (self->out_ioctx) {
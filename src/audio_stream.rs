@@ -7,6 +7,7 @@ use std::path::Path;
 use bytes::{BytesMut, buf::ext::BufExt};
 use tokio::sync::mpsc::Sender;
 
+use crate::config::Profile;
 use crate::musicd_c;
 
 extern "C" fn stream_c_callback(opaque: *const c_void, data: *const u8, len: c_int) -> c_int {
@@ -18,6 +19,107 @@ extern "C" fn stream_c_callback(opaque: *const c_void, data: *const u8, len: c_i
     closure(slice) as i32
 }
 
+pub struct Codec {
+    pub name: &'static str,
+    /// FFmpeg muxer, `None` if the source stream is passed through without re-encoding
+    pub format: Option<&'static str>,
+    pub content_type: &'static str,
+    /// Default bitrate in bits per second, zero for lossless output
+    pub bitrate: i32,
+}
+
+pub static CODECS: &[Codec] = &[
+    Codec {
+        name: "mp3",
+        format: Some("mp3"),
+        content_type: "audio/mpeg",
+        bitrate: 192000,
+    },
+    Codec {
+        name: "opus",
+        format: Some("opus"),
+        content_type: "audio/ogg",
+        bitrate: 128000,
+    },
+    Codec {
+        name: "ogg",
+        format: Some("ogg"),
+        content_type: "audio/ogg",
+        bitrate: 192000,
+    },
+    Codec {
+        name: "aac",
+        format: Some("adts"),
+        content_type: "audio/aac",
+        bitrate: 192000,
+    },
+    Codec {
+        name: "m4a",
+        format: Some("mp4"),
+        content_type: "audio/mp4",
+        bitrate: 192000,
+    },
+    Codec {
+        name: "flac",
+        format: Some("flac"),
+        content_type: "audio/flac",
+        bitrate: 0,
+    },
+    Codec {
+        name: "wav",
+        format: Some("wav"),
+        content_type: "audio/wav",
+        bitrate: 0,
+    },
+    Codec {
+        name: "raw",
+        format: None,
+        content_type: "",
+        bitrate: 0,
+    },
+    Codec {
+        name: "original",
+        format: None,
+        content_type: "",
+        bitrate: 0,
+    },
+];
+
+/// Muxer and content type used when passing through a source stream, by file extension
+static PASSTHROUGH_FORMATS: &[(&str, &str, &str)] = &[
+    ("mp3", "mp3", "audio/mpeg"),
+    ("flac", "flac", "audio/flac"),
+    ("ogg", "ogg", "audio/ogg"),
+    ("oga", "ogg", "audio/ogg"),
+    ("opus", "ogg", "audio/ogg"),
+    ("m4a", "ipod", "audio/mp4"),
+    ("mp4", "mp4", "audio/mp4"),
+    ("aac", "adts", "audio/aac"),
+    ("wav", "wav", "audio/wav"),
+];
+
+pub fn find_codec(name: &str) -> Option<&'static Codec> {
+    CODECS.iter().find(|c| c.name == name)
+}
+
+/// Used for passing through anything not in `PASSTHROUGH_FORMATS`, as Matroska can hold
+/// almost any codec
+pub static PASSTHROUGH_FALLBACK: (&str, &str) = ("matroska", "audio/x-matroska");
+
+/// Returns the muxer and content type for passing through the audio of `path`, if its file
+/// extension is known.
+pub fn passthrough_format(path: &Path) -> Option<(&'static str, &'static str)> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())?;
+
+    PASSTHROUGH_FORMATS
+        .iter()
+        .find(|f| f.0 == extension)
+        .map(|f| (f.1, f.2))
+}
+
 pub struct AudioStream {
     stream: *const c_void,
 }
@@ -39,10 +141,16 @@ impl AudioStream {
         track_index: i32,
         start: f64,
         length: f64,
-        target_codec: &str,
+        profile: &Profile,
     ) -> Option<AudioStream> {
+        let codec = find_codec(&profile.codec)?;
+
         let tmp_path = CString::new(path.as_os_str().as_bytes()).unwrap();
-        let tmp_codec = CString::new(target_codec).unwrap();
+        let tmp_format = CString::new(match codec.format {
+            Some(format) => format,
+            None => passthrough_format(path).unwrap_or(PASSTHROUGH_FALLBACK).0,
+        })
+        .unwrap();
 
         let config = musicd_c::AudioStreamOptions {
             path: tmp_path.as_ptr(),
@@ -50,7 +158,11 @@ impl AudioStream {
             track_index,
             start,
             length,
-            target_codec: tmp_codec.as_ptr(),
+            target_format: tmp_format.as_ptr(),
+            copy: codec.format.is_none() as i32,
+            bitrate: profile.bitrate.unwrap_or(0),
+            sample_rate: profile.sample_rate.unwrap_or(0),
+            channels: profile.channels.unwrap_or(0),
         };
 
         let result = unsafe { musicd_c::audio_stream_open(&config) };
@@ -75,10 +187,24 @@ impl AudioStream {
         }
     }
 
-    pub async fn execute(
+    /// Position of the last packet read from the source in seconds, from the beginning of
+    /// the file.
+    pub fn position(&self) -> f64 {
+        unsafe { musicd_c::audio_stream_position(self.stream) }
+    }
+
+    /// Feeds the encoded stream to `sender` until it ends or the receiver goes away. If `length`
+    /// is given, exactly that many bytes are sent: the output is cut off or padded with zeros,
+    /// which is needed when the length has been announced beforehand. `progress` is called
+    /// with the position after each chunk has been taken by the receiver.
+    pub async fn execute<F>(
         mut self,
         mut sender: Sender<Result<Vec<u8>, Box<dyn StdError + Send + Sync>>>,
-    ) {
+        mut length: Option<usize>,
+        mut progress: F,
+    ) where
+        F: FnMut(f64),
+    {
         loop {
             let mut buf = BytesMut::new();
 
@@ -91,14 +217,35 @@ impl AudioStream {
                 });
             }
 
+            if let Some(remaining) = length.as_mut() {
+                if buf.len() >= *remaining {
+                    buf.truncate(*remaining);
+                    result = false;
+                } else if !result {
+                    trace!(
+                        "audio stream ended {} bytes short, padding",
+                        *remaining - buf.len()
+                    );
+                    buf.resize(*remaining, 0);
+                }
+
+                *remaining -= buf.len();
+            }
+
             trace!("read {} bytes from audio stream, feeding", buf.len());
 
+            let len = buf.len();
             let result = if result {
-                let len = buf.len();
                 sender.send(Ok(buf.take(len).into_inner().to_vec())).await
             } else {
                 debug!("audio stream finished, flushing channel");
-                let _ = sender.send(Ok(vec![])).await;
+                if sender
+                    .send(Ok(buf.take(len).into_inner().to_vec()))
+                    .await
+                    .is_ok()
+                {
+                    progress(self.position());
+                }
                 break;
             };
 
@@ -106,6 +253,8 @@ impl AudioStream {
                 debug!("channel disconnected, stopping audio stream");
                 break;
             }
+
+            progress(self.position());
         }
     }
 }
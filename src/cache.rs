@@ -35,7 +35,12 @@ impl CacheSource {
             );
 
             let mut conn = Self::get_connection(&db_path)?;
-            if !db_meta::ensure_schema(&mut conn, schema::CACHE_SCHEMA)? {
+            if !db_meta::ensure_schema(
+                &mut conn,
+                db_path,
+                schema::CACHE_SCHEMA,
+                schema::CACHE_MIGRATIONS,
+            )? {
                 return Ok(None);
             }
         } else {
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// Optional settings read from the TOML file given with `--config`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Transcoding profiles, `[[profile]]` in the file
+    #[serde(rename = "profile")]
+    pub profiles: Vec<Profile>,
+    /// Percentage of a track that has to be streamed from `/api/audio_stream` for it to be
+    /// recorded as played. Plays are only recorded with `/api/track_play` if unset.
+    pub play_percentage: Option<f64>,
+    /// Lyrics web services, `[[lyrics_provider]]` in the file, tried in order after local
+    /// files and tags
+    #[serde(rename = "lyrics_provider")]
+    pub lyrics_providers: Vec<LyricsProviderConfig>,
+    /// Album covers are picked from the images of an album by matching their names against
+    /// these SQL LIKE patterns in order, falling back to built-in patterns if unset. Can be
+    /// changed at runtime with `/api/settings/cover_patterns`.
+    pub cover_patterns: Option<Vec<String>>,
+    /// Number of distinct track artists from which an album without an album artist tag is
+    /// considered a compilation and credited to "Various Artists", 4 if unset
+    pub compilation_artists: Option<i64>,
+    /// Artist names by alternative names, `[artist_aliases]` in the file. Credits matching an
+    /// alias aren't split into several artists. Joint credits like "A & B" are only split if
+    /// each part is a known artist, either in the index or here.
+    pub artist_aliases: HashMap<String, String>,
+    /// URL the server is reached at, like `https://music.example.com`, for absolute links in
+    /// the metadata of share pages. Taken from the `Host` header of the request if unset.
+    pub public_url: Option<String>,
+}
+
+/// Named set of encoder settings selectable with `profile=` on `/api/audio_stream`. Unset
+/// values are taken from the codec defaults or the source stream.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub codec: String,
+    /// Bits per second
+    pub bitrate: Option<i32>,
+    pub sample_rate: Option<i32>,
+    /// Number of channels to downmix to
+    pub channels: Option<i32>,
+}
+
+impl Profile {
+    /// Checks that the encoder settings given are positive.
+    pub fn validate(&self) -> Result<(), String> {
+        let settings = [
+            ("bitrate", self.bitrate),
+            ("sample_rate", self.sample_rate),
+            ("channels", self.channels),
+        ];
+
+        for (key, value) in settings.iter() {
+            if matches!(value, Some(v) if *v <= 0) {
+                return Err(format!("profile '{}': {} must be positive", self.name, key));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Lyrics web service queried with a plain GET request. The lyrics are picked from a JSON
+/// response with `json_pointer`, or from between `begin` and `end` in any other response.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LyricsProviderConfig {
+    pub name: String,
+    /// `{artist}`, `{title}` and `{album}` are replaced with the URL encoded track values
+    pub url: String,
+    /// As in `/result/lyrics`
+    pub json_pointer: Option<String>,
+    pub begin: Option<String>,
+    pub end: Option<String>,
+    /// Converts extracted HTML to plain text
+    #[serde(default)]
+    pub html: bool,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, Box<dyn StdError>> {
+        info!("loading '{}'", path.to_string_lossy());
+
+        let text = std::fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&text)?;
+
+        for profile in config.profiles.iter() {
+            profile.validate()?;
+        }
+
+        debug!("{:?}", config);
+
+        Ok(config)
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+}
+
+#[test]
+fn test_parse_profiles() {
+    let config: Config = toml::from_str(
+        "
+[[profile]]
+name = \"mobile-64k-opus\"
+codec = \"opus\"
+bitrate = 64000
+
+[[profile]]
+name = \"mono-voice\"
+codec = \"mp3\"
+bitrate = 48000
+sample_rate = 22050
+channels = 1
+",
+    )
+    .unwrap();
+
+    assert_eq!(config.profiles.len(), 2);
+    assert_eq!(
+        config.profile("mobile-64k-opus").unwrap().bitrate,
+        Some(64000)
+    );
+    assert_eq!(config.profile("mono-voice").unwrap().channels, Some(1));
+    assert!(config.profile("hifi-flac").is_none());
+    assert!(config.profiles.iter().all(|p| p.validate().is_ok()));
+
+    let config: Config = toml::from_str(
+        "
+[[profile]]
+name = \"broken\"
+codec = \"mp3\"
+bitrate = 0
+",
+    )
+    .unwrap();
+
+    assert!(config.profile("broken").unwrap().validate().is_err());
+}
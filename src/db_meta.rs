@@ -1,45 +1,189 @@
+use std::path::{Path, PathBuf};
+
 use rusqlite::OptionalExtension;
-use rusqlite::{Connection, Result, NO_PARAMS};
+use rusqlite::{Connection, OpenFlags, Result, NO_PARAMS};
+
+use crate::schema::{self, Migration};
+
+fn schema_version(conn: &Connection) -> Result<Option<u32>> {
+    conn.query_row(
+        "SELECT value FROM Musicd WHERE key = 'schema'",
+        NO_PARAMS,
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+fn pending_migrations(schema_version: u32, migrations: &[Migration]) -> Vec<&Migration> {
+    migrations
+        .iter()
+        .filter(|m| m.version > schema_version && m.version <= schema::SCHEMA_VERSION)
+        .collect()
+}
 
-use crate::schema;
+fn backup_path(db_path: &Path, schema_version: u32) -> PathBuf {
+    let mut file_name = db_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".v{}.bak", schema_version));
 
-pub fn ensure_schema(conn: &mut Connection, schema: &str) -> Result<bool> {
+    db_path.with_file_name(file_name)
+}
+
+/// Creates the schema in a new database or migrates an older one, backing it up first.
+/// Returns false if the database can't be used.
+pub fn ensure_schema(
+    conn: &mut Connection,
+    db_path: &Path,
+    schema: &str,
+    migrations: &[Migration],
+) -> Result<bool> {
     trace!("trying to get schema version");
 
     conn.execute_batch(schema::META_SCHEMA)?;
 
-    let schema_version: Option<u32> = conn
-        .query_row(
-            "SELECT value FROM Musicd WHERE key = 'schema'",
-            NO_PARAMS,
-            |row| row.get(0),
-        )
-        .optional()?;
-
-    if let Some(schema_version) = schema_version {
-        if schema_version != schema::SCHEMA_VERSION {
-            error!(
-                "unsupported schema version: got {}, expected {}",
-                schema_version,
-                schema::SCHEMA_VERSION
-            );
-            return Ok(false);
+    let schema_version = match schema_version(conn)? {
+        Some(v) => v,
+        None => {
+            debug!("schema meta not present, creating schema");
+
+            let tran = conn.transaction()?;
+
+            tran.execute(
+                "INSERT INTO Musicd (key, value) VALUES ('schema', ?)",
+                [schema::SCHEMA_VERSION],
+            )?;
+            tran.execute_batch(schema)?;
+
+            tran.commit()?;
+
+            return Ok(true);
         }
+    };
 
+    if schema_version == schema::SCHEMA_VERSION {
         debug!("schema version up-to-date, doing nothing");
-    } else {
-        debug!("schema meta not present, creating schema");
+        return Ok(true);
+    }
+
+    if schema_version > schema::SCHEMA_VERSION {
+        error!(
+            "unsupported schema version: got {}, expected {} or older",
+            schema_version,
+            schema::SCHEMA_VERSION
+        );
+        return Ok(false);
+    }
+
+    let backup_path = backup_path(db_path, schema_version);
+
+    info!(
+        "backing up '{}' to '{}'",
+        db_path.to_string_lossy(),
+        backup_path.to_string_lossy()
+    );
+
+    // Copy everything from the write-ahead log to the file first
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", NO_PARAMS, |_| Ok(()))?;
+
+    if let Err(e) = std::fs::copy(db_path, &backup_path) {
+        error!(
+            "can't back up '{}', not migrating: {}",
+            db_path.to_string_lossy(),
+            e
+        );
+        return Ok(false);
+    }
 
-        let tran = conn.transaction()?;
+    let tran = conn.transaction()?;
 
-        tran.execute(
-            "INSERT INTO Musicd (key, value) VALUES ('schema', ?)",
-            &[schema::SCHEMA_VERSION],
-        )?;
-        tran.execute_batch(schema)?;
+    for migration in pending_migrations(schema_version, migrations) {
+        info!(
+            "migrating to schema version {}: {}",
+            migration.version, migration.description
+        );
 
-        tran.commit()?;
+        tran.execute_batch(migration.sql)?;
     }
 
+    tran.execute(
+        "UPDATE Musicd SET value = ? WHERE key = 'schema'",
+        [schema::SCHEMA_VERSION],
+    )?;
+
+    tran.commit()?;
+
+    info!(
+        "migrated '{}' from schema version {} to {}",
+        db_path.to_string_lossy(),
+        schema_version,
+        schema::SCHEMA_VERSION
+    );
+
     Ok(true)
 }
+
+/// Prints the migration steps `ensure_schema` would run, without modifying anything.
+pub fn print_pending_migrations(db_path: &Path, migrations: &[Migration]) -> Result<()> {
+    let name = db_path.to_string_lossy();
+
+    if !db_path.exists() {
+        println!("{}: doesn't exist, will be created", name);
+        return Ok(());
+    }
+
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let has_meta: bool = conn.query_row(
+        "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'Musicd'",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+
+    let schema_version = if has_meta {
+        schema_version(&conn)?
+    } else {
+        None
+    };
+
+    match schema_version {
+        None => println!("{}: no schema, will be created", name),
+        Some(v) if v == schema::SCHEMA_VERSION => println!("{}: up-to-date", name),
+        Some(v) if v > schema::SCHEMA_VERSION => println!(
+            "{}: unsupported schema version {}, expected {} or older",
+            name,
+            v,
+            schema::SCHEMA_VERSION
+        ),
+        Some(v) => {
+            println!(
+                "{}: schema version {}, migrating to {}, backup in '{}'",
+                name,
+                v,
+                schema::SCHEMA_VERSION,
+                backup_path(db_path, v).to_string_lossy()
+            );
+
+            for migration in pending_migrations(v, migrations) {
+                println!("  {}: {}", migration.version, migration.description);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_migrations_ordered() {
+    for migrations in &[
+        schema::CACHE_MIGRATIONS,
+        schema::INDEX_MIGRATIONS,
+        schema::STORE_MIGRATIONS,
+    ] {
+        for pair in migrations.windows(2) {
+            assert!(pair[0].version < pair[1].version);
+        }
+
+        assert!(migrations
+            .iter()
+            .all(|m| m.version > 1 && m.version <= schema::SCHEMA_VERSION));
+    }
+}
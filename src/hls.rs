@@ -0,0 +1,163 @@
+use std::path::Path;
+
+use crate::audio_stream::AudioStream;
+use crate::config::Profile;
+
+/// Length of segments in seconds, the last segment of a track being shorter
+pub const SEGMENT_DURATION: f64 = 6.0;
+
+/// Codecs segments can be encoded with, as HLS packed audio
+pub static SEGMENT_CODECS: &[&str] = &["aac", "mp3"];
+
+/// Default codec for segments, if the request doesn't name a codec or a profile
+pub static DEFAULT_SEGMENT_CODEC: &str = "aac";
+
+pub fn segment_count(length: f64) -> usize {
+    ((length / SEGMENT_DURATION).ceil() as usize).max(1)
+}
+
+/// Start and length of segment `index` in seconds from the beginning of a track of `length`
+/// seconds, `None` if the track doesn't have that many segments.
+pub fn segment_range(length: f64, index: usize) -> Option<(f64, f64)> {
+    if index >= segment_count(length) {
+        return None;
+    }
+
+    let start = index as f64 * SEGMENT_DURATION;
+
+    Some((start, SEGMENT_DURATION.min(length - start)))
+}
+
+/// Creates a VOD media playlist for a track of `length` seconds. `uri` returns the URI of
+/// segment at an index, absolute or relative to the playlist.
+pub fn playlist<F>(length: f64, uri: F) -> String
+where
+    F: Fn(usize) -> String,
+{
+    let mut playlist = format!(
+        "#EXTM3U\n\
+         #EXT-X-VERSION:3\n\
+         #EXT-X-PLAYLIST-TYPE:VOD\n\
+         #EXT-X-TARGETDURATION:{}\n\
+         #EXT-X-MEDIA-SEQUENCE:0\n",
+        SEGMENT_DURATION.ceil()
+    );
+
+    for index in 0..segment_count(length) {
+        let (_, duration) = segment_range(length, index).unwrap();
+        playlist.push_str(&format!("#EXTINF:{:.3},\n{}\n", duration, uri(index)));
+    }
+
+    playlist.push_str("#EXT-X-ENDLIST\n");
+
+    playlist
+}
+
+/// ID3 tag with the timestamp of a packed audio segment, which HLS expects at the start of
+/// each segment to place it on the timeline.
+pub fn timestamp_tag(start: f64) -> Vec<u8> {
+    static OWNER: &[u8] = b"com.apple.streaming.transportStreamTimestamp\0";
+
+    // 33-bit MPEG-2 timestamp with a 90 kHz clock
+    let timestamp = (start * 90000f64).round() as u64 & 0x1_ffff_ffff;
+
+    let frame_size = OWNER.len() + 8;
+    let tag_size = 10 + frame_size;
+
+    let mut tag = Vec::with_capacity(10 + tag_size);
+
+    tag.extend_from_slice(b"ID3\x04\x00\x00");
+    tag.extend_from_slice(&synchsafe(tag_size));
+    tag.extend_from_slice(b"PRIV");
+    tag.extend_from_slice(&synchsafe(frame_size));
+    tag.extend_from_slice(&[0, 0]);
+    tag.extend_from_slice(OWNER);
+    tag.extend_from_slice(&timestamp.to_be_bytes());
+
+    tag
+}
+
+/// ID3v2.4 sizes use 7 bits per byte
+fn synchsafe(size: usize) -> [u8; 4] {
+    [
+        (size >> 21 & 0x7f) as u8,
+        (size >> 14 & 0x7f) as u8,
+        (size >> 7 & 0x7f) as u8,
+        (size & 0x7f) as u8,
+    ]
+}
+
+/// Encodes segment `index` of a track starting at `track_start` seconds in the file.
+/// Returns `None` if the track doesn't have the segment or it can't be encoded.
+pub fn encode_segment(
+    path: &Path,
+    stream_index: i32,
+    track_index: i32,
+    track_start: f64,
+    track_length: f64,
+    index: usize,
+    profile: &Profile,
+) -> Option<Vec<u8>> {
+    let (start, length) = segment_range(track_length, index)?;
+
+    let mut audio_stream = AudioStream::open(
+        path,
+        stream_index,
+        track_index,
+        track_start + start,
+        length,
+        profile,
+    )?;
+
+    let mut data = timestamp_tag(start);
+    let tag_len = data.len();
+
+    while audio_stream.next(|chunk| {
+        data.extend_from_slice(chunk);
+        chunk.len()
+    }) {}
+
+    // Errors can't be told apart from the end of the stream, but nothing is output then
+    if data.len() == tag_len {
+        return None;
+    }
+
+    Some(data)
+}
+
+#[test]
+fn test_playlist() {
+    assert_eq!(segment_count(0f64), 1);
+    assert_eq!(segment_count(12f64), 2);
+    assert_eq!(segment_count(12.5), 3);
+    assert_eq!(segment_range(12.5, 2), Some((12f64, 0.5)));
+    assert_eq!(segment_range(12.5, 3), None);
+
+    let playlist = playlist(12.5, |index| format!("segment?index={}", index));
+    let lines: Vec<&str> = playlist.lines().collect();
+
+    assert_eq!(lines[0], "#EXTM3U");
+    assert!(lines.contains(&"#EXT-X-TARGETDURATION:6"));
+    assert_eq!(
+        &lines[lines.len() - 7..],
+        &[
+            "#EXTINF:6.000,",
+            "segment?index=0",
+            "#EXTINF:6.000,",
+            "segment?index=1",
+            "#EXTINF:0.500,",
+            "segment?index=2",
+            "#EXT-X-ENDLIST"
+        ]
+    );
+}
+
+#[test]
+fn test_timestamp_tag() {
+    let tag = timestamp_tag(6f64);
+
+    assert_eq!(tag.len(), 73);
+    assert_eq!(&tag[..10], b"ID3\x04\x00\x00\x00\x00\x00\x3f");
+    assert_eq!(&tag[10..20], b"PRIV\x00\x00\x00\x35\x00\x00");
+    assert_eq!(&tag[65..], &540000u64.to_be_bytes());
+}
@@ -1,7 +1,9 @@
 use std::collections::HashMap;
 use std::error::Error as StdError;
+use std::fs::File;
 use std::io::{Cursor, Read, Seek, SeekFrom};
 use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
 
 use hyper::server::conn::AddrStream;
@@ -9,11 +11,18 @@ use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use serde_json::json;
 
-use crate::audio_stream::AudioStream;
+use crate::audio_stream::{self, AudioStream};
+use crate::config::Profile;
+use crate::hls;
 use crate::http_util::HttpQuery;
-use crate::index::TrackLyrics;
+use crate::index::{Index, Track, TrackLyrics};
 use crate::lyrics;
 use crate::media;
+use crate::scan;
+use crate::store::{
+    Role, Session, Share, ShareOptions, SharedItem, Store, User, DEFAULT_USER_NAME,
+};
+use crate::subsonic_api;
 use crate::Musicd;
 
 #[derive(Debug)]
@@ -83,54 +92,130 @@ pub async fn run_api(musicd: Arc<crate::Musicd>, bind: SocketAddr) {
         .expect("running server failed");
 }
 
-static OK: &[u8] = b"OK";
 static BAD_REQUEST: &[u8] = b"Bad Request";
 static UNAUTHORIZED: &[u8] = b"Unauthorized";
+static FORBIDDEN: &[u8] = b"Forbidden";
 static NOT_FOUND: &[u8] = b"Not Found";
 static INTERNAL_SERVER_ERROR: &[u8] = b"Internal Server Error";
 
-fn bad_request() -> Response<Body> {
+pub fn bad_request() -> Response<Body> {
     Response::builder()
         .status(StatusCode::BAD_REQUEST)
         .body(BAD_REQUEST.into())
         .unwrap()
 }
 
-fn unauthorized() -> Response<Body> {
+pub fn unauthorized() -> Response<Body> {
     Response::builder()
         .status(StatusCode::UNAUTHORIZED)
         .body(UNAUTHORIZED.into())
         .unwrap()
 }
 
-fn not_found() -> Response<Body> {
+pub fn forbidden() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(FORBIDDEN.into())
+        .unwrap()
+}
+
+pub fn not_found() -> Response<Body> {
     Response::builder()
         .status(StatusCode::NOT_FOUND)
         .body(NOT_FOUND.into())
         .unwrap()
 }
 
-fn server_error() -> Response<Body> {
+pub fn server_error() -> Response<Body> {
     Response::builder()
         .status(StatusCode::INTERNAL_SERVER_ERROR)
         .body(INTERNAL_SERVER_ERROR.into())
         .unwrap()
 }
 
-fn json_ok(json: &str) -> Response<Body> {
+pub fn json_ok(json: &str) -> Response<Body> {
     Response::builder()
         .header("Content-Type", "application/json; charset=utf8")
         .body(json.to_string().into())
         .unwrap()
 }
 
-struct ApiRequest {
-    request: Request<Body>,
-    musicd: Arc<Musicd>,
-    query: HttpQuery,
-    cookies: HashMap<String, String>,
+pub struct ApiRequest {
+    pub request: Request<Body>,
+    pub musicd: Arc<Musicd>,
+    pub query: HttpQuery,
+    pub cookies: HashMap<String, String>,
+    /// Set once the request has been authenticated
+    pub user: Option<User>,
+    /// Session or API key the request was authenticated with, None if authentication is
+    /// disabled
+    pub session: Option<Session>,
+    /// Share the request was authorized with, the user being its owner
+    pub share: Option<Share>,
+}
+
+impl ApiRequest {
+    pub fn user(&self) -> &User {
+        self.user.as_ref().expect("request not authenticated")
+    }
+
+    /// Index limited to the roots the user can access.
+    pub fn index(&self) -> Index {
+        let mut index = self.musicd.index();
+
+        if let Some(user) = &self.user {
+            index.restrict_roots(&user.roots);
+        }
+
+        index
+    }
+}
+
+/// Cookie holding the session token set by `/api/auth`
+const SESSION_COOKIE: &str = "musicd2-session";
+
+/// Seconds a login session stays valid
+const SESSION_LIFETIME: i64 = 30 * 24 * 60 * 60;
+
+/// Resolves the user of a request from an `Authorization: Bearer` API key or the session
+/// cookie. Without valid credentials falls back to the default user if authentication is
+/// disabled, otherwise returns None.
+fn authenticate(r: &ApiRequest) -> Result<Option<(Option<Session>, User)>, Error> {
+    let store = r.musicd.store();
+
+    let token = crate::http_util::bearer_token(r.request.headers())
+        .or_else(|| r.cookies.get(SESSION_COOKIE).map(|t| t.as_str()));
+
+    if let Some(token) = token {
+        if let Some((session, user)) =
+            store.authenticate_session(token, crate::scan::unix_time())?
+        {
+            return Ok(Some((Some(session), user)));
+        }
+    }
+
+    if r.musicd.password.is_empty() {
+        return Ok(store.default_user()?.map(|user| (None, user)));
+    }
+
+    Ok(None)
+}
+
+/// Runs `f` only if the user is an admin.
+fn admin_only<F>(r: &ApiRequest, f: F) -> Result<Response<Body>, Error>
+where
+    F: FnOnce(&ApiRequest) -> Result<Response<Body>, Error>,
+{
+    if r.user().role != Role::Admin {
+        return Ok(forbidden());
+    }
+
+    f(r)
 }
 
+/// Paths a share-only user can access
+const SHARE_ONLY_PATHS: &[&str] = &["/api/me", "/api/logout", "/api/received_shares"];
+
 async fn process_request(
     request: Request<Body>,
     musicd: Arc<Musicd>,
@@ -147,19 +232,28 @@ async fn process_request(
         }
     };
 
-    let api_request = ApiRequest {
+    let mut api_request = ApiRequest {
         request,
         musicd,
         query,
         cookies,
+        user: None,
+        session: None,
+        share: None,
     };
 
+    if let Some(token) = request_share_token(&api_request) {
+        return process_share_request(api_request, &token).await;
+    }
+
     let result = match (
         api_request.request.method(),
         api_request.request.uri().path(),
     ) {
         (&Method::GET, "/api/musicd") => Some(api_musicd(&api_request)),
         (&Method::GET, "/api/auth") => Some(api_auth(&api_request)),
+        (&Method::POST, "/api/auth") => Some(api_auth(&api_request)),
+        (&Method::GET, "/share") => Some(res_share(&api_request, false)),
         _ => None,
     };
 
@@ -170,12 +264,26 @@ async fn process_request(
         };
     }
 
-    if let Some(auth_password) = api_request.cookies.get("musicd2-auth") {
-        if !api_request.musicd.password.is_empty() && api_request.musicd.password != *auth_password
-        {
+    if api_request.request.uri().path().starts_with("/rest/") {
+        return subsonic_api::process_request(api_request).await;
+    }
+
+    match authenticate(&api_request) {
+        Ok(Some((session, user))) => {
+            api_request.session = session;
+            api_request.user = Some(user);
+        }
+        Ok(None) => {
             debug!("invalid auth");
             return Ok(unauthorized());
         }
+        Err(_e) => return Ok(server_error()),
+    };
+
+    if api_request.user().role == Role::ShareOnly
+        && !SHARE_ONLY_PATHS.contains(&api_request.request.uri().path())
+    {
+        return Ok(forbidden());
     }
 
     let result = match (
@@ -184,15 +292,46 @@ async fn process_request(
     ) {
         (&Method::GET, "/api/audio_stream") => api_audio_stream(&api_request),
         (&Method::GET, "/api/image_file") => api_image_file(&api_request),
+        (&Method::GET, "/api/hls/playlist.m3u8") => api_hls_playlist(&api_request),
+        (&Method::GET, "/api/hls/segment") => api_hls_segment(&api_request).await,
         (&Method::GET, "/api/track_lyrics") => api_track_lyrics(&api_request).await,
         (&Method::GET, "/api/nodes") => api_nodes(&api_request),
         (&Method::GET, "/api/tracks") => api_tracks(&api_request),
         (&Method::GET, "/api/artists") => api_artists(&api_request),
+        (&Method::GET, "/api/artist") => api_artist(&api_request),
         (&Method::GET, "/api/albums") => api_albums(&api_request),
+        (&Method::GET, "/api/genres") => api_genres(&api_request),
         (&Method::GET, "/api/images") => api_images(&api_request),
-        (&Method::GET, "/api/scan") => api_scan(&api_request),
-        (&Method::POST, "/api/scan") => api_scan(&api_request),
-        (&Method::GET, "/share") => res_share(&api_request),
+        (&Method::GET, "/api/album_images") => api_album_images(&api_request),
+        (&Method::POST, "/api/album_cover") => admin_only(&api_request, api_album_cover),
+        (&Method::GET, "/api/search") => api_search(&api_request),
+        (&Method::POST, "/api/track_play") => api_track_play(&api_request),
+        (&Method::POST, "/api/track_rating") => api_track_rating(&api_request),
+        (&Method::GET, "/api/rated_tracks") => api_rated_tracks(&api_request),
+        (&Method::GET, "/api/history") => api_history(&api_request),
+        (&Method::GET, "/api/played_tracks") => api_played_tracks(&api_request),
+        (&Method::GET, "/api/lists") => api_lists(&api_request),
+        (&Method::GET, "/api/list_tracks") => api_list_tracks(&api_request),
+        (&Method::POST, "/api/list") => api_list(&api_request),
+        (&Method::GET, "/api/scan") => admin_only(&api_request, api_scan),
+        (&Method::POST, "/api/scan") => admin_only(&api_request, api_scan),
+        (&Method::GET, "/api/scan/status") => api_scan_status(&api_request),
+        (&Method::GET, "/api/scan/history") => api_scan_history(&api_request),
+        (&Method::GET, "/api/settings/cover_patterns") => api_cover_patterns(&api_request),
+        (&Method::POST, "/api/settings/cover_patterns") => {
+            admin_only(&api_request, api_cover_patterns)
+        }
+        (&Method::GET, "/api/me") => api_me(&api_request),
+        (&Method::POST, "/api/logout") => api_logout(&api_request),
+        (&Method::GET, "/api/sessions") => api_sessions(&api_request),
+        (&Method::POST, "/api/session") => api_session(&api_request),
+        (&Method::GET, "/api/subsonic_password") => api_subsonic_password(&api_request),
+        (&Method::POST, "/api/subsonic_password") => api_subsonic_password(&api_request),
+        (&Method::GET, "/api/shares") => api_shares(&api_request),
+        (&Method::GET, "/api/received_shares") => api_received_shares(&api_request),
+        (&Method::POST, "/api/share") => api_share(&api_request),
+        (&Method::GET, "/api/users") => admin_only(&api_request, api_users),
+        (&Method::POST, "/api/user") => admin_only(&api_request, api_user),
         _ => Ok(not_found()),
     };
 
@@ -202,29 +341,277 @@ async fn process_request(
     }
 }
 
+/// Share token of a request, given in `share`, or for `/api/oembed` in the share page URL
+/// given in `url`.
+fn request_share_token(r: &ApiRequest) -> Option<String> {
+    let query = if r.request.uri().path() == "/api/oembed" {
+        let url = r.query.get_str("url")?;
+        HttpQuery::from(url.split_once('?')?.1)
+    } else {
+        return r.query.get_str("share").map(|t| t.to_string());
+    };
+
+    query.get_str("share").map(|t| t.to_string())
+}
+
+/// Serves a request authorized with a share `token`, which only gives access to the shared
+/// tracks and their album covers. Tokens of password protected shares need to be unlocked
+/// first.
+async fn process_share_request(
+    mut api_request: ApiRequest,
+    token: &str,
+) -> Result<Response<Body>, hyper::Error> {
+    let store = api_request.musicd.store();
+
+    let (share, unlocked) = match store.share_by_token(token, crate::scan::unix_time()) {
+        Ok(Some(share)) => share,
+        Ok(None) => {
+            // The page shows the error itself
+            if api_request.request.uri().path() == "/share" {
+                return match res_share(&api_request, false) {
+                    Ok(res) => Ok(res),
+                    Err(_e) => Ok(server_error()),
+                };
+            }
+
+            return Ok(unauthorized());
+        }
+        Err(_e) => return Ok(server_error()),
+    };
+
+    api_request.user = match store.user(share.user_id) {
+        Ok(user) => user,
+        Err(_e) => return Ok(server_error()),
+    };
+    api_request.share = Some(share);
+
+    let result = match (
+        api_request.request.method(),
+        api_request.request.uri().path(),
+        unlocked,
+    ) {
+        (&Method::GET, "/share", _) => res_share(&api_request, unlocked),
+        (&Method::GET, "/api/oembed", _) => api_oembed(&api_request, token, unlocked),
+        (&Method::GET, "/api/share/info", _) => api_share_info(&api_request, unlocked),
+        (&Method::POST, "/api/share/unlock", _) => api_share_unlock(&api_request),
+        (_, _, false) => Ok(unauthorized()),
+        (&Method::POST, "/api/share/play", true) => match shared_track_requested(&api_request) {
+            Ok(true) => api_share_play(&api_request),
+            Ok(false) => Ok(forbidden()),
+            Err(e) => Err(e),
+        },
+        (&Method::GET, "/api/audio_stream", true) => match shared_track_requested(&api_request) {
+            Ok(true) => api_audio_stream(&api_request),
+            Ok(false) => Ok(forbidden()),
+            Err(e) => Err(e),
+        },
+        (&Method::GET, "/api/hls/playlist.m3u8", true) => {
+            match shared_track_requested(&api_request) {
+                Ok(true) => api_hls_playlist(&api_request),
+                Ok(false) => Ok(forbidden()),
+                Err(e) => Err(e),
+            }
+        }
+        (&Method::GET, "/api/hls/segment", true) => match shared_track_requested(&api_request) {
+            Ok(true) => api_hls_segment(&api_request).await,
+            Ok(false) => Ok(forbidden()),
+            Err(e) => Err(e),
+        },
+        (&Method::GET, "/api/track_lyrics", true) => match shared_track_requested(&api_request) {
+            Ok(true) => api_track_lyrics(&api_request).await,
+            Ok(false) => Ok(forbidden()),
+            Err(e) => Err(e),
+        },
+        (&Method::GET, "/api/image_file", true) => match shared_image_requested(&api_request) {
+            Ok(true) => api_image_file(&api_request),
+            Ok(false) => Ok(forbidden()),
+            Err(e) => Err(e),
+        },
+        _ => Ok(forbidden()),
+    };
+
+    match result {
+        Ok(res) => Ok(res),
+        Err(_e) => Ok(server_error()),
+    }
+}
+
+fn shared_track_ids(r: &ApiRequest) -> Result<Vec<i64>, Error> {
+    match &r.share {
+        Some(share) => Ok(r.musicd.store().share_track_ids(share)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Whether `track_id` is one of the shared tracks.
+fn shared_track_requested(r: &ApiRequest) -> Result<bool, Error> {
+    match r.query.get_i64("track_id") {
+        Some(track_id) => Ok(shared_track_ids(r)?.contains(&track_id)),
+        None => Ok(false),
+    }
+}
+
+/// Seconds a play through a share can take beyond the length of the track, for pauses and
+/// buffering
+const SHARE_PLAY_GRACE: i64 = 60 * 60;
+
+/// Counts a play of `track` through the share of the request, returning the token the play
+/// fetches the track with. None if the share has no plays left.
+fn start_share_play(r: &ApiRequest, track: &Track) -> Result<Option<String>, Error> {
+    let share = r.share.as_ref().unwrap();
+    let mut store = r.musicd.store();
+
+    if !store.register_share_play(share.share_id)? {
+        return Ok(None);
+    }
+
+    let expires = crate::scan::unix_time() + track.length.ceil() as i64 + SHARE_PLAY_GRACE;
+
+    let token = store.share_play_token(share, track.track_id, expires)?;
+
+    Ok(Some(token))
+}
+
+/// Whether the request has the token of a play of `track_id` started through its share.
+fn share_play_started(r: &ApiRequest, track_id: i64) -> Result<bool, Error> {
+    let share = r.share.as_ref().unwrap();
+    let play = r.query.get_str("play").unwrap_or_default();
+
+    let now = crate::scan::unix_time();
+
+    let started = r
+        .musicd
+        .store()
+        .verify_share_play_token(share, track_id, play, now)?;
+
+    Ok(started)
+}
+
+/// Whether `image_id` is the cover of an album of the shared tracks.
+fn shared_image_requested(r: &ApiRequest) -> Result<bool, Error> {
+    let image_id = match r.query.get_i64("image_id") {
+        Some(id) => id,
+        None => {
+            return Ok(false);
+        }
+    };
+
+    let index = r.index();
+
+    let mut album_ids: Vec<i64> = Vec::new();
+
+    for track_id in shared_track_ids(r)? {
+        if let Some(track) = index.track(track_id)? {
+            if !album_ids.contains(&track.album_id) {
+                album_ids.push(track.album_id);
+            }
+        }
+    }
+
+    for album_id in album_ids {
+        if let Some(album) = index.album(album_id)? {
+            if album.image_id == Some(image_id) {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
 fn api_musicd(_: &ApiRequest) -> Result<Response<Body>, Error> {
     Ok(json_ok("{}"))
 }
 
+/// Logs in as `user`, or the default user if not given, starting a new session.
 fn api_auth(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let name = r.query.get_str("user").unwrap_or(DEFAULT_USER_NAME);
     let password = r.query.get_str("password").unwrap_or_default();
-    if r.musicd.password != password {
-        return Ok(unauthorized());
+
+    let mut store = r.musicd.store();
+
+    let user = match store.authenticate(name, password)? {
+        Some(u) => u,
+        None => {
+            return Ok(unauthorized());
+        }
+    };
+
+    let user_agent = r
+        .request
+        .headers()
+        .get("User-Agent")
+        .and_then(|v| v.to_str().ok());
+
+    let now = crate::scan::unix_time();
+
+    let (_, token) = store.create_session(
+        user.user_id,
+        false,
+        user_agent,
+        now,
+        Some(now + SESSION_LIFETIME),
+    )?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "application/json; charset=utf8")
+        .header(
+            "Set-Cookie",
+            format!(
+                "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+                SESSION_COOKIE, token, SESSION_LIFETIME
+            ),
+        )
+        .body(json!(user).to_string().into())
+        .unwrap())
+}
+
+/// Ends the session of the request.
+fn api_logout(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    if let Some(session) = &r.session {
+        r.musicd.store().delete_session(session.session_id)?;
     }
 
     Ok(Response::builder()
-        .header("Set-Cookie", format!("musicd2-auth={}", r.musicd.password))
-        .body(OK.into())
+        .header("Content-Type", "application/json; charset=utf8")
+        .header(
+            "Set-Cookie",
+            format!(
+                "{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax",
+                SESSION_COOKIE
+            ),
+        )
+        .body("{}".into())
         .unwrap())
 }
 
-static CODECS: &[(&str, &str)] = &[
-    ("mp3", "audio/mpeg"),
-    ("opus", "audio/ogg"),
-    ("ogg", "audio/ogg"),
-];
+fn range_not_satisfiable(total: u64) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+        .header("Content-Range", format!("bytes */{}", total))
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Transcoding profile named by `profile`, or one with the default settings of `codec`.
+/// Returns `None` if there is no such profile.
+fn request_profile(r: &ApiRequest, default_codec: &str) -> Option<Profile> {
+    match r.query.get_str("profile") {
+        Some(name) => r.musicd.config.profile(name).cloned(),
+        None => {
+            let codec = r.query.get_str("codec").unwrap_or(default_codec);
+            Some(Profile {
+                name: codec.to_string(),
+                codec: codec.to_string(),
+                bitrate: None,
+                sample_rate: None,
+                channels: None,
+            })
+        }
+    }
+}
 
-fn api_audio_stream(r: &ApiRequest) -> Result<Response<Body>, Error> {
+pub fn api_audio_stream(r: &ApiRequest) -> Result<Response<Body>, Error> {
     let track_id = match r.query.get_i64("track_id") {
         Some(id) => id,
         None => {
@@ -232,20 +619,28 @@ fn api_audio_stream(r: &ApiRequest) -> Result<Response<Body>, Error> {
         }
     };
 
-    let codec_req = r.query.get_str("codec").unwrap_or(CODECS[0].0);
-    let target_codec = match CODECS.iter().find(|c| c.0 == codec_req) {
+    let mut profile = match request_profile(r, audio_stream::CODECS[0].name) {
+        Some(p) => p,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    let target_codec = match audio_stream::find_codec(&profile.codec) {
         Some(c) => c,
         None => {
             return Ok(bad_request());
         }
     };
 
+    let bitrate = *profile.bitrate.get_or_insert(target_codec.bitrate);
+
     let start = r.query.get_i64("start").unwrap_or(0) as f64;
     if start < 0f64 {
         return Ok(bad_request());
     }
 
-    let index = r.musicd.index();
+    let index = r.index();
 
     let track = match index.track(track_id)? {
         Some(t) => t,
@@ -255,7 +650,79 @@ fn api_audio_stream(r: &ApiRequest) -> Result<Response<Body>, Error> {
     };
 
     let node = index.node(track.node_id)?.unwrap();
-    let fs_path = index.map_fs_path(&node.path).unwrap();
+    let fs_path = match index.map_fs_path(&node.path) {
+        Some(p) => p,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    // Plays through a share are started with `/api/share/play`, which gives the token the
+    // play streams with
+    if r.share.is_some() && !share_play_started(r, track_id)? {
+        return Ok(forbidden());
+    }
+
+    let passthrough = audio_stream::passthrough_format(&fs_path);
+
+    let content_type = match (target_codec.format, passthrough) {
+        (Some(_), _) => target_codec.content_type,
+        (None, Some(passthrough)) => passthrough.1,
+        (None, None) => audio_stream::PASSTHROUGH_FALLBACK.1,
+    };
+
+    // The file can be served as is if it holds only this track and already is in the
+    // requested format, unless a profile asks for specific encoder settings
+    let extension = fs_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    if track.start.is_none()
+        && start == 0f64
+        && r.query.get_str("profile").is_none()
+        && (target_codec.format.is_none() || extension.as_deref() == Some(target_codec.name))
+    {
+        let total = std::fs::metadata(&fs_path)?.len() as f64;
+
+        return file_response(
+            r,
+            &fs_path,
+            match passthrough {
+                Some(p) => p.1,
+                None => "application/octet-stream",
+            },
+            |offset| play_recorder(r, &track, 0f64, total, offset as f64),
+        );
+    }
+
+    // Byte ranges of the transcoded stream are mapped to seek positions using the target
+    // bitrate, so that browsers can seek; the announced length is only an estimate. This
+    // isn't possible for lossless output, where the size can't be estimated.
+    let range = if target_codec.bitrate > 0 {
+        crate::http_util::parse_range(r.request.headers())
+    } else {
+        None
+    };
+
+    let bytes_per_second = f64::from(bitrate) / 8f64;
+    let total = ((track.length - start).max(0f64) * bytes_per_second).ceil() as u64;
+
+    let (offset, length) = match &range {
+        Some(range) => match range.resolve(total) {
+            Some(r) => r,
+            None => {
+                return Ok(range_not_satisfiable(total));
+            }
+        },
+        None => (0, total),
+    };
+
+    let start = if range.is_some() {
+        start + offset as f64 / bytes_per_second
+    } else {
+        start
+    };
 
     let audio_stream = AudioStream::open(
         &fs_path,
@@ -267,7 +734,7 @@ fn api_audio_stream(r: &ApiRequest) -> Result<Response<Body>, Error> {
         } else {
             0f64
         },
-        target_codec.0,
+        &profile,
     );
 
     let audio_stream = match audio_stream {
@@ -284,157 +751,655 @@ fn api_audio_stream(r: &ApiRequest) -> Result<Response<Body>, Error> {
     let (sender, receiver) =
         tokio::sync::mpsc::channel::<Result<Vec<u8>, Box<dyn StdError + Send + Sync>>>(5);
 
+    // The announced length is kept to by padding or cutting off the stream
+    let exact_length = if target_codec.bitrate > 0 {
+        Some(length as usize)
+    } else {
+        None
+    };
+
+    let track_start = track.start.unwrap_or_default();
+    let record_play = play_recorder(
+        r,
+        &track,
+        track_start,
+        track_start + track.length,
+        track_start + start,
+    );
+
     tokio::spawn(async move {
-        audio_stream.execute(sender).await;
+        audio_stream
+            .execute(sender, exact_length, record_play)
+            .await;
     });
 
-    Ok(Response::builder()
-        .header("Content-Type", target_codec.1)
-        .body(Body::wrap_stream(receiver))
-        .unwrap())
+    let mut builder = Response::builder().header("Content-Type", content_type);
+
+    if target_codec.bitrate > 0 {
+        builder = builder
+            .header("Accept-Ranges", "bytes")
+            .header("Content-Length", length);
+    }
+
+    if range.is_some() {
+        builder = builder.status(StatusCode::PARTIAL_CONTENT).header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", offset, offset + length - 1, total),
+        );
+    }
+
+    Ok(builder.body(Body::wrap_stream(receiver)).unwrap())
 }
 
-fn api_image_file(r: &ApiRequest) -> Result<Response<Body>, Error> {
-    let image_id = match r.query.get_i64("image_id") {
+const HLS_PLAYLIST_CONTENT_TYPE: &str = "application/vnd.apple.mpegurl";
+
+/// Segments only change with the file, but they're behind authentication
+const HLS_SEGMENT_CACHE_CONTROL: &str = "private, max-age=86400";
+
+/// Transcoding profile of HLS segments with the bitrate filled in, `None` if the profile
+/// doesn't exist or its codec can't be used for segments.
+fn hls_profile(r: &ApiRequest) -> Option<(Profile, &'static audio_stream::Codec)> {
+    let mut profile = request_profile(r, hls::DEFAULT_SEGMENT_CODEC)?;
+
+    if !hls::SEGMENT_CODECS.contains(&profile.codec.as_str()) {
+        return None;
+    }
+
+    let codec = audio_stream::find_codec(&profile.codec)?;
+    profile.bitrate.get_or_insert(codec.bitrate);
+
+    Some((profile, codec))
+}
+
+pub fn api_hls_playlist(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let track_id = match r.query.get_i64("track_id") {
         Some(id) => id,
         None => {
             return Ok(bad_request());
         }
     };
 
-    let size = r.query.get_i64("size").unwrap_or(0);
+    if hls_profile(r).is_none() {
+        return Ok(bad_request());
+    }
 
-    let index = r.musicd.index();
+    let index = r.index();
 
-    let image = match index.image(image_id)? {
-        Some(i) => i,
+    let track = match index.track(track_id)? {
+        Some(t) => t,
         None => {
             return Ok(not_found());
         }
     };
 
-    let cache_str = format!("image:{}_{}", image_id, size);
+    // Tracks outside the roots of the user would have no segments
+    if index.track_fs_path(&track)?.is_none() {
+        return Ok(not_found());
+    }
 
-    let cache = r.musicd.cache();
-    let image_data = if let Some(image_data) = cache.get_blob(&cache_str)? {
-        image_data
-    } else {
-        let node = index.node(image.node_id)?.unwrap();
-        let fs_path = match index.map_fs_path(&node.path) {
-            Some(p) => p,
+    // Each fetch of the playlist through a share is a play, so that retried segments don't
+    // count again. Segments are only served with the token of the play.
+    let play = match &r.share {
+        Some(_) => match start_share_play(r, &track)? {
+            Some(play) => Some(play),
             None => {
-                return Ok(not_found());
+                return Ok(forbidden());
             }
-        };
-
-        let mut image_obj = if let Some(stream_index) = image.stream_index {
-            debug!(
-                "loading image data from media file '{}'",
-                fs_path.to_string_lossy()
-            );
-
-            let image_data = match media::media_image_data_read(&fs_path, stream_index as i32) {
-                Some(i) => i,
-                None => {
-                    return Ok(not_found());
-                }
-            };
-
-            image::load_from_memory_with_format(&image_data, image::ImageFormat::JPEG)
-        } else {
-            debug!("loading image file '{}'", fs_path.to_string_lossy());
-            image::open(&fs_path)
-        }?;
+        },
+        None => None,
+    };
 
-        if size > 0 && size < std::cmp::max(image.width, image.height) {
-            debug!("resizing {}x{} to size {}", image.width, image.height, size);
-            image_obj = image_obj.resize(size as u32, size as u32, image::FilterType::Lanczos3);
+    // Segment URIs are relative to the playlist and carry on its profile and share token
+    let mut query = format!("track_id={}", track_id);
+    for key in &["profile", "codec", "share"] {
+        if let Some(value) = r.query.get_str(key) {
+            query.push_str(&format!(
+                "&{}={}",
+                key,
+                crate::http_util::encode_url_component(value)
+            ));
         }
+    }
 
-        let mut c = Cursor::new(Vec::new());
-        image_obj.write_to(&mut c, image::ImageOutputFormat::JPEG(70))?;
-
-        c.seek(SeekFrom::Start(0))?;
-        let mut image_data = Vec::new();
-        c.read_to_end(&mut image_data)?;
-
-        cache.set_blob(&cache_str, &image_data)?;
+    if let Some(play) = play {
+        query.push_str(&format!(
+            "&play={}",
+            crate::http_util::encode_url_component(&play)
+        ));
+    }
 
-        image_data
-    };
+    let playlist = hls::playlist(track.length, |index| {
+        format!("segment?{}&index={}", query, index)
+    });
 
     Ok(Response::builder()
-        .header("Content-Type", "image/jpeg")
-        .body(image_data.into())
+        .header("Content-Type", HLS_PLAYLIST_CONTENT_TYPE)
+        .body(Body::from(playlist))
         .unwrap())
 }
 
-async fn api_track_lyrics(r: &ApiRequest) -> Result<Response<Body>, Error> {
-    let track_id = match r.query.get_i64("track_id") {
-        Some(id) => id,
+pub async fn api_hls_segment(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (track_id, index) = match (r.query.get_i64("track_id"), r.query.get_i64("index")) {
+        (Some(track_id), Some(index)) if index >= 0 => (track_id, index as usize),
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    let (profile, codec) = match hls_profile(r) {
+        Some(p) => p,
         None => {
             return Ok(bad_request());
         }
     };
 
-    let (track, lyrics) = {
-        let index = r.musicd.index();
+    if r.share.is_some() && !share_play_started(r, track_id)? {
+        return Ok(forbidden());
+    }
 
-        (
-            match index.track(track_id)? {
-                Some(t) => t,
-                None => {
-                    return Ok(not_found());
-                }
-            },
-            index.track_lyrics(track_id)?,
-        )
-    };
+    let (track, node, fs_path) = {
+        let index_db = r.index();
 
-    let lyrics = match lyrics {
-        Some(lyrics) => lyrics,
-        None => {
-            let lyrics = match lyrics::try_fetch_lyrics(&track.artist_name, &track.title).await {
-                Ok(lyrics) => match lyrics {
-                    Some(l) => TrackLyrics {
-                        track_id,
-                        lyrics: Some(l.lyrics),
-                        provider: Some(l.provider),
-                        source: Some(l.source),
-                        modified: 0,
-                    },
-                    None => TrackLyrics {
-                        track_id,
-                        lyrics: None,
-                        provider: None,
-                        source: None,
-                        modified: 0,
-                    },
-                },
-                Err(e) => {
-                    error!("fetching lyrics failed: {}", e.description());
+        let track = match index_db.track(track_id)? {
+            Some(t) => t,
+            None => {
+                return Ok(not_found());
+            }
+        };
+
+        let node = index_db.node(track.node_id)?.unwrap();
+
+        let fs_path = match index_db.map_fs_path(&node.path) {
+            Some(p) => p,
+            None => {
+                return Ok(not_found());
+            }
+        };
+
+        (track, node, fs_path)
+    };
+
+    let (start, length) = match hls::segment_range(track.length, index) {
+        Some(range) => range,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    let cache_str = format!(
+        "hls:{}_{}_{}_{}_{}_{}_{}",
+        track_id,
+        index,
+        profile.codec,
+        profile.bitrate.unwrap_or(0),
+        profile.sample_rate.unwrap_or(0),
+        profile.channels.unwrap_or(0),
+        node.modified
+    );
+
+    let cached = r.musicd.cache().get_blob(&cache_str)?;
+
+    let data = match cached {
+        Some(data) => data,
+        None => {
+            let stream_index = track.stream_index as i32;
+            let track_index = track.track_index.unwrap_or(0) as i32;
+            let track_start = track.start.unwrap_or_default();
+            let track_length = track.length;
+            let task_path = fs_path.clone();
+
+            // Decoding and encoding takes a while, keep it off the request threads
+            let result = tokio::task::spawn_blocking(move || {
+                hls::encode_segment(
+                    &task_path,
+                    stream_index,
+                    track_index,
+                    track_start,
+                    track_length,
+                    index,
+                    &profile,
+                )
+            })
+            .await;
+
+            match result {
+                Ok(Some(data)) => {
+                    r.musicd.cache().set_blob(&cache_str, &data)?;
+                    data
+                }
+                _ => {
+                    error!(
+                        "can't encode segment {} of track {} from '{}'",
+                        index,
+                        track_id,
+                        fs_path.to_string_lossy()
+                    );
                     return Ok(server_error());
                 }
+            }
+        }
+    };
+
+    // The segment counts as sent in whole, so the play is recorded by the segment holding
+    // the mark. Plays through a share are counted by the playlist instead.
+    if r.share.is_none() {
+        let track_start = track.start.unwrap_or_default();
+        let mut record_play = play_recorder(
+            r,
+            &track,
+            track_start,
+            track_start + track.length,
+            track_start + start,
+        );
+        record_play(track_start + start + length);
+    }
+
+    Ok(Response::builder()
+        .header("Content-Type", codec.content_type)
+        .header("Content-Length", data.len())
+        .header("Cache-Control", HLS_SEGMENT_CACHE_CONTROL)
+        .body(Body::from(data))
+        .unwrap())
+}
+
+/// Returns a callback recording a play of `track` once the stream position passes the
+/// configured percentage of the range from `begin` to `end`. Streams starting past that point,
+/// as when seeking, aren't counted. Clients buffer ahead, so this is where the data was sent
+/// up to, not where playback is. Plays through a share count for the share as soon as a stream
+/// from `begin` starts, and don't go to the history of its owner.
+fn play_recorder(
+    r: &ApiRequest,
+    track: &Track,
+    begin: f64,
+    end: f64,
+    start: f64,
+) -> impl FnMut(f64) + Send + 'static {
+    // Plays through a share are counted when they're started with `/api/share/play`
+    let mut mark = match r.share {
+        Some(_) => None,
+        None => r
+            .musicd
+            .config
+            .play_percentage
+            .map(|p| begin + (end - begin) * p / 100f64)
+            .filter(|mark| start <= *mark),
+    };
+
+    let musicd = r.musicd.clone();
+    let user_id = r.user().user_id;
+    let track = track.clone();
+
+    move |position| {
+        if mark.is_none_or(|mark| position < mark) {
+            return;
+        }
+
+        mark = None;
+
+        let result = musicd
+            .store()
+            .register_track_play(user_id, &track, crate::scan::unix_time());
+
+        if let Err(e) = result {
+            error!("can't register play of track {}: {}", track.track_id, e);
+        }
+    }
+}
+
+/// Serves a file from the file system, honoring a `Range` header. `progress` is given the
+/// offset of the range and returns a callback for the position after each chunk sent.
+fn file_response<F, P>(
+    r: &ApiRequest,
+    fs_path: &Path,
+    content_type: &str,
+    progress: F,
+) -> Result<Response<Body>, Error>
+where
+    F: FnOnce(u64) -> P,
+    P: FnMut(f64) + Send + 'static,
+{
+    debug!("serving file '{}' as is", fs_path.to_string_lossy());
+
+    let mut file = File::open(fs_path)?;
+    let total = file.metadata()?.len();
+
+    let range = crate::http_util::parse_range(r.request.headers());
+
+    let (offset, length) = match &range {
+        Some(range) => match range.resolve(total) {
+            Some(r) => r,
+            None => {
+                return Ok(range_not_satisfiable(total));
+            }
+        },
+        None => (0, total),
+    };
+
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut progress = progress(offset);
+
+    let (mut sender, receiver) =
+        tokio::sync::mpsc::channel::<Result<Vec<u8>, Box<dyn StdError + Send + Sync>>>(5);
+
+    tokio::spawn(async move {
+        let mut remaining = length as usize;
+        let mut position = offset;
+
+        while remaining > 0 {
+            let mut buf = vec![0u8; std::cmp::min(remaining, 64 * 1024)];
+
+            let result = match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    buf.truncate(n);
+                    remaining -= n;
+                    position += n as u64;
+                    sender.send(Ok(buf)).await
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(Box::new(e))).await;
+                    break;
+                }
+            };
+
+            if result.is_err() {
+                debug!("channel disconnected, stopping file stream");
+                break;
+            }
+
+            progress(position as f64);
+        }
+    });
+
+    let mut builder = Response::builder()
+        .header("Content-Type", content_type)
+        .header("Content-Length", length)
+        .header("Accept-Ranges", "bytes");
+
+    if range.is_some() {
+        builder = builder.status(StatusCode::PARTIAL_CONTENT).header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", offset, offset + length - 1, total),
+        );
+    }
+
+    Ok(builder.body(Body::wrap_stream(receiver)).unwrap())
+}
+
+/// Largest width or height `/api/image_file` resizes to
+const MAX_IMAGE_SIZE: i64 = 4096;
+
+const DEFAULT_IMAGE_QUALITY: i64 = 70;
+
+/// Images only change along with their files, which the ETag covers, so clients may keep them
+/// for a day without revalidating
+const IMAGE_CACHE_CONTROL: &str = "private, max-age=86400";
+
+/// Serves an image as `format=` jpeg (default), png or webp, with `quality=` from 1 to 100 for
+/// lossy formats. `size=` fits the image in a square without enlarging it, `width=` and
+/// `height=` resize it exactly. With `crop=1` the image is cropped to the aspect ratio instead
+/// of being stretched, so `size=` and `crop=1` give a centered square.
+pub fn api_image_file(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let image_id = match r.query.get_i64("image_id") {
+        Some(id) => id,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    let (format, content_type) = match r.query.get_str("format").unwrap_or("jpeg") {
+        "jpeg" | "jpg" => ("jpeg", "image/jpeg"),
+        "png" => ("png", "image/png"),
+        "webp" => ("webp", "image/webp"),
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    let quality = r
+        .query
+        .get_i64("quality")
+        .unwrap_or(DEFAULT_IMAGE_QUALITY)
+        .clamp(1, 100);
+
+    let size = r.query.get_i64("size").unwrap_or(0);
+    let crop = r.query.get_i64("crop").unwrap_or(0) != 0;
+
+    let (width, height) = match (r.query.get_i64("width"), r.query.get_i64("height")) {
+        (Some(w), Some(h)) => (w, h),
+        (None, None) if crop && size > 0 => (size, size),
+        (None, None) => (0, 0),
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    if [size, width, height]
+        .iter()
+        .any(|v| !(0..=MAX_IMAGE_SIZE).contains(v))
+    {
+        return Ok(bad_request());
+    }
+
+    let index = r.index();
+
+    let image = match index.image(image_id)? {
+        Some(i) => i,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    let node = index.node(image.node_id)?.unwrap();
+
+    let variant = format!(
+        "{}_{}_{}x{}_{}_{}_{}",
+        image_id, size, width, height, crop, format, quality
+    );
+
+    let etag = format!(
+        "\"{:x}\"",
+        md5::compute(format!("{}_{}", variant, node.modified))
+    );
+
+    if crate::http_util::not_modified(r.request.headers(), &etag, node.modified) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header("ETag", etag)
+            .header("Cache-Control", IMAGE_CACHE_CONTROL)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    let cache_str = format!("image:{}_{}", variant, node.modified);
+
+    let cache = r.musicd.cache();
+    let image_data = if let Some(image_data) = cache.get_blob(&cache_str)? {
+        image_data
+    } else {
+        let fs_path = match index.map_fs_path(&node.path) {
+            Some(p) => p,
+            None => {
+                return Ok(not_found());
+            }
+        };
+
+        let mut image_obj = if let Some(stream_index) = image.stream_index {
+            debug!(
+                "loading image data from media file '{}'",
+                fs_path.to_string_lossy()
+            );
+
+            let image_data = match media::media_image_data_read(&fs_path, stream_index as i32) {
+                Some(i) => i,
+                None => {
+                    return Ok(not_found());
+                }
+            };
+
+            image::load_from_memory_with_format(&image_data, image::ImageFormat::JPEG)
+        } else {
+            debug!("loading image file '{}'", fs_path.to_string_lossy());
+            image::open(&fs_path)
+        }?;
+
+        if width > 0 && height > 0 {
+            debug!(
+                "resizing {}x{} to {}x{}, crop {}",
+                image.width, image.height, width, height, crop
+            );
+
+            image_obj = if crop {
+                image_obj.resize_to_fill(width as u32, height as u32, image::FilterType::Lanczos3)
+            } else {
+                image_obj.resize_exact(width as u32, height as u32, image::FilterType::Lanczos3)
             };
+        } else if size > 0 && size < std::cmp::max(image.width, image.height) {
+            debug!("resizing {}x{} to size {}", image.width, image.height, size);
+            image_obj = image_obj.resize(size as u32, size as u32, image::FilterType::Lanczos3);
+        }
+
+        let image_data = if format == "webp" {
+            let rgba = image_obj.to_rgba();
+
+            webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                .encode(quality as f32)
+                .to_vec()
+        } else {
+            let output_format = if format == "png" {
+                image::ImageOutputFormat::PNG
+            } else {
+                image::ImageOutputFormat::JPEG(quality as u8)
+            };
+
+            let mut c = Cursor::new(Vec::new());
+            image_obj.write_to(&mut c, output_format)?;
+
+            c.seek(SeekFrom::Start(0))?;
+            let mut image_data = Vec::new();
+            c.read_to_end(&mut image_data)?;
+
+            image_data
+        };
+
+        cache.set_blob(&cache_str, &image_data)?;
+
+        image_data
+    };
+
+    Ok(Response::builder()
+        .header("Content-Type", content_type)
+        .header("ETag", etag)
+        .header("Last-Modified", crate::http_util::http_date(node.modified))
+        .header("Cache-Control", IMAGE_CACHE_CONTROL)
+        .body(image_data.into())
+        .unwrap())
+}
+
+async fn api_track_lyrics(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let track_id = match r.query.get_i64("track_id") {
+        Some(id) => id,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    let (track, fs_path, lyrics) = {
+        let index = r.index();
+
+        let track = match index.track(track_id)? {
+            Some(t) => t,
+            None => {
+                return Ok(not_found());
+            }
+        };
+
+        let fs_path = match index.track_fs_path(&track)? {
+            Some(p) => p,
+            None => {
+                return Ok(not_found());
+            }
+        };
+
+        (track, fs_path, index.track_lyrics(track_id)?)
+    };
+
+    let lyrics = match lyrics {
+        Some(lyrics) => lyrics,
+        None => match fetch_track_lyrics(&r.musicd, &track, &fs_path).await? {
+            Some(lyrics) => lyrics,
+            None => {
+                return Ok(server_error());
+            }
+        },
+    };
+
+    let mut result = json!({
+        "track_id": lyrics.track_id,
+        "lyrics": lyrics.lyrics,
+        "provider": lyrics.provider,
+        "source": lyrics.source,
+        "modified": lyrics.modified,
+    });
+
+    if let Some(lines) = lyrics.synced.as_deref().and_then(lyrics::parse_lrc) {
+        result["lines"] = json!(lines);
+    }
+
+    Ok(json_ok(&result.to_string()))
+}
 
-            r.musicd.index().set_track_lyrics(&lyrics)?
+/// Fetches lyrics for a track not yet in the index and stores the result, also when nothing
+/// was found. `fs_path` is the file of the track, mapped with the index of the requesting user.
+/// Returns `None` if fetching failed.
+pub async fn fetch_track_lyrics(
+    musicd: &Musicd,
+    track: &Track,
+    fs_path: &Path,
+) -> Result<Option<TrackLyrics>, Error> {
+    let lyrics = match lyrics::try_fetch_lyrics(&musicd.lyrics_providers, track, fs_path).await {
+        Ok(lyrics) => match lyrics {
+            Some(l) => TrackLyrics {
+                track_id: track.track_id,
+                lyrics: Some(l.lyrics),
+                synced: l.synced,
+                provider: Some(l.provider),
+                source: Some(l.source),
+                modified: 0,
+            },
+            None => TrackLyrics {
+                track_id: track.track_id,
+                lyrics: None,
+                synced: None,
+                provider: None,
+                source: None,
+                modified: 0,
+            },
+        },
+        Err(e) => {
+            error!("fetching lyrics failed: {}", e.description());
+            return Ok(None);
         }
     };
 
+    Ok(Some(musicd.index().set_track_lyrics(&lyrics)?))
+}
+
+fn api_nodes(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) = crate::query::query_nodes(&r.index(), &r.query)?;
+
     Ok(json_ok(
         &json!({
-            "track_id": lyrics.track_id,
-            "lyrics": lyrics.lyrics,
-            "provider": lyrics.provider,
-            "source": lyrics.source,
-            "modified": lyrics.modified,
+            "total": total,
+            "items": items
         })
         .to_string(),
     ))
 }
 
-fn api_nodes(r: &ApiRequest) -> Result<Response<Body>, Error> {
-    let (total, items) = crate::query::query_nodes(&r.musicd.index(), &r.query)?;
+fn api_tracks(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) = crate::query::query_tracks(&r.index(), &r.query)?;
 
     Ok(json_ok(
         &json!({
@@ -445,8 +1410,8 @@ fn api_nodes(r: &ApiRequest) -> Result<Response<Body>, Error> {
     ))
 }
 
-fn api_tracks(r: &ApiRequest) -> Result<Response<Body>, Error> {
-    let (total, items) = crate::query::query_tracks(&r.musicd.index(), &r.query)?;
+fn api_artists(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) = crate::query::query_artists(&r.index(), &r.query)?;
 
     Ok(json_ok(
         &json!({
@@ -457,8 +1422,45 @@ fn api_tracks(r: &ApiRequest) -> Result<Response<Body>, Error> {
     ))
 }
 
-fn api_artists(r: &ApiRequest) -> Result<Response<Body>, Error> {
-    let (total, items) = crate::query::query_artists(&r.musicd.index(), &r.query)?;
+/// Number of most played tracks in `/api/artist`
+const ARTIST_TOP_TRACKS: i64 = 10;
+
+fn api_artist(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let artist_id = match r.query.get_i64("artist_id") {
+        Some(a) => a,
+        None => return Ok(bad_request()),
+    };
+
+    let index = r.index();
+
+    let mut query = HttpQuery::new();
+    query.set("artist_id", &artist_id.to_string());
+
+    let (_, artists) = crate::query::query_artists(&index, &query)?;
+
+    let artist = match artists.into_iter().next() {
+        Some(a) => a,
+        None => return Ok(not_found()),
+    };
+
+    let (_, albums) = crate::query::query_albums(&index, &query)?;
+
+    query.set("limit", &ARTIST_TOP_TRACKS.to_string());
+
+    let (_, top_tracks) = crate::query::query_played_tracks(&index, &query, r.user().user_id)?;
+
+    Ok(json_ok(
+        &json!({
+            "artist": artist,
+            "albums": albums,
+            "top_tracks": top_tracks
+        })
+        .to_string(),
+    ))
+}
+
+fn api_albums(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) = crate::query::query_albums(&r.index(), &r.query)?;
 
     Ok(json_ok(
         &json!({
@@ -469,8 +1471,8 @@ fn api_artists(r: &ApiRequest) -> Result<Response<Body>, Error> {
     ))
 }
 
-fn api_albums(r: &ApiRequest) -> Result<Response<Body>, Error> {
-    let (total, items) = crate::query::query_albums(&r.musicd.index(), &r.query)?;
+fn api_genres(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) = crate::query::query_genres(&r.index(), &r.query)?;
 
     Ok(json_ok(
         &json!({
@@ -482,7 +1484,7 @@ fn api_albums(r: &ApiRequest) -> Result<Response<Body>, Error> {
 }
 
 fn api_images(r: &ApiRequest) -> Result<Response<Body>, Error> {
-    let (total, items) = crate::query::query_images(&r.musicd.index(), &r.query)?;
+    let (total, items) = crate::query::query_images(&r.index(), &r.query)?;
 
     Ok(json_ok(
         &json!({
@@ -493,34 +1495,1028 @@ fn api_images(r: &ApiRequest) -> Result<Response<Body>, Error> {
     ))
 }
 
-fn api_scan(r: &ApiRequest) -> Result<Response<Body>, Error> {
-    if let Some(action) = r.query.get_str("action") {
-        match action {
-            "start" => r.musicd.scan_thread.start(r.musicd.index()),
-            "restart" => {
-                r.musicd.scan_thread.stop();
-                r.musicd.scan_thread.start(r.musicd.index());
-            }
-            "stop" => {
-                r.musicd.scan_thread.stop();
-            }
-            _ => {}
-        }
-    }
+fn api_album_images(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let album_id = match r.query.get_i64("album_id") {
+        Some(a) => a,
+        None => return Ok(bad_request()),
+    };
+
+    let index = r.index();
+
+    let album = match index.album(album_id)? {
+        Some(a) => a,
+        None => return Ok(not_found()),
+    };
+
+    let (total, items) = crate::query::query_album_images(&index, &r.query)?;
 
     Ok(json_ok(
         &json!({
-            "running": r.musicd.scan_thread.is_running()
+            "image_id": album.image_id,
+            "total": total,
+            "items": items
         })
         .to_string(),
     ))
 }
 
-static SHARE_HTML: &[u8] = include_bytes!("./share.html");
+fn api_album_cover(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let album_id = match r.query.get_i64("album_id") {
+        Some(a) => a,
+        None => return Ok(bad_request()),
+    };
+
+    let index = r.index();
+
+    let album = match index.album(album_id)? {
+        Some(a) => a,
+        None => return Ok(not_found()),
+    };
+
+    let image = match r.query.get_str("image_id").filter(|i| !i.is_empty()) {
+        Some(image_id) => match image_id.parse() {
+            Ok(image_id) => match index.image(image_id)? {
+                Some(i) => Some(i),
+                None => return Ok(not_found()),
+            },
+            Err(_) => return Ok(bad_request()),
+        },
+        None => None,
+    };
+
+    r.musicd.store().set_album_cover(&album, image.as_ref())?;
+
+    let album = index.album(album_id)?.unwrap();
+
+    Ok(json_ok(
+        &json!({
+            "album_id": album.album_id,
+            "image_id": album.image_id,
+        })
+        .to_string(),
+    ))
+}
+
+fn api_search(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) = crate::query::query_search(&r.index(), &r.query)?;
+
+    Ok(json_ok(
+        &json!({
+            "total": total,
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+fn api_lists(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) = crate::query::query_lists(&r.index(), &r.query, r.user().user_id)?;
+
+    Ok(json_ok(
+        &json!({
+            "total": total,
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+/// Records a play of `track_id`, at the unix timestamp `played` if given.
+fn api_track_play(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let track_id = match r.query.get_i64("track_id") {
+        Some(id) => id,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    let track = match r.index().track(track_id)? {
+        Some(t) => t,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    let played = r
+        .query
+        .get_i64("played")
+        .unwrap_or_else(crate::scan::unix_time);
+
+    r.musicd
+        .store()
+        .register_track_play(r.user().user_id, &track, played)?;
+
+    Ok(json_ok("{}"))
+}
+
+/// Rates `track_id` from 1 to 5 for the user, or removes the rating with `rating=0`.
+fn api_track_rating(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (track_id, rating) = match (r.query.get_i64("track_id"), r.query.get_i64("rating")) {
+        (Some(t), Some(r)) if (0..=5).contains(&r) => (t, r),
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    let track = match r.index().track(track_id)? {
+        Some(t) => t,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    r.musicd
+        .store()
+        .set_track_rating(r.user().user_id, &track, Some(rating).filter(|r| *r > 0))?;
+
+    Ok(json_ok("{}"))
+}
+
+fn api_rated_tracks(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) = crate::query::query_rated_tracks(&r.index(), &r.query, r.user().user_id)?;
+
+    Ok(json_ok(
+        &json!({
+            "total": total,
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+fn api_history(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) = crate::query::query_history(&r.index(), &r.query, r.user().user_id)?;
+
+    Ok(json_ok(
+        &json!({
+            "total": total,
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+fn api_played_tracks(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) = crate::query::query_played_tracks(&r.index(), &r.query, r.user().user_id)?;
+
+    Ok(json_ok(
+        &json!({
+            "total": total,
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+fn api_list_tracks(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) = crate::query::query_list_tracks(&r.index(), &r.query, r.user().user_id)?;
+
+    Ok(json_ok(
+        &json!({
+            "total": total,
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+fn api_list(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let action = match r.query.get_str("action") {
+        Some(a) => a,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    let name = r.query.get_str("name").filter(|n| !n.is_empty());
+
+    let mut store = r.musicd.store();
+
+    if action == "create" {
+        let list = match name {
+            Some(name) => store.create_list(r.user().user_id, name)?,
+            None => {
+                return Ok(bad_request());
+            }
+        };
+
+        return Ok(json_ok(
+            &json!({
+                "list_id": list.list_id,
+                "name": list.name,
+            })
+            .to_string(),
+        ));
+    }
+
+    let list_id = match r.query.get_i64("list_id") {
+        Some(id) => id,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    match store.list(list_id)? {
+        Some(list) if list.user_id == Some(r.user().user_id) => {}
+        _ => {
+            return Ok(not_found());
+        }
+    }
+
+    let sort_index = r.query.get_i64("sort_index");
+
+    match action {
+        "rename" => match name {
+            Some(name) => store.rename_list(list_id, name)?,
+            None => {
+                return Ok(bad_request());
+            }
+        },
+        "delete" => store.delete_list(list_id)?,
+        "add" => {
+            let index = r.index();
+            let mut tracks: Vec<Track> = Vec::new();
+
+            for track_id in r.query.get_str("track_id").unwrap_or_default().split(',') {
+                let track_id: i64 = match track_id.parse() {
+                    Ok(id) => id,
+                    Err(_) => {
+                        return Ok(bad_request());
+                    }
+                };
+
+                match index.track(track_id)? {
+                    Some(t) => tracks.push(t),
+                    None => {
+                        return Ok(not_found());
+                    }
+                }
+            }
+
+            store.add_list_tracks(list_id, &tracks, sort_index)?;
+        }
+        "remove" => {
+            if !store.remove_list_track(list_id, sort_index.unwrap_or(-1))? {
+                return Ok(not_found());
+            }
+        }
+        "move" => {
+            let to_index = r.query.get_i64("to_index").unwrap_or(-1);
+
+            if !store.move_list_track(list_id, sort_index.unwrap_or(-1), to_index)? {
+                return Ok(bad_request());
+            }
+        }
+        _ => {
+            return Ok(bad_request());
+        }
+    }
+
+    Ok(json_ok("{}"))
+}
+
+fn api_scan(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    if let Some(action) = r.query.get_str("action") {
+        match action {
+            "start" => {
+                r.musicd
+                    .scan_thread
+                    .start(r.musicd.index(), r.musicd.store());
+            }
+            "restart" => {
+                r.musicd.scan_thread.stop();
+                r.musicd
+                    .scan_thread
+                    .start(r.musicd.index(), r.musicd.store());
+            }
+            "stop" => {
+                r.musicd.scan_thread.stop();
+            }
+            _ => {}
+        }
+    }
+
+    Ok(json_ok(
+        &json!({
+            "running": r.musicd.scan_thread.is_running()
+        })
+        .to_string(),
+    ))
+}
+
+/// Shows the album cover patterns, or on POST sets them from the comma-separated `patterns`
+/// and picks the covers of all albums again. Empty `patterns` reverts to the configured ones.
+fn api_cover_patterns(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let mut store = r.musicd.store();
+
+    if *r.request.method() == Method::POST {
+        let patterns: Option<Vec<String>> = r
+            .query
+            .get_str("patterns")
+            .filter(|p| !p.is_empty())
+            .map(|p| p.split(',').map(|p| p.to_string()).collect());
+
+        store.set_cover_patterns(patterns.as_deref())?;
+    }
+
+    let custom_patterns = store.cover_patterns()?;
+    let custom = custom_patterns.is_some();
+    let patterns = custom_patterns.unwrap_or_else(|| scan::config_cover_patterns(&r.musicd.config));
+
+    if *r.request.method() == Method::POST {
+        let index = r.index();
+
+        index.set_album_image_patterns(&patterns)?;
+        index.update_album_images(None)?;
+    }
+
+    Ok(json_ok(
+        &json!({
+            "patterns": patterns,
+            "custom": custom
+        })
+        .to_string(),
+    ))
+}
+
+fn api_me(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    Ok(json_ok(&json!(r.user()).to_string()))
+}
+
+/// Sessions and API keys of the user.
+fn api_sessions(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let sessions = r
+        .musicd
+        .store()
+        .sessions(r.user().user_id, crate::scan::unix_time())?;
+
+    Ok(json_ok(
+        &json!({
+            "session_id": r.session.as_ref().map(|s| s.session_id),
+            "total": sessions.len(),
+            "items": sessions
+        })
+        .to_string(),
+    ))
+}
+
+/// Creates a named API key, or revokes a session or API key of the user by `action`. The
+/// token of a new API key is only returned here.
+fn api_session(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let mut store = r.musicd.store();
+
+    match r.query.get_str("action") {
+        Some("create") => {
+            let name = match r.query.get_str("name").filter(|n| !n.is_empty()) {
+                Some(n) => n,
+                None => {
+                    return Ok(bad_request());
+                }
+            };
+
+            let (session, token) = store.create_session(
+                r.user().user_id,
+                true,
+                Some(name),
+                crate::scan::unix_time(),
+                None,
+            )?;
+
+            Ok(json_ok(
+                &json!({
+                    "session": session,
+                    "token": token,
+                })
+                .to_string(),
+            ))
+        }
+        Some("revoke") => {
+            let session_id = match r.query.get_i64("session_id") {
+                Some(id) => id,
+                None => {
+                    return Ok(bad_request());
+                }
+            };
+
+            match store.session(session_id)? {
+                Some(session) if session.user_id == r.user().user_id => {
+                    store.delete_session(session_id)?;
+                }
+                _ => {
+                    return Ok(not_found());
+                }
+            }
+
+            Ok(json_ok("{}"))
+        }
+        _ => Ok(bad_request()),
+    }
+}
+
+/// Subsonic password of the user, which Subsonic clients authenticate with. POST with
+/// `action` generates a new one or deletes it.
+fn api_subsonic_password(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let mut store = r.musicd.store();
+    let user_id = r.user().user_id;
+
+    let password = match (r.request.method(), r.query.get_str("action")) {
+        (&Method::GET, _) => store.subsonic_password(user_id)?,
+        (_, Some("create")) => Some(store.reset_subsonic_password(user_id)?),
+        (_, Some("delete")) => {
+            store.delete_subsonic_password(user_id)?;
+            None
+        }
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    Ok(json_ok(&json!({ "password": password }).to_string()))
+}
+
+/// Seconds a share is valid if `expires` isn't given
+const DEFAULT_SHARE_LIFETIME: i64 = 7 * 24 * 60 * 60;
+
+/// Share with its token and the URL of the share page, the token being `unlocked` for one
+/// not needing the password.
+fn share_json(store: &Store, share: &Share, unlocked: bool) -> Result<serde_json::Value, Error> {
+    let token = store.share_token(share, unlocked)?;
+
+    let mut value = json!(share);
+    value["url"] = json!(format!("/share?share={}", token));
+    value["token"] = json!(token);
+
+    Ok(value)
+}
+
+fn api_shares(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let store = r.musicd.store();
+
+    let items = store
+        .shares(r.user().user_id)?
+        .iter()
+        .map(|share| share_json(&store, share, false))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    Ok(json_ok(
+        &json!({
+            "total": items.len(),
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+/// Shares addressed to the user, with tokens that don't need the password.
+fn api_received_shares(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let store = r.musicd.store();
+
+    let items = store
+        .received_shares(r.user().user_id, crate::scan::unix_time())?
+        .iter()
+        .map(|share| share_json(&store, share, true))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    Ok(json_ok(
+        &json!({
+            "total": items.len(),
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+/// Shares one of `track_id`, `album_id` or `list_id`, or deletes a share by `action`. A share
+/// expires at the unix timestamp `expires`, and can be limited to `max_plays` plays, protected
+/// with `password` and addressed to the user named `recipient`.
+fn api_share(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let mut store = r.musicd.store();
+
+    match r.query.get_str("action") {
+        Some("create") => {}
+        Some("delete") => {
+            let share_id = match r.query.get_i64("share_id") {
+                Some(id) => id,
+                None => {
+                    return Ok(bad_request());
+                }
+            };
+
+            match store.share(share_id)? {
+                Some(share) if share.user_id == r.user().user_id => {
+                    store.delete_share(share_id)?;
+                }
+                _ => {
+                    return Ok(not_found());
+                }
+            }
+
+            return Ok(json_ok("{}"));
+        }
+        _ => {
+            return Ok(bad_request());
+        }
+    }
+
+    let now = crate::scan::unix_time();
+
+    let expires = r
+        .query
+        .get_i64("expires")
+        .unwrap_or(now + DEFAULT_SHARE_LIFETIME);
+    let max_plays = r.query.get_i64("max_plays");
+
+    if expires <= now || max_plays.is_some_and(|m| m < 1) {
+        return Ok(bad_request());
+    }
+
+    let password = r.query.get_str("password").filter(|p| !p.is_empty());
+
+    let recipient_id = match r.query.get_str("recipient") {
+        Some(name) => match store.user_by_name(name)? {
+            Some(user) => Some(user.user_id),
+            None => {
+                return Ok(not_found());
+            }
+        },
+        None => None,
+    };
+
+    let index = r.index();
+
+    let track = match r.query.get_i64("track_id") {
+        Some(track_id) => match index.track(track_id)? {
+            Some(track) => Some(track),
+            None => {
+                return Ok(not_found());
+            }
+        },
+        None => None,
+    };
+
+    let album = match r.query.get_i64("album_id") {
+        Some(album_id) => match index.album(album_id)? {
+            Some(album) => Some(album),
+            None => {
+                return Ok(not_found());
+            }
+        },
+        None => None,
+    };
+
+    let list_id = match r.query.get_i64("list_id") {
+        Some(list_id) => match store.list(list_id)? {
+            Some(list) if list.user_id == Some(r.user().user_id) => Some(list_id),
+            _ => {
+                return Ok(not_found());
+            }
+        },
+        None => None,
+    };
+
+    let item = match (&track, &album, list_id) {
+        (Some(track), None, None) => SharedItem::Track(track),
+        (None, Some(album), None) => SharedItem::Album(album),
+        (None, None, Some(list_id)) => SharedItem::List(list_id),
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    let options = ShareOptions {
+        recipient_id,
+        password,
+        expires: Some(expires),
+        max_plays,
+    };
+
+    let share = store.create_share(r.user().user_id, item, options, now)?;
+
+    Ok(json_ok(&share_json(&store, &share, false)?.to_string()))
+}
+
+/// Describes the share of the request, with its tracks if `unlocked`.
+fn share_info(r: &ApiRequest, unlocked: bool) -> Result<serde_json::Value, Error> {
+    let share = r.share.as_ref().unwrap();
+
+    let mut result = json!({
+        "share_id": share.share_id,
+        "has_password": share.has_password,
+        "locked": !unlocked,
+        "expires": share.expires,
+        "plays_left": share.max_plays.map(|m| (m - share.play_count).max(0)),
+    });
+
+    if !unlocked {
+        return Ok(result);
+    }
+
+    let index = r.index();
+
+    let mut tracks = Vec::new();
+
+    for track_id in shared_track_ids(r)? {
+        let track = match index.track(track_id)? {
+            Some(t) => t,
+            None => continue,
+        };
+
+        let image_id = index.album(track.album_id)?.and_then(|a| a.image_id);
+
+        tracks.push(json!({
+            "track_id": track.track_id,
+            "number": track.number,
+            "title": track.title,
+            "artist_name": track.artist_name,
+            "album_id": track.album_id,
+            "album_name": track.album_name,
+            "length": track.length,
+            "image_id": image_id,
+        }));
+    }
+
+    if let Some(album_id) = share.album_id {
+        if let Some(album) = index.album(album_id)? {
+            result["name"] = json!(album.name);
+            result["artist_name"] = json!(album.artist_name);
+            result["image_id"] = json!(album.image_id);
+        }
+    } else if let Some(list_id) = share.list_id {
+        if let Some(list) = r.musicd.store().list(list_id)? {
+            result["name"] = json!(list.name);
+        }
+    } else if let Some(track) = tracks.first() {
+        result["name"] = track["title"].clone();
+        result["artist_name"] = track["artist_name"].clone();
+        result["image_id"] = track["image_id"].clone();
+    }
+
+    result["tracks"] = json!(tracks);
+
+    Ok(result)
+}
+
+fn api_share_info(r: &ApiRequest, unlocked: bool) -> Result<Response<Body>, Error> {
+    Ok(json_ok(&share_info(r, unlocked)?.to_string()))
+}
+
+/// Base URL for absolute links, `public_url` from the config or taken from the request.
+fn public_url(r: &ApiRequest) -> String {
+    match &r.musicd.config.public_url {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => format!(
+            "http://{}",
+            r.request
+                .headers()
+                .get("Host")
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("localhost")
+        ),
+    }
+}
+
+/// What link previews show of a share.
+struct ShareSummary {
+    url: String,
+    title: String,
+    description: Option<String>,
+    /// Open Graph type
+    og_type: &'static str,
+    artist_name: Option<String>,
+    image_url: Option<String>,
+    duration: Option<f64>,
+}
+
+/// Size of the cover in link previews
+const SHARE_IMAGE_SIZE: i64 = 600;
+
+fn share_summary(r: &ApiRequest, token: &str, unlocked: bool) -> Result<ShareSummary, Error> {
+    let share = r.share.as_ref().unwrap();
+    let base_url = public_url(r);
+    let token = crate::http_util::encode_url_component(token);
+
+    let mut summary = ShareSummary {
+        url: format!("{}/share?share={}", base_url, token),
+        title: "Password protected share".to_string(),
+        description: None,
+        og_type: "website",
+        artist_name: None,
+        image_url: None,
+        duration: None,
+    };
+
+    if !unlocked {
+        return Ok(summary);
+    }
+
+    let info = share_info(r, unlocked)?;
+    let tracks = info["tracks"].as_array().map_or(0, |t| t.len());
+
+    summary.title = info["name"].as_str().unwrap_or("Shared music").to_string();
+    summary.artist_name = info["artist_name"].as_str().map(|a| a.to_string());
+
+    summary.image_url = info["image_id"].as_i64().map(|image_id| {
+        format!(
+            "{}/api/image_file?image_id={}&size={}&crop=1&share={}",
+            base_url, image_id, SHARE_IMAGE_SIZE, token
+        )
+    });
+
+    if share.track_id.is_some() {
+        let track = &info["tracks"][0];
+
+        summary.og_type = "music.song";
+        summary.description = track["album_name"].as_str().map(|a| format!("on {}", a));
+        summary.duration = track["length"].as_f64();
+    } else {
+        summary.og_type = if share.album_id.is_some() {
+            "music.album"
+        } else {
+            "music.playlist"
+        };
+        summary.description = Some(match tracks {
+            1 => "1 track".to_string(),
+            n => format!("{} tracks", n),
+        });
+    }
+
+    Ok(summary)
+}
+
+/// Open Graph tags and the oEmbed link of a share page.
+fn share_metadata(r: &ApiRequest, token: &str, unlocked: bool) -> Result<String, Error> {
+    let summary = share_summary(r, token, unlocked)?;
+
+    let title = match &summary.artist_name {
+        Some(artist_name) => format!("{} by {}", summary.title, artist_name),
+        None => summary.title.clone(),
+    };
+
+    let mut properties = vec![
+        ("og:site_name", "musicd2".to_string()),
+        ("og:type", summary.og_type.to_string()),
+        ("og:url", summary.url.clone()),
+        ("og:title", title.clone()),
+    ];
+
+    if let Some(description) = &summary.description {
+        properties.push(("og:description", description.clone()));
+    }
+
+    if let Some(image_url) = &summary.image_url {
+        properties.push(("og:image", image_url.clone()));
+        properties.push(("og:image:width", SHARE_IMAGE_SIZE.to_string()));
+        properties.push(("og:image:height", SHARE_IMAGE_SIZE.to_string()));
+    }
+
+    if let Some(duration) = summary.duration {
+        properties.push(("music:duration", (duration.round() as i64).to_string()));
+    }
+
+    let escape = crate::http_util::escape_html;
+
+    let mut metadata: Vec<String> = properties
+        .iter()
+        .map(|(property, content)| {
+            format!(
+                "<meta property=\"{}\" content=\"{}\" />",
+                property,
+                escape(content)
+            )
+        })
+        .collect();
+
+    metadata.push("<meta name=\"twitter:card\" content=\"summary\" />".to_string());
+
+    metadata.push(format!(
+        "<link rel=\"alternate\" type=\"application/json+oembed\" href=\"{}\" title=\"{}\" />",
+        escape(&format!(
+            "{}/api/oembed?format=json&url={}",
+            public_url(r),
+            crate::http_util::encode_url_component(&summary.url)
+        )),
+        escape(&title)
+    ));
+
+    Ok(metadata.join("\n        "))
+}
+
+/// Default size of the player embedded with oEmbed, fitting the share page
+const SHARE_EMBED_WIDTH: i64 = 350;
+const SHARE_EMBED_HEIGHT: i64 = 500;
+
+/// oEmbed description of the share page in `url`, with the page embedded as the player.
+fn api_oembed(r: &ApiRequest, token: &str, unlocked: bool) -> Result<Response<Body>, Error> {
+    if r.query.get_str("format").is_some_and(|f| f != "json") {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_IMPLEMENTED)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    let summary = share_summary(r, token, unlocked)?;
+
+    let width = r
+        .query
+        .get_i64("maxwidth")
+        .map_or(SHARE_EMBED_WIDTH, |w| w.min(SHARE_EMBED_WIDTH));
+    let height = r
+        .query
+        .get_i64("maxheight")
+        .map_or(SHARE_EMBED_HEIGHT, |h| h.min(SHARE_EMBED_HEIGHT));
+
+    let mut result = json!({
+        "version": "1.0",
+        "type": "rich",
+        "provider_name": "musicd2",
+        "provider_url": public_url(r),
+        "title": summary.title,
+        "html": format!(
+            "<iframe src=\"{}\" width=\"{}\" height=\"{}\" frameborder=\"0\" allow=\"autoplay\"></iframe>",
+            crate::http_util::escape_html(&summary.url),
+            width,
+            height
+        ),
+        "width": width,
+        "height": height,
+    });
+
+    if let Some(artist_name) = summary.artist_name {
+        result["author_name"] = json!(artist_name);
+    }
+
+    if let Some(image_url) = summary.image_url {
+        result["thumbnail_url"] = json!(image_url);
+        result["thumbnail_width"] = json!(SHARE_IMAGE_SIZE);
+        result["thumbnail_height"] = json!(SHARE_IMAGE_SIZE);
+    }
+
+    Ok(json_ok(&result.to_string()))
+}
+
+/// Starts a play of `track_id` through the share of the request, returning the token to
+/// stream it with. Refused once the share has no plays left.
+fn api_share_play(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let track = match r.query.get_i64("track_id") {
+        Some(track_id) => match r.index().track(track_id)? {
+            Some(t) => t,
+            None => {
+                return Ok(not_found());
+            }
+        },
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    match start_share_play(r, &track)? {
+        Some(play) => Ok(json_ok(&json!({ "play": play }).to_string())),
+        None => Ok(forbidden()),
+    }
+}
+
+/// Returns an unlocked token for the share of the request if `password` matches.
+fn api_share_unlock(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let share = r.share.as_ref().unwrap();
+    let password = r.query.get_str("password").unwrap_or_default();
+
+    match r.musicd.store().unlock_share(share, password)? {
+        Some(token) => Ok(json_ok(&json!({ "token": token }).to_string())),
+        None => Ok(unauthorized()),
+    }
+}
+
+fn api_users(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let users = r.musicd.store().users()?;
+
+    Ok(json_ok(
+        &json!({
+            "total": users.len(),
+            "items": users
+        })
+        .to_string(),
+    ))
+}
+
+/// Creates, updates or deletes a user by `action`. `roots` is a comma-separated list of the
+/// root names the user can access, empty for all.
+fn api_user(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let action = match r.query.get_str("action") {
+        Some(a) => a,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    let password = r.query.get_str("password");
+
+    let role = match r.query.get_str("role") {
+        Some(role) => match Role::from_str(role) {
+            Some(role) => Some(role),
+            None => {
+                return Ok(bad_request());
+            }
+        },
+        None => None,
+    };
+
+    let roots: Option<Vec<String>> = r.query.get_str("roots").map(|roots| {
+        roots
+            .split(',')
+            .filter(|root| !root.is_empty())
+            .map(|root| root.to_string())
+            .collect()
+    });
+
+    let mut store = r.musicd.store();
+
+    let user = if action == "create" {
+        let name = match r.query.get_str("name").filter(|n| !n.is_empty()) {
+            Some(n) => n,
+            None => {
+                return Ok(bad_request());
+            }
+        };
+
+        if store.user_by_name(name)?.is_some() {
+            return Ok(bad_request());
+        }
+
+        store.create_user(
+            name,
+            password.unwrap_or_default(),
+            role.unwrap_or(Role::Listener),
+        )?
+    } else {
+        let user_id = match r.query.get_i64("user_id") {
+            Some(id) => id,
+            None => {
+                return Ok(bad_request());
+            }
+        };
+
+        match store.user(user_id)? {
+            Some(u) => u,
+            None => {
+                return Ok(not_found());
+            }
+        }
+    };
+
+    match action {
+        "create" | "update" => {
+            if action == "update" {
+                store.update_user(user.user_id, password, role)?;
+            }
+
+            if let Some(roots) = roots {
+                store.set_user_roots(user.user_id, &roots)?;
+            }
+        }
+        "delete" => {
+            if user.user_id == r.user().user_id {
+                return Ok(bad_request());
+            }
+
+            store.delete_user(user.user_id)?;
+
+            return Ok(json_ok("{}"));
+        }
+        _ => {
+            return Ok(bad_request());
+        }
+    }
+
+    Ok(json_ok(&json!(store.user(user.user_id)?).to_string()))
+}
+
+fn api_scan_status(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    Ok(json_ok(&json!(r.musicd.scan_thread.status()).to_string()))
+}
+
+fn api_scan_history(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) = crate::query::query_scans(&r.index(), &r.query)?;
+
+    Ok(json_ok(
+        &json!({
+            "total": total,
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+static SHARE_HTML: &str = include_str!("./share.html");
+
+/// Share page, with link preview metadata if the request has a valid share token.
+fn res_share(r: &ApiRequest, unlocked: bool) -> Result<Response<Body>, Error> {
+    let metadata = match (&r.share, request_share_token(r)) {
+        (Some(_), Some(token)) => share_metadata(r, &token, unlocked)?,
+        _ => String::new(),
+    };
 
-fn res_share(_r: &ApiRequest) -> Result<Response<Body>, Error> {
     Ok(Response::builder()
         .header("Content-Type", "text/html; charset=utf-8")
-        .body(SHARE_HTML.into())
+        .body(SHARE_HTML.replace("<!-- metadata -->", &metadata).into())
         .unwrap())
 }
@@ -1,5 +1,6 @@
 use std::collections::{BTreeMap, HashMap};
 
+use chrono::{DateTime, TimeZone, Utc};
 use hyper::header::ToStrError;
 use hyper::HeaderMap;
 
@@ -10,9 +11,9 @@ pub fn parse_cookies(headers: &HeaderMap) -> Result<HashMap<String, String>, ToS
         match cookie_header.to_str() {
             Ok(cookie_headers) => {
                 for c in cookie_headers.split(';') {
-                    let mut parts = c.split('=');
+                    let mut parts = c.splitn(2, '=');
                     cookies.insert(
-                        parts.next().unwrap().to_string(),
+                        parts.next().unwrap().trim().to_string(),
                         parts.next().unwrap_or_default().to_string(),
                     );
                 }
@@ -26,12 +27,172 @@ pub fn parse_cookies(headers: &HeaderMap) -> Result<HashMap<String, String>, ToS
     Ok(cookies)
 }
 
+/// Token from an `Authorization: Bearer <token>` header.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get("Authorization")?.to_str().ok()?;
+
+    let mut parts = value.splitn(2, ' ');
+
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+            Some(token.trim()).filter(|t| !t.is_empty())
+        }
+        _ => None,
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, for use in URL query values.
+pub fn encode_url_component(s: &str) -> String {
+    let mut result = String::new();
+
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                result.push(b as char)
+            }
+            _ => result.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    result
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Escapes text for HTML or XML content and quoted attribute values.
+pub fn escape_html(s: &str) -> String {
+    let mut result = String::new();
+
+    for c in s.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+
+    result
+}
+
+/// Single byte range from a `Range` request header.
+#[derive(Debug, PartialEq)]
+pub enum ByteRange {
+    /// `bytes=first-`
+    From(u64),
+    /// `bytes=first-last`
+    FromTo(u64, u64),
+    /// `bytes=-suffix_length`
+    Last(u64),
+}
+
+impl ByteRange {
+    /// Resolves the range against a resource of `total` bytes, returning the offset and length
+    /// of the range, or `None` if it is unsatisfiable.
+    pub fn resolve(&self, total: u64) -> Option<(u64, u64)> {
+        let (first, last) = match *self {
+            ByteRange::From(first) => (first, total.checked_sub(1)?),
+            ByteRange::FromTo(first, last) => (first, std::cmp::min(last, total.checked_sub(1)?)),
+            ByteRange::Last(0) => return None,
+            ByteRange::Last(length) => (total.saturating_sub(length), total.checked_sub(1)?),
+        };
+
+        if first > last {
+            return None;
+        }
+
+        Some((first, last - first + 1))
+    }
+}
+
+/// Parses the `Range` header. Only a single range in bytes is supported, anything else is
+/// treated as if there was no header, which means serving the whole resource.
+pub fn parse_range(headers: &HeaderMap) -> Option<ByteRange> {
+    let value = headers.get("Range")?.to_str().ok()?.trim();
+
+    if !value.starts_with("bytes=") || value.contains(',') {
+        return None;
+    }
+
+    let mut parts = value["bytes=".len()..].splitn(2, '-');
+    let first = parts.next()?.trim();
+    let last = parts.next()?.trim();
+
+    match (first.is_empty(), last.is_empty()) {
+        (false, true) => Some(ByteRange::From(first.parse().ok()?)),
+        (false, false) => {
+            let (first, last) = (first.parse().ok()?, last.parse().ok()?);
+
+            // Invalid rather than unsatisfiable, so the header is ignored
+            if last < first {
+                return None;
+            }
+
+            Some(ByteRange::FromTo(first, last))
+        }
+        (true, false) => Some(ByteRange::Last(last.parse().ok()?)),
+        (true, true) => None,
+    }
+}
+
+/// Formats a unix timestamp as an HTTP date, as in `Last-Modified`.
+pub fn http_date(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0)
+        .unwrap()
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// Checks the `If-None-Match` and `If-Modified-Since` request headers against a response with
+/// `etag` last modified at `modified`, returning true if the client already has it.
+pub fn not_modified(headers: &HeaderMap, etag: &str, modified: i64) -> bool {
+    if let Some(value) = headers.get("If-None-Match") {
+        return match value.to_str() {
+            Ok(value) => value
+                .split(',')
+                .map(|t| t.trim())
+                .any(|t| t == "*" || t.trim_start_matches("W/") == etag),
+            Err(_) => false,
+        };
+    }
+
+    match headers
+        .get("If-Modified-Since")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+    {
+        Some(since) => modified <= since.timestamp(),
+        None => false,
+    }
+}
+
 #[derive(Debug)]
 pub struct HttpQuery {
     value: BTreeMap<String, String>,
 }
 
 impl HttpQuery {
+    pub fn new() -> HttpQuery {
+        HttpQuery {
+            value: BTreeMap::new(),
+        }
+    }
+
     pub fn from(s: &str) -> HttpQuery {
         let mut query = HttpQuery {
             value: BTreeMap::new(),
@@ -79,6 +240,10 @@ impl HttpQuery {
         String::from_utf8_lossy(&result).into_owned()
     }
 
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.value.insert(key.to_string(), value.to_string());
+    }
+
     pub fn get_str(&self, key: &str) -> Option<&str> {
         match self.value.get(key) {
             Some(s) => Some(s),
@@ -96,3 +261,78 @@ impl HttpQuery {
         }
     }
 }
+
+#[test]
+fn test_byte_range() {
+    let mut headers = HeaderMap::new();
+
+    headers.insert("Range", "bytes=100-".parse().unwrap());
+    assert_eq!(parse_range(&headers), Some(ByteRange::From(100)));
+    assert_eq!(ByteRange::From(100).resolve(1000), Some((100, 900)));
+    assert_eq!(ByteRange::From(1000).resolve(1000), None);
+
+    headers.insert("Range", "bytes=0-499".parse().unwrap());
+    assert_eq!(parse_range(&headers), Some(ByteRange::FromTo(0, 499)));
+    assert_eq!(ByteRange::FromTo(0, 499).resolve(1000), Some((0, 500)));
+    assert_eq!(ByteRange::FromTo(900, 1999).resolve(1000), Some((900, 100)));
+
+    headers.insert("Range", "bytes=-200".parse().unwrap());
+    assert_eq!(parse_range(&headers), Some(ByteRange::Last(200)));
+    assert_eq!(ByteRange::Last(200).resolve(1000), Some((800, 200)));
+    assert_eq!(ByteRange::Last(2000).resolve(1000), Some((0, 1000)));
+
+    headers.insert("Range", "bytes=0-1,5-6".parse().unwrap());
+    assert_eq!(parse_range(&headers), None);
+
+    headers.insert("Range", "bytes=500-100".parse().unwrap());
+    assert_eq!(parse_range(&headers), None);
+}
+
+#[test]
+fn test_not_modified() {
+    let mut headers = HeaderMap::new();
+    assert!(!not_modified(&headers, "\"abc\"", 1000));
+
+    headers.insert("If-None-Match", "\"xyz\", W/\"abc\"".parse().unwrap());
+    assert!(not_modified(&headers, "\"abc\"", 1000));
+    assert!(!not_modified(&headers, "\"def\"", 1000));
+
+    let mut headers = HeaderMap::new();
+    headers.insert("If-Modified-Since", http_date(1000).parse().unwrap());
+    assert!(not_modified(&headers, "\"abc\"", 1000));
+    assert!(!not_modified(&headers, "\"abc\"", 1001));
+}
+
+#[test]
+fn test_auth_headers() {
+    let mut headers = HeaderMap::new();
+    assert_eq!(bearer_token(&headers), None);
+
+    headers.insert("Cookie", "a=1; musicd2-session=abc=".parse().unwrap());
+    let cookies = parse_cookies(&headers).unwrap();
+    assert_eq!(cookies.get("a").map(|c| c.as_str()), Some("1"));
+    assert_eq!(
+        cookies.get("musicd2-session").map(|c| c.as_str()),
+        Some("abc=")
+    );
+
+    headers.insert("Authorization", "Bearer abc".parse().unwrap());
+    assert_eq!(bearer_token(&headers), Some("abc"));
+
+    headers.insert("Authorization", "Basic abc".parse().unwrap());
+    assert_eq!(bearer_token(&headers), None);
+}
+
+#[test]
+fn test_escape() {
+    assert_eq!(encode_url_component("a b/c?d=é"), "a%20b%2Fc%3Fd%3D%C3%A9");
+    assert_eq!(
+        escape_html("<a href=\"x\">Tom & Jerry's</a>"),
+        "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+    );
+
+    assert_eq!(encode_hex(&[0x0a, 0xff]), "0aff");
+    assert_eq!(decode_hex("0aff"), Some(vec![0x0a, 0xff]));
+    assert_eq!(decode_hex("abc"), None);
+    assert_eq!(decode_hex("zz"), None);
+}
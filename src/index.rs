@@ -28,6 +28,71 @@ impl NodeType {
     }
 }
 
+/// Normalizes an artist name for matching: lowercase, with whitespace collapsed and a leading
+/// "The " or trailing ", The" removed.
+pub fn artist_key(name: &str) -> String {
+    let key = name
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase();
+
+    match key
+        .strip_prefix("the ")
+        .or_else(|| key.strip_suffix(", the"))
+    {
+        Some(k) => k.to_string(),
+        None => key,
+    }
+}
+
+/// Role of an artist credited on a track in `TrackArtist`
+pub const ARTIST_ROLE_MAIN: &str = "main";
+pub const ARTIST_ROLE_FEATURED: &str = "featured";
+
+/// Album artist of compilations
+pub const VARIOUS_ARTISTS: &str = "Various Artists";
+
+/// Cover of `Album`: the image chosen in the store, or the album image matching the earliest
+/// `AlbumImagePattern`
+const ALBUM_COVER: &str = "coalesce(
+    (
+        SELECT StoreAlbumCover.image_id
+        FROM StoreAlbumCover
+        WHERE StoreAlbumCover.album_id = Album.album_id
+    ),
+    (
+        SELECT image.image_id
+        FROM AlbumImage album_image
+        INNER JOIN Image image ON image.image_id = album_image.image_id
+        LEFT OUTER JOIN AlbumImagePattern pattern ON image.description LIKE pattern.pattern
+        WHERE album_image.album_id = Album.album_id
+        ORDER BY
+            pattern.rowid IS NULL ASC,
+            pattern.rowid ASC,
+            image.description COLLATE NOCASE ASC
+    ))";
+
+/// Returns the disc number of a directory named like `CD1`, `CD 2` or `Disc 3 - Title`.
+pub fn disc_directory_number(name: &Path) -> Option<i64> {
+    let name = name.to_str()?.to_lowercase();
+
+    let rest = ["disc", "disk", "cd"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))?
+        .trim_start_matches(&[' ', '-', '_', '.'][..]);
+
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+
+    if rest[digits..].starts_with(char::is_alphanumeric) {
+        return None;
+    }
+
+    rest[..digits].parse().ok()
+}
+
 #[derive(Debug, Clone)]
 pub struct Node {
     pub node_id: i64,
@@ -39,7 +104,7 @@ pub struct Node {
     pub modified: i64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Track {
     pub track_id: i64,
     pub node_id: i64,
@@ -55,6 +120,24 @@ pub struct Track {
     pub album_artist_id: Option<i64>,
     pub album_artist_name: Option<String>,
     pub length: f64,
+    pub date: Option<String>,
+    pub year: Option<i64>,
+    pub original_date: Option<String>,
+    /// Multiple genres separated with `;`
+    pub genre: Option<String>,
+    pub disc_number: Option<i64>,
+    pub disc_total: Option<i64>,
+    pub track_total: Option<i64>,
+    pub composer: Option<String>,
+    pub performer: Option<String>,
+    pub label: Option<String>,
+    pub catalog_number: Option<String>,
+    pub comment: Option<String>,
+    pub musicbrainz_track_id: Option<String>,
+    pub musicbrainz_release_id: Option<String>,
+    pub musicbrainz_artist_id: Option<String>,
+    /// Set with the compilation tag
+    pub compilation: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -74,18 +157,31 @@ pub struct Album {
     pub artist_id: Option<i64>,
     pub artist_name: Option<String>,
     pub image_id: Option<i64>,
+    /// Credited to `VARIOUS_ARTISTS` instead of a single artist
+    pub compilation: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct Artist {
     pub artist_id: i64,
     pub name: String,
+    /// Normalized name, see `artist_key`
+    pub key: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Genre {
+    pub genre_id: i64,
+    pub name: String,
 }
 
 #[derive(Debug, Clone)]
 pub struct TrackLyrics {
     pub track_id: i64,
+    /// Plain text, without timestamps
     pub lyrics: Option<String>,
+    /// Original LRC text of synchronized lyrics
+    pub synced: Option<String>,
     pub provider: Option<String>,
     pub source: Option<String>,
     pub modified: i64,
@@ -99,6 +195,8 @@ pub struct IndexSource {
 pub struct Index {
     conn: Connection,
     roots: Arc<Vec<Root>>,
+    /// Set by `restrict_roots`, queries then only return items under `roots`
+    restricted: bool,
 }
 
 impl IndexSource {
@@ -108,7 +206,12 @@ impl IndexSource {
         let source = IndexSource { db_path, roots };
 
         let mut index = source.get()?;
-        if !db_meta::ensure_schema(&mut index.conn, schema::INDEX_SCHEMA)? {
+        if !db_meta::ensure_schema(
+            &mut index.conn,
+            &source.db_path,
+            schema::INDEX_SCHEMA,
+            schema::INDEX_MIGRATIONS,
+        )? {
             return Ok(None);
         }
 
@@ -136,6 +239,7 @@ impl IndexSource {
         Ok(Index {
             conn,
             roots: self.roots.clone(),
+            restricted: false,
         })
     }
 }
@@ -145,6 +249,10 @@ impl Index {
         &self.roots
     }
 
+    pub fn is_restricted(&self) -> bool {
+        self.restricted
+    }
+
     pub fn connection(&self) -> &Connection {
         &self.conn
     }
@@ -153,6 +261,27 @@ impl Index {
         &mut self.conn
     }
 
+    /// Limits `roots`, `map_fs_path` and the queries in `query` to the named roots, if any
+    /// are given.
+    pub fn restrict_roots(&mut self, names: &[String]) {
+        if names.is_empty() {
+            return;
+        }
+
+        self.restricted = true;
+
+        self.roots = Arc::new(
+            self.roots
+                .iter()
+                .filter(|r| names.contains(&r.name))
+                .map(|r| Root {
+                    name: r.name.clone(),
+                    path: r.path.clone(),
+                })
+                .collect(),
+        );
+    }
+
     pub fn map_fs_path(&self, path: &Path) -> Option<PathBuf> {
         let mut iter = path.iter();
 
@@ -178,6 +307,13 @@ impl Index {
         Some(result)
     }
 
+    /// File system path of the file holding `track`, `None` if it's outside the roots.
+    pub fn track_fs_path(&self, track: &Track) -> Result<Option<PathBuf>> {
+        Ok(self
+            .node(track.node_id)?
+            .and_then(|node| self.map_fs_path(&node.path)))
+    }
+
     fn _get_node(row: &Row) -> Result<Node> {
         let node_type: i64 = row.get(1)?;
         let name_bytes: Vec<u8> = row.get(4)?;
@@ -342,16 +478,40 @@ impl Index {
         Ok(())
     }
 
-    pub fn clear_node(&self, node_id: i64) -> Result<()> {
+    /// Returns the number of tracks and images removed.
+    pub fn clear_node(&self, node_id: i64) -> Result<(usize, usize)> {
         trace!("clear node node_id={}", node_id);
 
-        self.conn
+        let tracks = self
+            .conn
             .execute("DELETE FROM Track WHERE node_id = ?", &[node_id])?;
 
-        self.conn
+        let images = self
+            .conn
             .execute("DELETE FROM Image WHERE node_id = ?", &[node_id])?;
 
-        Ok(())
+        Ok((tracks, images))
+    }
+
+    /// Returns the number of tracks and images in the node and all nodes below it.
+    pub fn count_node_items(&self, node_id: i64) -> Result<(i64, i64)> {
+        trace!("count node items node_id={}", node_id);
+
+        self.conn.query_row(
+            "WITH RECURSIVE
+                iter(node_id) AS
+                    (
+                        VALUES(?)
+                        UNION ALL
+                        SELECT Node.node_id FROM Node, iter
+                        WHERE Node.parent_id = iter.node_id
+                    )
+            SELECT
+                (SELECT count(*) FROM Track WHERE Track.node_id IN iter),
+                (SELECT count(*) FROM Image WHERE Image.node_id IN iter)",
+            [node_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
     }
 
     fn _get_track(row: &Row) -> Result<Track> {
@@ -370,6 +530,22 @@ impl Index {
             album_artist_id: row.get(11)?,
             album_artist_name: row.get(12)?,
             length: row.get(13)?,
+            date: row.get(14)?,
+            year: row.get(15)?,
+            original_date: row.get(16)?,
+            genre: row.get(17)?,
+            disc_number: row.get(18)?,
+            disc_total: row.get(19)?,
+            track_total: row.get(20)?,
+            composer: row.get(21)?,
+            performer: row.get(22)?,
+            label: row.get(23)?,
+            catalog_number: row.get(24)?,
+            comment: row.get(25)?,
+            musicbrainz_track_id: row.get(26)?,
+            musicbrainz_release_id: row.get(27)?,
+            musicbrainz_artist_id: row.get(28)?,
+            compilation: row.get(29)?,
         })
     }
 
@@ -378,7 +554,9 @@ impl Index {
 
         let mut st = self.conn
             .prepare(
-                "SELECT track_id, node_id, stream_index, track_index, start, number, title, artist_id, artist_name, album_id, album_name, album_artist_id, album_artist_name, length
+                "SELECT track_id, node_id, stream_index, track_index, start, number, title, artist_id, artist_name, album_id, album_name, album_artist_id, album_artist_name, length,
+                    date, year, original_date, genre, disc_number, disc_total, track_total, composer, performer, label, catalog_number, comment,
+                    musicbrainz_track_id, musicbrainz_release_id, musicbrainz_artist_id, compilation
                 FROM Track
                 WHERE track_id = ?"
             )?;
@@ -395,8 +573,10 @@ impl Index {
     pub fn create_track(&self, track: &Track) -> Result<Track> {
         let mut st = self.conn
             .prepare(
-                "INSERT INTO Track (node_id, stream_index, track_index, start, number, title, artist_id, artist_name, album_id, album_name, album_artist_id, album_artist_name, length)
-                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+                "INSERT INTO Track (node_id, stream_index, track_index, start, number, title, artist_id, artist_name, album_id, album_name, album_artist_id, album_artist_name, length,
+                    date, year, original_date, genre, disc_number, disc_total, track_total, composer, performer, label, catalog_number, comment,
+                    musicbrainz_track_id, musicbrainz_release_id, musicbrainz_artist_id, compilation)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
             )?;
 
         st.execute(params![
@@ -413,10 +593,37 @@ impl Index {
             track.album_artist_id,
             track.album_artist_name,
             track.length,
+            track.date,
+            track.year,
+            track.original_date,
+            track.genre,
+            track.disc_number,
+            track.disc_total,
+            track.track_total,
+            track.composer,
+            track.performer,
+            track.label,
+            track.catalog_number,
+            track.comment,
+            track.musicbrainz_track_id,
+            track.musicbrainz_release_id,
+            track.musicbrainz_artist_id,
+            track.compilation,
         ])?;
 
         let result = self.track(self.conn.last_insert_rowid())?.unwrap();
 
+        self.conn.execute(
+            "INSERT INTO TrackSearch (rowid, title, artist_name, album_name)
+            VALUES (?, ?, ?, ?)",
+            params![
+                result.track_id,
+                result.title,
+                result.artist_name,
+                result.album_name
+            ],
+        )?;
+
         debug!("create {:?}", result);
 
         Ok(result)
@@ -476,6 +683,7 @@ impl Index {
         Ok(Artist {
             artist_id: row.get(0)?,
             name: row.get(1)?,
+            key: row.get(2)?,
         })
     }
 
@@ -483,7 +691,7 @@ impl Index {
         trace!("get artist artist_id={}", artist_id);
 
         let mut st = self.conn.prepare(
-            "SELECT artist_id, name
+            "SELECT artist_id, name, key
             FROM Artist
             WHERE artist_id = ?",
         )?;
@@ -497,16 +705,17 @@ impl Index {
         }
     }
 
+    /// Finds an artist by name, ignoring case, whitespace and a leading "The".
     pub fn artist_by_name(&self, name: &str) -> Result<Option<Artist>> {
         trace!("get artist name={}", name);
 
         let mut st = self.conn.prepare(
-            "SELECT artist_id, name
+            "SELECT artist_id, name, key
             FROM Artist
-            WHERE name = ?",
+            WHERE key = ?",
         )?;
 
-        let mut rows = st.query(&[name])?;
+        let mut rows = st.query(&[artist_key(name)])?;
 
         if let Some(row) = rows.next()? {
             Ok(Some(Self::_get_artist(row)?))
@@ -517,19 +726,148 @@ impl Index {
 
     pub fn create_artist(&self, name: &str) -> Result<Artist> {
         let mut st = self.conn.prepare(
-            "INSERT INTO Artist (name)
+            "INSERT INTO Artist (name, key)
+            VALUES (?, ?)",
+        )?;
+
+        st.execute(params![name, artist_key(name)])?;
+
+        let result = self.artist(self.conn.last_insert_rowid())?.unwrap();
+
+        self.conn.execute(
+            "INSERT INTO ArtistSearch (rowid, name) VALUES (?, ?)",
+            params![result.artist_id, result.name],
+        )?;
+
+        debug!("create {:?}", result);
+
+        Ok(result)
+    }
+
+    pub fn link_track_artist(&self, track_id: i64, artist_id: i64, role: &str) -> Result<()> {
+        trace!(
+            "link track artist track_id={} artist_id={} role={}",
+            track_id,
+            artist_id,
+            role
+        );
+
+        self.conn.execute(
+            "INSERT OR IGNORE INTO TrackArtist (track_id, artist_id, role) VALUES (?, ?, ?)",
+            params![track_id, artist_id, role],
+        )?;
+
+        Ok(())
+    }
+
+    /// Deletes artists not credited on any track or album anymore.
+    pub fn delete_unused_artists(&self) -> Result<usize> {
+        trace!("delete unused artists");
+
+        self.conn.execute(
+            "DELETE FROM Artist
+            WHERE
+                artist_id NOT IN (SELECT TrackArtist.artist_id FROM TrackArtist)
+                AND artist_id NOT IN (SELECT Track.artist_id FROM Track)
+                AND artist_id NOT IN
+                    (
+                        SELECT Track.album_artist_id FROM Track
+                        WHERE Track.album_artist_id IS NOT NULL
+                    )
+                AND artist_id NOT IN
+                    (
+                        SELECT Album.artist_id FROM Album
+                        WHERE Album.artist_id IS NOT NULL
+                    )",
+            NO_PARAMS,
+        )
+    }
+
+    fn _get_genre(row: &Row) -> Result<Genre> {
+        Ok(Genre {
+            genre_id: row.get(0)?,
+            name: row.get(1)?,
+        })
+    }
+
+    pub fn genre(&self, genre_id: i64) -> Result<Option<Genre>> {
+        trace!("get genre genre_id={}", genre_id);
+
+        let mut st = self.conn.prepare(
+            "SELECT genre_id, name
+            FROM Genre
+            WHERE genre_id = ?",
+        )?;
+
+        let mut rows = st.query([genre_id])?;
+
+        if let Some(row) = rows.next()? {
+            Ok(Some(Self::_get_genre(row)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Finds a genre by name, ignoring case.
+    pub fn genre_by_name(&self, name: &str) -> Result<Option<Genre>> {
+        trace!("get genre name={}", name);
+
+        let mut st = self.conn.prepare(
+            "SELECT genre_id, name
+            FROM Genre
+            WHERE name = ? COLLATE NOCASE",
+        )?;
+
+        let mut rows = st.query(&[name])?;
+
+        if let Some(row) = rows.next()? {
+            Ok(Some(Self::_get_genre(row)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn create_genre(&self, name: &str) -> Result<Genre> {
+        let mut st = self.conn.prepare(
+            "INSERT INTO Genre (name)
             VALUES (?)",
         )?;
 
         st.execute(params![name])?;
 
-        let result = self.artist(self.conn.last_insert_rowid())?.unwrap();
+        let result = self.genre(self.conn.last_insert_rowid())?.unwrap();
 
         debug!("create {:?}", result);
 
         Ok(result)
     }
 
+    pub fn link_track_genre(&self, track_id: i64, genre_id: i64) -> Result<()> {
+        trace!(
+            "link track genre track_id={} genre_id={}",
+            track_id,
+            genre_id
+        );
+
+        self.conn.execute(
+            "INSERT OR IGNORE INTO TrackGenre (track_id, genre_id) VALUES (?, ?)",
+            [track_id, genre_id],
+        )?;
+
+        Ok(())
+    }
+
+    /// Deletes genres no track is linked to anymore.
+    pub fn delete_unused_genres(&self) -> Result<usize> {
+        trace!("delete unused genres");
+
+        self.conn.execute(
+            "DELETE FROM Genre
+            WHERE genre_id NOT IN (SELECT TrackGenre.genre_id FROM TrackGenre)",
+            NO_PARAMS,
+        )
+    }
+
     fn _get_album(row: &Row) -> Result<Album> {
         Ok(Album {
             album_id: row.get(0)?,
@@ -537,6 +875,7 @@ impl Index {
             artist_id: row.get(2)?,
             artist_name: row.get(3)?,
             image_id: row.get(4)?,
+            compilation: row.get(5)?,
         })
     }
 
@@ -544,7 +883,7 @@ impl Index {
         trace!("get album album_id={}", album_id);
 
         let mut st = self.conn.prepare(
-            "SELECT Album.album_id, Album.name, Album.artist_id, Album.artist_name, Album.image_id
+            "SELECT Album.album_id, Album.name, Album.artist_id, Album.artist_name, Album.image_id, Album.compilation
                 FROM Album
                 WHERE album_id = ?",
         )?;
@@ -565,43 +904,95 @@ impl Index {
 
         let result = self.album(self.conn.last_insert_rowid())?.unwrap();
 
+        self.conn.execute(
+            "INSERT INTO AlbumSearch (rowid, name, artist_name) VALUES (?, ?, ?)",
+            params![result.album_id, result.name, result.artist_name],
+        )?;
+
         debug!("create {:?}", result);
 
         Ok(result)
     }
 
-    pub fn find_album(&self, track_node_id: i64, album_name: &str) -> Result<Option<Album>> {
+    /// Finds the album of a track among tracks in the same directory, or with `multi_disc` also
+    /// among tracks in sibling directories, such as `Album/CD1` and `Album/CD2`. Albums are
+    /// identified by name, album artist and directory. Falls back to an unused album with the
+    /// same name and artist.
+    pub fn find_album(&self, track: &Track, multi_disc: bool) -> Result<Option<Album>> {
         trace!(
-            "find album track_node_id={} album_name={}",
-            track_node_id,
-            album_name
+            "find album track_node_id={} album_name={} album_artist_name={:?} multi_disc={}",
+            track.node_id,
+            track.album_name,
+            track.album_artist_name,
+            multi_disc
         );
 
-        // Search the same directory
-        let mut st = self.conn.prepare(
-            "SELECT Album.album_id, Album.name, Album.artist_id, Album.artist_name, Album.image_id
-                FROM Album
-                INNER JOIN Node AS node ON node.node_id = ?
-                INNER JOIN Node AS other_node ON node.parent_id = node.parent_id
-                INNER JOIN Track AS track ON track.node_id = other_node.node_id
-                WHERE track.album_id = Album.album_id AND Album.name = ?",
-        )?;
+        let directory = match self.node(track.node_id)?.and_then(|n| n.parent_id) {
+            Some(parent_id) => {
+                let album_directory_id = if multi_disc {
+                    self.node(parent_id)?.and_then(|n| n.parent_id)
+                } else {
+                    None
+                };
+
+                match album_directory_id {
+                    Some(album_directory_id) => Some((album_directory_id, 2)),
+                    None => Some((parent_id, 1)),
+                }
+            }
+            None => None,
+        };
 
-        let mut rows = st.query(params![track_node_id, album_name])?;
+        if let Some((directory_id, depth)) = directory {
+            let mut st = self.conn.prepare(
+                "WITH RECURSIVE
+                    iter(node_id, depth) AS
+                        (
+                            VALUES(?, 0)
+                            UNION ALL
+                            SELECT Node.node_id, iter.depth + 1 FROM Node, iter
+                            WHERE iter.depth < ? AND Node.parent_id = iter.node_id
+                        )
+                SELECT Album.album_id, Album.name, Album.artist_id, Album.artist_name, Album.image_id, Album.compilation
+                    FROM Album
+                    INNER JOIN Track ON Track.album_id = Album.album_id
+                    WHERE
+                        Track.node_id IN (SELECT iter.node_id FROM iter)
+                        AND Album.name = ?
+                        AND Track.album_artist_name IS ?
+                    LIMIT 1",
+            )?;
 
-        if let Some(row) = rows.next()? {
-            return Ok(Some(Self::_get_album(row)?));
+            let mut rows = st.query(params![
+                directory_id,
+                depth,
+                track.album_name,
+                track.album_artist_name
+            ])?;
+
+            if let Some(row) = rows.next()? {
+                return Ok(Some(Self::_get_album(row)?));
+            }
         }
 
         // See if there's an unused album
         let mut st = self.conn.prepare(
-            "SELECT Album.album_id, Album.name, Album.artist_id, Album.artist_name, Album.image_id
+            "SELECT Album.album_id, Album.name, Album.artist_id, Album.artist_name, Album.image_id, Album.compilation
                 FROM Album
                 LEFT OUTER JOIN Track ON Track.album_id = Album.album_id
-                WHERE Track.track_id IS NULL AND Album.name = ?",
+                WHERE Track.track_id IS NULL AND Album.name = ? AND Album.artist_name = ?",
         )?;
 
-        let mut rows = st.query(params![album_name])?;
+        let artist_name = if track.compilation {
+            VARIOUS_ARTISTS
+        } else {
+            track
+                .album_artist_name
+                .as_deref()
+                .unwrap_or(&track.artist_name)
+        };
+
+        let mut rows = st.query(params![track.album_name, artist_name])?;
 
         if let Some(row) = rows.next()? {
             return Ok(Some(Self::_get_album(row)?));
@@ -614,9 +1005,10 @@ impl Index {
         Ok(TrackLyrics {
             track_id: row.get(0)?,
             lyrics: row.get(1)?,
-            provider: row.get(2)?,
-            source: row.get(3)?,
-            modified: row.get(4)?,
+            synced: row.get(2)?,
+            provider: row.get(3)?,
+            source: row.get(4)?,
+            modified: row.get(5)?,
         })
     }
 
@@ -624,7 +1016,7 @@ impl Index {
         trace!("get track lyrics track_id={}", track_id);
 
         let mut st = self.conn.prepare(
-            "SELECT TrackLyrics.track_id, TrackLyrics.lyrics, TrackLyrics.provider, TrackLyrics.source, TrackLyrics.modified
+            "SELECT TrackLyrics.track_id, TrackLyrics.lyrics, TrackLyrics.synced, TrackLyrics.provider, TrackLyrics.source, TrackLyrics.modified
                 FROM TrackLyrics
                 WHERE track_id = ?",
         )?;
@@ -639,11 +1031,12 @@ impl Index {
     }
 
     pub fn set_track_lyrics(&self, track_lyrics: &TrackLyrics) -> Result<TrackLyrics> {
-        let mut st = self.conn.prepare("INSERT OR REPLACE INTO TrackLyrics (track_id, lyrics, provider, source, modified) VALUES (?, ?, ?, ?, strftime('%s','now'))")?;
+        let mut st = self.conn.prepare("INSERT OR REPLACE INTO TrackLyrics (track_id, lyrics, synced, provider, source, modified) VALUES (?, ?, ?, ?, ?, strftime('%s','now'))")?;
 
         st.execute(params![
             track_lyrics.track_id,
             track_lyrics.lyrics,
+            track_lyrics.synced,
             track_lyrics.provider,
             track_lyrics.source,
         ])?;
@@ -655,8 +1048,15 @@ impl Index {
         Ok(result)
     }
 
-    pub fn process_node_updates(&self, node_id: i64) -> Result<()> {
-        trace!("process node updates node_id={}", node_id);
+    /// Updates albums with tracks in the directory. Albums without an album artist tag having
+    /// at least `compilation_artists` track artists, none of them on half of the tracks, are
+    /// considered compilations.
+    pub fn process_node_updates(&self, node_id: i64, compilation_artists: i64) -> Result<()> {
+        trace!(
+            "process node updates node_id={} compilation_artists={}",
+            node_id,
+            compilation_artists
+        );
 
         self.conn
             .execute(
@@ -667,20 +1067,22 @@ impl Index {
                             (
                                 SELECT id, name FROM
                                     (
-                                        SELECT Track.album_artist_id AS id, Track.album_artist_name AS name
+                                        SELECT Artist.artist_id AS id, Artist.name AS name
                                         FROM Track
+                                        INNER JOIN Artist ON Artist.artist_id = Track.album_artist_id
                                         WHERE Track.album_id = Album.album_id
-                                        GROUP BY Track.album_artist_id, Track.album_artist_name
-                                        ORDER BY count(Track.album_artist_name) DESC
+                                        GROUP BY Artist.artist_id
+                                        ORDER BY count(Track.track_id) DESC
                                     )
-                                UNION
+                                UNION ALL
                                 SELECT id, name FROM
                                     (
-                                        SELECT Track.artist_id AS id, Track.artist_name AS name
+                                        SELECT Artist.artist_id AS id, Artist.name AS name
                                         FROM Track
+                                        INNER JOIN Artist ON Artist.artist_id = Track.artist_id
                                         WHERE Track.album_id = Album.album_id
-                                        GROUP BY Track.album_artist_id, Track.album_artist_name
-                                        ORDER BY count(Track.artist_name) DESC
+                                        GROUP BY Artist.artist_id
+                                        ORDER BY count(Track.track_id) DESC
                                     )
                             )
                         WHERE id IS NOT NULL
@@ -696,6 +1098,89 @@ impl Index {
                 &[node_id]
             )?;
 
+        self.conn.execute(
+            "UPDATE Album
+            SET compilation =
+                EXISTS (SELECT * FROM Track WHERE Track.album_id = Album.album_id AND Track.compilation)
+                OR ifnull(Album.artist_name, '') COLLATE NOCASE IN ('Various Artists', 'Various', 'VA')
+                OR
+                (
+                    NOT EXISTS
+                        (
+                            SELECT * FROM Track
+                            WHERE Track.album_id = Album.album_id AND Track.album_artist_id IS NOT NULL
+                        )
+                    AND
+                        (
+                            SELECT count(DISTINCT Track.artist_id) FROM Track
+                            WHERE Track.album_id = Album.album_id
+                        ) >= ?
+                    AND
+                        (
+                            SELECT count(Track.track_id) FROM Track
+                            WHERE Track.album_id = Album.album_id
+                            GROUP BY Track.artist_id
+                            ORDER BY count(Track.track_id) DESC
+                            LIMIT 1
+                        ) * 2 < (SELECT count(Track.track_id) FROM Track WHERE Track.album_id = Album.album_id)
+                )
+            WHERE Album.album_id IN
+                (
+                    SELECT Track.album_id
+                    FROM Track
+                    INNER JOIN Node ON Node.parent_id = ?
+                    WHERE Track.node_id = Node.node_id
+                )",
+            [compilation_artists, node_id],
+        )?;
+
+        let compilations: i64 = self.conn.query_row(
+            "SELECT count(Album.album_id)
+            FROM Album
+            WHERE Album.compilation AND Album.album_id IN
+                (
+                    SELECT Track.album_id
+                    FROM Track
+                    INNER JOIN Node ON Node.parent_id = ?
+                    WHERE Track.node_id = Node.node_id
+                )",
+            [node_id],
+            |row| row.get(0),
+        )?;
+
+        if compilations > 0 {
+            let artist = match self.artist_by_name(VARIOUS_ARTISTS)? {
+                Some(a) => a,
+                None => self.create_artist(VARIOUS_ARTISTS)?,
+            };
+
+            self.conn.execute(
+                "UPDATE Album
+                SET (artist_id, artist_name) = (?, ?)
+                WHERE Album.compilation AND Album.album_id IN
+                    (
+                        SELECT Track.album_id
+                        FROM Track
+                        INNER JOIN Node ON Node.parent_id = ?
+                        WHERE Track.node_id = Node.node_id
+                    )",
+                params![artist.artist_id, artist.name, node_id],
+            )?;
+        }
+
+        self.conn.execute(
+            "UPDATE AlbumSearch
+            SET artist_name = (SELECT Album.artist_name FROM Album WHERE Album.album_id = AlbumSearch.rowid)
+            WHERE AlbumSearch.rowid IN
+                (
+                    SELECT Track.album_id
+                    FROM Track
+                    INNER JOIN Node ON Node.parent_id = ?
+                    WHERE Track.node_id = Node.node_id
+                )",
+            [node_id],
+        )?;
+
         self.conn.execute(
             "WITH RECURSIVE
                 iter(node_id, depth) AS
@@ -727,21 +1212,32 @@ impl Index {
             &[node_id, node_id],
         )?;
 
-        self.conn
-            .execute(
+        // Covers of multi-disc albums are usually next to the disc directories
+        let is_disc_directory = self
+            .node(node_id)?
+            .and_then(|n| disc_directory_number(&n.name))
+            .is_some();
+
+        if is_disc_directory {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO AlbumImage (album_id, image_id)
+                SELECT DISTINCT track.album_id, image.image_id
+                FROM Node node
+                INNER JOIN Node image_node ON image_node.parent_id = node.parent_id
+                INNER JOIN Image image ON image.node_id = image_node.node_id
+                INNER JOIN Node track_node ON track_node.parent_id = node.node_id
+                INNER JOIN Track track ON track.node_id = track_node.node_id
+                WHERE node.node_id = ?",
+                [node_id],
+            )?;
+        }
+
+        self.link_artist_images(node_id)?;
+
+        self.conn.execute(
+            &format!(
                 "UPDATE Album
-                SET image_id = 
-                    (
-                        SELECT image.image_id
-                        FROM AlbumImage album_image
-                        INNER JOIN Image image ON image.image_id = album_image.image_id
-                        LEFT OUTER JOIN AlbumImagePattern pattern ON image.description LIKE pattern.pattern
-                        WHERE album_image.album_id = Album.album_id
-                        ORDER BY
-                            pattern.rowid IS NULL ASC,
-                            pattern.rowid ASC,
-                            image.description COLLATE NOCASE ASC
-                    )
+                SET image_id = {}
                 WHERE Album.album_id IN
                     (
                         SELECT Track.album_id
@@ -749,8 +1245,104 @@ impl Index {
                         INNER JOIN Node ON Node.parent_id = ?
                         WHERE Track.node_id = Node.node_id
                     )",
-                &[node_id]
-            )?;
+                ALBUM_COVER
+            ),
+            [node_id],
+        )?;
+
+        Ok(())
+    }
+
+    /// Replaces the patterns album covers are picked by, in order of preference. Matched with
+    /// LIKE against image descriptions.
+    pub fn set_album_image_patterns(&self, patterns: &[String]) -> Result<()> {
+        trace!("set album image patterns {:?}", patterns);
+
+        self.conn
+            .execute("DELETE FROM AlbumImagePattern", NO_PARAMS)?;
+
+        let mut st = self
+            .conn
+            .prepare("INSERT INTO AlbumImagePattern (pattern) VALUES (?)")?;
+
+        for pattern in patterns {
+            st.execute(&[pattern])?;
+        }
+
+        Ok(())
+    }
+
+    /// Picks the cover of an album again, or of all albums if `album_id` is None.
+    pub fn update_album_images(&self, album_id: Option<i64>) -> Result<()> {
+        trace!("update album images album_id={:?}", album_id);
+
+        self.conn.execute(
+            &format!(
+                "UPDATE Album
+                SET image_id = {}
+                WHERE ?1 IS NULL OR Album.album_id = ?1",
+                ALBUM_COVER
+            ),
+            [album_id],
+        )?;
+
+        Ok(())
+    }
+
+    /// Links images in an artist directory, which has album directories below it, to the
+    /// artist. Images named like "artist" are linked if the albums are all by one artist, images
+    /// named after an artist to that artist.
+    fn link_artist_images(&self, node_id: i64) -> Result<()> {
+        self.conn.execute(
+            "WITH RECURSIVE
+                iter(node_id, depth) AS
+                    (
+                        VALUES(?, 0)
+                        UNION ALL
+                        SELECT Node.node_id, iter.depth + 1 FROM Node, iter
+                        WHERE iter.depth < 3 AND Node.parent_id = iter.node_id
+                    ),
+                artists(artist_id) AS
+                    (
+                        SELECT DISTINCT Album.artist_id
+                        FROM iter
+                        INNER JOIN Track ON Track.node_id = iter.node_id
+                        INNER JOIN Album ON Album.album_id = Track.album_id
+                        WHERE iter.depth >= 2 AND Album.artist_id IS NOT NULL AND NOT Album.compilation
+                    )
+            INSERT OR IGNORE INTO ArtistImage (artist_id, image_id)
+            SELECT artists.artist_id, Image.image_id
+            FROM artists, Image
+            INNER JOIN Node image_node ON image_node.node_id = Image.node_id
+            WHERE
+                image_node.parent_id = ?
+                AND lower(Image.description) IN ('artist', 'band', 'performer')
+                AND (SELECT count(*) FROM artists) = 1",
+            [node_id, node_id],
+        )?;
+
+        self.conn.execute(
+            "WITH RECURSIVE
+                iter(node_id, depth) AS
+                    (
+                        VALUES(?, 0)
+                        UNION ALL
+                        SELECT Node.node_id, iter.depth + 1 FROM Node, iter
+                        WHERE iter.depth < 3 AND Node.parent_id = iter.node_id
+                    )
+            INSERT OR IGNORE INTO ArtistImage (artist_id, image_id)
+            SELECT DISTINCT Artist.artist_id, Image.image_id
+            FROM iter
+            INNER JOIN Track ON Track.node_id = iter.node_id
+            INNER JOIN TrackArtist ON TrackArtist.track_id = Track.track_id
+            INNER JOIN Artist ON Artist.artist_id = TrackArtist.artist_id
+            INNER JOIN Node image_node ON image_node.parent_id = ?
+            INNER JOIN Image ON Image.node_id = image_node.node_id
+            WHERE
+                iter.depth >= 2
+                AND lower(Image.description) IN (lower(Artist.name), Artist.key)",
+            [node_id, node_id],
+        )?;
 
         Ok(())
     }
@@ -763,9 +1355,30 @@ impl Index {
             DELETE FROM Image;
             DELETE FROM Artist;
             DELETE FROM Album;
+            DELETE FROM Genre;
             DELETE FROM Node;",
         )?;
 
         Ok(())
     }
 }
+
+#[test]
+fn test_disc_directory_number() {
+    assert_eq!(disc_directory_number(Path::new("CD1")), Some(1));
+    assert_eq!(disc_directory_number(Path::new("cd 02")), Some(2));
+    assert_eq!(disc_directory_number(Path::new("Disc 3 - Live")), Some(3));
+    assert_eq!(disc_directory_number(Path::new("Disk_4")), Some(4));
+    assert_eq!(disc_directory_number(Path::new("Disco Hits")), None);
+    assert_eq!(disc_directory_number(Path::new("CD")), None);
+    assert_eq!(disc_directory_number(Path::new("CD1x")), None);
+}
+
+#[test]
+fn test_artist_key() {
+    assert_eq!(artist_key("The Beatles"), "beatles");
+    assert_eq!(artist_key("Beatles, The"), "beatles");
+    assert_eq!(artist_key("  the  BEATLES "), "beatles");
+    assert_eq!(artist_key("The"), "the");
+    assert_eq!(artist_key("Theatre of Tragedy"), "theatre of tragedy");
+}
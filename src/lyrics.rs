@@ -1,130 +1,606 @@
+use std::error::Error as StdError;
+use std::path::{Path, PathBuf};
 use std::result::Result;
 
-use reqwest::Error;
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::{Config, LyricsProviderConfig};
+use crate::http_util::encode_url_component;
+use crate::index::Track;
+use crate::media;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Http(reqwest::Error),
+    Task(tokio::task::JoinError),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Error {
+        Error::Http(err)
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(err: tokio::task::JoinError) -> Error {
+        Error::Task(err)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match self {
+            Error::Io(e) => e.description(),
+            Error::Http(e) => e.description(),
+            Error::Task(e) => e.description(),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Lyrics {
     pub lyrics: String,
+    /// Original text if the lyrics are in LRC format, `lyrics` then being stripped of timing
+    pub synced: Option<String>,
     pub provider: String,
     pub source: String,
 }
 
-pub async fn try_fetch_lyrics(artist: &str, title: &str) -> Result<Option<Lyrics>, Error> {
-    Ok(try_lyricwiki_lyrics(artist, title).await?)
+#[async_trait]
+pub trait LyricsProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Looks up lyrics for `track`, which is read from the file `fs_path`.
+    async fn fetch(&self, track: &Track, fs_path: &Path) -> Result<Option<Lyrics>, Error>;
 }
 
-async fn try_lyricwiki_lyrics(artist: &str, title: &str) -> Result<Option<Lyrics>, Error> {
-    // Try exact page
-    let url = &format!("https://lyrics.fandom.com/wiki/{}:{}", artist, title);
-    debug!("fetching url {}", &url);
-    if let Some(l) = parse_lyricwiki_lyrics(&reqwest::get(url).await?.text().await?) {
-        return Ok(Some(Lyrics {
-            lyrics: l,
-            provider: "LyricWiki".to_owned(),
-            source: url.to_string(),
+/// Returns the provider chain: sidecar files, embedded tags and then the configured web
+/// services.
+pub fn providers(config: &Config) -> Vec<Box<dyn LyricsProvider>> {
+    let mut providers: Vec<Box<dyn LyricsProvider>> =
+        vec![Box::new(SidecarProvider), Box::new(EmbeddedProvider)];
+
+    for provider in &config.lyrics_providers {
+        providers.push(Box::new(HttpProvider {
+            config: provider.clone(),
+            client: reqwest::Client::new(),
         }));
     }
 
-    // Fetch list of all pages associated with this artist
-    let url = &format!(
-        "http://lyrics.wikia.com/api.php?func=getArtist&artist={}&fmt=text",
-        artist
-    );
-    debug!("fetching url {}", url);
-    let song_list = reqwest::get(url).await?.text().await?;
+    providers
+}
 
-    // Try to find the exact page name
-    if let Some(t) = song_list.split('\n').find(|t| t.ends_with(title)) {
-        let url = &format!("https://lyrics.fandom.com/wiki/{}", t);
-        debug!("fetching url {}", url);
-        if let Some(l) = parse_lyricwiki_lyrics(&reqwest::get(url).await?.text().await?) {
-            return Ok(Some(Lyrics {
-                lyrics: l,
-                provider: "LyricWiki".to_owned(),
-                source: url.to_string(),
-            }));
+/// Tries the providers in order and returns the first lyrics found. Fails only if nothing was
+/// found and some provider failed, so that the lookup can be retried later.
+pub async fn try_fetch_lyrics(
+    providers: &[Box<dyn LyricsProvider>],
+    track: &Track,
+    fs_path: &Path,
+) -> Result<Option<Lyrics>, Error> {
+    let mut error: Option<Error> = None;
+
+    for provider in providers {
+        match provider.fetch(track, fs_path).await {
+            Ok(Some(mut lyrics)) => {
+                if let Some(lines) = parse_lrc(&lyrics.lyrics) {
+                    let text = lines
+                        .iter()
+                        .map(|l| l.text.as_str())
+                        .collect::<Vec<&str>>()
+                        .join("\n");
+
+                    lyrics.synced = Some(std::mem::replace(&mut lyrics.lyrics, text));
+                }
+
+                debug!(
+                    "found lyrics for track {} from {}",
+                    track.track_id, lyrics.provider
+                );
+                return Ok(Some(lyrics));
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("lyrics provider {} failed: {}", provider.name(), e);
+                error = Some(e);
+            }
         }
     }
 
-    // Try to find the primary artist name and use it
-    if let Some(artist) = song_list.split(':').next() {
-        let url = &format!("https://lyrics.fandom.com/wiki/{}:{}", artist, title);
+    match error {
+        Some(e) => Err(e),
+        None => Ok(None),
+    }
+}
+
+/// Reads `.lrc` or `.txt` files named after the track file or as "artist - title".
+struct SidecarProvider;
+
+impl SidecarProvider {
+    fn candidates(track: &Track, fs_path: &Path) -> Vec<PathBuf> {
+        let mut stems: Vec<String> = Vec::new();
+
+        // A file holding several tracks can't have a sidecar file for just one of them
+        if track.start.is_none() {
+            if let Some(stem) = fs_path.file_stem() {
+                stems.push(stem.to_string_lossy().to_string());
+            }
+        }
+
+        stems.push(format!("{} - {}", track.artist_name, track.title).replace('/', "_"));
+
+        let dir = fs_path.parent().unwrap_or_else(|| Path::new(""));
+
+        let mut result: Vec<PathBuf> = Vec::new();
+
+        for stem in stems {
+            for extension in &["lrc", "txt"] {
+                result.push(dir.join(format!("{}.{}", stem, extension)));
+            }
+        }
+
+        result
+    }
+}
+
+#[async_trait]
+impl LyricsProvider for SidecarProvider {
+    fn name(&self) -> &str {
+        "sidecar"
+    }
+
+    async fn fetch(&self, track: &Track, fs_path: &Path) -> Result<Option<Lyrics>, Error> {
+        let candidates = Self::candidates(track, fs_path);
+
+        // Files can be on a slow mount, keep reading them off the request threads
+        let found = tokio::task::spawn_blocking(move || -> std::io::Result<_> {
+            for path in candidates {
+                if !path.is_file() {
+                    continue;
+                }
+
+                trace!("reading '{}'", path.to_string_lossy());
+
+                let bytes = std::fs::read(&path)?;
+                let text = String::from_utf8_lossy(&bytes);
+                let text = text.trim_start_matches('\u{feff}').trim();
+
+                if !text.is_empty() {
+                    return Ok(Some((path, text.to_string())));
+                }
+            }
+
+            Ok(None)
+        })
+        .await??;
+
+        Ok(found.map(|(path, text)| Lyrics {
+            lyrics: text,
+            synced: None,
+            provider: self.name().to_string(),
+            source: path.to_string_lossy().to_string(),
+        }))
+    }
+}
+
+/// Reads lyrics tags from the track file.
+struct EmbeddedProvider;
+
+#[async_trait]
+impl LyricsProvider for EmbeddedProvider {
+    fn name(&self) -> &str {
+        "embedded"
+    }
+
+    async fn fetch(&self, track: &Track, fs_path: &Path) -> Result<Option<Lyrics>, Error> {
+        let path = fs_path.to_path_buf();
+        let stream_index = track.stream_index as i32;
+
+        let lyrics =
+            tokio::task::spawn_blocking(move || media::media_lyrics_read(&path, stream_index))
+                .await?;
+
+        let lyrics = match lyrics {
+            Some(l) if !l.trim().is_empty() => l,
+            _ => return Ok(None),
+        };
+
+        Ok(Some(Lyrics {
+            lyrics: lyrics.trim().replace("\r\n", "\n"),
+            synced: None,
+            provider: self.name().to_string(),
+            source: fs_path.to_string_lossy().to_string(),
+        }))
+    }
+}
+
+struct HttpProvider {
+    config: LyricsProviderConfig,
+    client: reqwest::Client,
+}
+
+#[async_trait]
+impl LyricsProvider for HttpProvider {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    async fn fetch(&self, track: &Track, _fs_path: &Path) -> Result<Option<Lyrics>, Error> {
+        let url = self
+            .config
+            .url
+            .replace("{artist}", &encode_url_component(&track.artist_name))
+            .replace("{title}", &encode_url_component(&track.title))
+            .replace("{album}", &encode_url_component(&track.album_name));
+
         debug!("fetching url {}", url);
-        if let Some(l) = parse_lyricwiki_lyrics(&reqwest::get(url).await?.text().await?) {
-            return Ok(Some(Lyrics {
-                lyrics: l,
-                provider: "LyricWiki".to_owned(),
-                source: url.to_string(),
-            }));
+
+        let response = self.client.get(&url).send().await?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let body = response.error_for_status()?.text().await?;
+
+        Ok(extract_lyrics(&self.config, &body).map(|lyrics| Lyrics {
+            lyrics,
+            synced: None,
+            provider: self.name().to_string(),
+            source: url,
+        }))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LyricsLine {
+    /// Seconds from the beginning of the track
+    pub time: f64,
+    pub text: String,
+    /// Word timing from enhanced LRC, if given
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub words: Vec<LyricsWord>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LyricsWord {
+    pub time: f64,
+    pub text: String,
+}
+
+/// Parses `mm:ss`, `mm:ss.xx` or `mm:ss:xx`.
+fn parse_lrc_time(s: &str) -> Option<f64> {
+    let mut parts = s.trim().splitn(2, ':');
+
+    let minutes: u32 = parts.next()?.parse().ok()?;
+    let seconds = parts.next()?;
+
+    // Some files separate the fraction with a colon
+    let seconds: f64 = match seconds.find(':') {
+        Some(i) => format!("{}.{}", &seconds[..i], &seconds[i + 1..]),
+        None => seconds.to_string(),
+    }
+    .parse()
+    .ok()?;
+
+    if seconds < 0f64 || !seconds.is_finite() {
+        return None;
+    }
+
+    Some(f64::from(minutes) * 60f64 + seconds)
+}
+
+/// Splits the text of an enhanced LRC line, as in `<00:01.00>word <00:01.50>word`, into plain
+/// text and timed words.
+fn parse_lrc_words(s: &str) -> (String, Vec<LyricsWord>) {
+    let mut text = String::new();
+    let mut words: Vec<LyricsWord> = Vec::new();
+
+    let mut rest = s;
+    let mut time: Option<f64> = None;
+
+    loop {
+        let marker = rest.find('<').and_then(|begin| {
+            let end = begin + rest[begin..].find('>')?;
+            Some((begin, end, parse_lrc_time(&rest[begin + 1..end])?))
+        });
+
+        let segment = match marker {
+            Some((begin, _, _)) => &rest[..begin],
+            None => rest,
+        };
+
+        text.push_str(segment);
+
+        if let Some(time) = time {
+            if !segment.trim().is_empty() {
+                words.push(LyricsWord {
+                    time,
+                    text: segment.trim().to_string(),
+                });
+            }
+        }
+
+        match marker {
+            Some((_, end, t)) => {
+                time = Some(t);
+                rest = &rest[end + 1..];
+            }
+            None => break,
         }
     }
 
-    Ok(None)
+    (text.trim().to_string(), words)
 }
 
-fn parse_lyricwiki_lyrics(body: &str) -> Option<String> {
-    let begin_pattern = "<div class='lyricbox'>";
-    let end_pattern = "<div class='lyricsbreak'>";
+/// Parses LRC lyrics into lines ordered by time, applying the `[offset:]` tag. Returns `None`
+/// if there are no timestamped lines.
+pub fn parse_lrc(lrc: &str) -> Option<Vec<LyricsLine>> {
+    let mut lines: Vec<LyricsLine> = Vec::new();
+    let mut offset = 0f64;
+
+    for line in lrc.lines() {
+        let mut rest = line.trim();
+        let mut times: Vec<f64> = Vec::new();
+
+        while rest.starts_with('[') {
+            let end = match rest.find(']') {
+                Some(e) => e,
+                None => break,
+            };
 
-    let begin_index = match body.find(begin_pattern) {
-        Some(i) => i + begin_pattern.len(),
-        None => {
-            trace!("begin '{}' not found", begin_pattern);
-            return None;
+            let tag = &rest[1..end];
+
+            if let Some(time) = parse_lrc_time(tag) {
+                times.push(time);
+            } else if let Some(value) = tag.strip_prefix("offset:") {
+                // Milliseconds, positive values make the lyrics appear sooner
+                offset = value.trim().parse::<f64>().unwrap_or(0f64) / 1000f64;
+            }
+
+            rest = &rest[end + 1..];
         }
-    };
 
-    let end_index = match body.find(end_pattern) {
-        Some(i) => i,
-        None => {
-            trace!("end '{}' not found", end_pattern);
-            return None;
+        if times.is_empty() {
+            continue;
         }
+
+        let (text, words) = parse_lrc_words(rest);
+
+        // Word timestamps are absolute, so they can't be shared by repeated lines
+        let words = if times.len() == 1 { words } else { Vec::new() };
+
+        for time in times {
+            lines.push(LyricsLine {
+                time,
+                text: text.clone(),
+                words: words.clone(),
+            });
+        }
+    }
+
+    if lines.is_empty() {
+        return None;
+    }
+
+    for line in lines.iter_mut() {
+        line.time = (line.time - offset).max(0f64);
+
+        for word in line.words.iter_mut() {
+            word.time = (word.time - offset).max(0f64);
+        }
+    }
+
+    lines.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+
+    Some(lines)
+}
+
+fn extract_lyrics(config: &LyricsProviderConfig, body: &str) -> Option<String> {
+    let mut text = match &config.json_pointer {
+        Some(pointer) => {
+            let value: Value = serde_json::from_str(body).ok()?;
+            value.pointer(pointer)?.as_str()?.to_string()
+        }
+        None => body.to_string(),
     };
 
+    if let Some(begin) = &config.begin {
+        let begin_index = match text.find(begin.as_str()) {
+            Some(i) => i + begin.len(),
+            None => {
+                trace!("begin '{}' not found", begin);
+                return None;
+            }
+        };
+
+        text = text[begin_index..].to_string();
+    }
+
+    if let Some(end) = &config.end {
+        let end_index = match text.find(end.as_str()) {
+            Some(i) => i,
+            None => {
+                trace!("end '{}' not found", end);
+                return None;
+            }
+        };
+
+        text.truncate(end_index);
+    }
+
+    if config.html {
+        text = html_to_text(&text);
+    }
+
+    let text = text.trim();
+
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+/// Drops tags, turns `<br>` into line breaks and decodes character references.
+fn html_to_text(html: &str) -> String {
     let mut result = String::new();
 
-    let body = &body[begin_index..end_index];
+    let mut rest = html;
 
-    let bytes = body.as_bytes();
-    let mut i = 0;
-    while i < bytes.len() {
-        match bytes[i] {
-            b'&' if i <= bytes.len() - 3 && bytes[i + 1] == b'#' => {
-                let mut i2 = i + 2;
-                while i2 < bytes.len() && bytes[i2] != b';' {
-                    i2 += 1;
-                }
+    while let Some(i) = rest.find(&['<', '&'][..]) {
+        result.push_str(&rest[..i]);
+        rest = &rest[i..];
 
-                if let Ok(s) = std::str::from_utf8(&bytes[i + 2..i2]) {
-                    if let Ok(cp) = s.parse() {
-                        if let Some(ch) = std::char::from_u32(cp) {
-                            result.push(ch);
-                        }
-                    }
-                }
+        if rest.starts_with('<') {
+            let end = rest.find('>').map(|e| e + 1).unwrap_or_else(|| rest.len());
+            let tag = rest[1..end].trim_start_matches('/').to_ascii_lowercase();
 
-                i = i2;
+            if tag.starts_with("br") || tag.starts_with("p>") || tag.starts_with("p ") {
+                result.push('\n');
             }
-            b'<' => {
-                let mut i2 = i;
-                while i2 < bytes.len() && bytes[i2] != b'>' {
-                    i2 += 1;
-                }
 
-                if let Ok(s) = std::str::from_utf8(&bytes[i..=i2]) {
-                    if s == "<br />" {
-                        result.push('\n')
-                    }
-                }
+            rest = &rest[end..];
+            continue;
+        }
 
-                i = i2 + 1;
-            }
+        let end = match rest.find(';') {
+            Some(e) if e <= 10 => e,
             _ => {
-                i += 1;
+                result.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        let entity = &rest[1..end];
+
+        let ch = if let Some(hex) = entity
+            .strip_prefix("#x")
+            .or_else(|| entity.strip_prefix("#X"))
+        {
+            u32::from_str_radix(hex, 16)
+                .ok()
+                .and_then(std::char::from_u32)
+        } else if let Some(decimal) = entity.strip_prefix('#') {
+            decimal.parse().ok().and_then(std::char::from_u32)
+        } else {
+            match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => None,
+            }
+        };
+
+        match ch {
+            Some(ch) => {
+                result.push(ch);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
             }
         }
     }
 
-    Some(result)
+    result.push_str(rest);
+
+    result
+}
+
+#[test]
+fn test_extract_lyrics() {
+    let mut config = LyricsProviderConfig {
+        name: "test".to_string(),
+        url: String::new(),
+        json_pointer: Some("/result/lyrics".to_string()),
+        begin: None,
+        end: None,
+        html: false,
+    };
+
+    assert_eq!(
+        extract_lyrics(
+            &config,
+            "{\"result\": {\"lyrics\": \"Line 1\\nLine 2\\n\"}}"
+        ),
+        Some("Line 1\nLine 2".to_string())
+    );
+    assert_eq!(extract_lyrics(&config, "{\"result\": {}}"), None);
+
+    config.json_pointer = None;
+    config.begin = Some("<div class='lyricbox'>".to_string());
+    config.end = Some("</div>".to_string());
+    config.html = true;
+
+    assert_eq!(
+        extract_lyrics(
+            &config,
+            "<html><div class='lyricbox'>Caf&#233; &amp; <i>bar</i><br />Next&#x21;</div></html>"
+        ),
+        Some("Café & bar\nNext!".to_string())
+    );
+    assert_eq!(extract_lyrics(&config, "<html></html>"), None);
+}
+
+#[test]
+fn test_parse_lrc() {
+    assert_eq!(parse_lrc("Just text\n[ar:Someone]"), None);
+
+    let lines = parse_lrc(
+        "[ti:Song]
+[offset:+500]
+[00:12.00][01:02.50]Chorus line
+[00:05.25]<00:05.25>First <00:05.75>line<00:06.50>
+[00:20:50]
+",
+    )
+    .unwrap();
+
+    assert_eq!(
+        lines
+            .iter()
+            .map(|l| (l.time, l.text.as_str()))
+            .collect::<Vec<(f64, &str)>>(),
+        vec![
+            (4.75, "First line"),
+            (11.5, "Chorus line"),
+            (20.0, ""),
+            (62.0, "Chorus line")
+        ]
+    );
+
+    assert_eq!(
+        lines[0].words,
+        vec![
+            LyricsWord {
+                time: 4.75,
+                text: "First".to_string()
+            },
+            LyricsWord {
+                time: 5.25,
+                text: "line".to_string()
+            }
+        ]
+    );
+    assert!(lines[1].words.is_empty());
 }
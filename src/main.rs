@@ -3,8 +3,10 @@ extern crate log;
 
 mod audio_stream;
 mod cache;
+mod config;
 mod cue;
 mod db_meta;
+mod hls;
 mod http_api;
 mod http_util;
 mod index;
@@ -16,6 +18,8 @@ mod query;
 mod scan;
 mod schema;
 mod store;
+mod subsonic_api;
+mod watch;
 
 use std::ffi::OsStr;
 use std::net::SocketAddr;
@@ -26,11 +30,15 @@ use std::sync::Arc;
 use clap::Arg;
 
 use cache::{Cache, CacheSource};
+use config::Config;
 use index::{Index, IndexSource};
+use lyrics::LyricsProvider;
 use scan::ScanThread;
 use store::{Store, StoreSource};
 
 pub struct Musicd {
+    config: Config,
+    lyrics_providers: Vec<Box<dyn LyricsProvider>>,
     cache_source: CacheSource,
     index_source: IndexSource,
     store_source: StoreSource,
@@ -77,6 +85,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .help("Maximum cache size in bytes")
                 .default_value("104857600"),
         )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help("Configuration file")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("directory")
                 .long("directory")
@@ -95,15 +109,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .default_value("info")
                 .possible_values(&["error", "warn", "info", "debug", "trace"]),
         )
+        .arg(
+            Arg::with_name("migrate-dry-run")
+                .long("migrate-dry-run")
+                .help("Print pending database migrations and exit"),
+        )
         .arg(
             Arg::with_name("no-initial-scan")
                 .long("no-initial-scan")
                 .help("Disable initial scan"),
         )
+        .arg(
+            Arg::with_name("no-watch")
+                .long("no-watch")
+                .help("Disable watching roots for changes"),
+        )
         .arg(
             Arg::with_name("password")
                 .long("password")
-                .help("Authentication password, empty disables authentication")
+                .help("Password of the initial admin user, empty disables authentication")
                 .default_value(""),
         )
         .arg(
@@ -132,6 +156,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!("{}", MUSICD_VERSION);
 
+    let config = match matches.value_of("config") {
+        Some(path) => {
+            Config::load(Path::new(&*shellexpand::tilde(path))).expect("can't load config")
+        }
+        None => Config::default(),
+    };
+
     let mut roots: Vec<Root> = Vec::new();
 
     if matches.is_present("root") {
@@ -157,6 +188,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Some(directory.join("cache.db"))
     };
 
+    if matches.is_present("migrate-dry-run") {
+        if let Some(cache_path) = &cache_path {
+            db_meta::print_pending_migrations(cache_path, schema::CACHE_MIGRATIONS)?;
+        }
+
+        db_meta::print_pending_migrations(&directory.join("index.db"), schema::INDEX_MIGRATIONS)?;
+        db_meta::print_pending_migrations(&directory.join("store.db"), schema::STORE_MIGRATIONS)?;
+
+        return Ok(());
+    }
+
     let cache_source = CacheSource::create(cache_path, cache_limit)
         .unwrap()
         .unwrap();
@@ -169,9 +211,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .unwrap()
         .unwrap();
 
-    let scan_thread = scan::ScanThread::new();
+    let scan_thread = scan::ScanThread::new(&config);
 
     let musicd = Arc::new(Musicd {
+        lyrics_providers: lyrics::providers(&config),
+        config,
         cache_source,
         index_source,
         store_source,
@@ -179,15 +223,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         password: matches.value_of("password").unwrap().to_string(),
     });
 
+    if matches.is_present("no-watch") {
+        info!("watching disabled");
+    } else {
+        watch::start(musicd.clone());
+    }
+
     let index = musicd.index();
 
     if matches.is_present("no-initial-scan") {
         info!("initial scan disabled");
     } else {
-        musicd.scan_thread.start(index);
+        musicd.scan_thread.start(index, musicd.store());
     }
 
     let mut store = musicd.store();
+    store.ensure_default_user(&musicd.password).unwrap();
     store.synchronize().unwrap();
 
     http_api::run_api(musicd.clone(), bind).await;
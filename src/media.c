@@ -27,6 +27,53 @@ static char *copy_metadata(
     return av_strdup(get_metadata(avctx, stream_index, key));
 }
 
+static char *copy_first_metadata(
+    const AVFormatContext *avctx,
+    int stream_index,
+    const char **keys
+) {
+    for (; *keys; ++keys) {
+        const char *value = get_metadata(avctx, stream_index, *keys);
+        if (value && value[0]) {
+            return av_strdup(value);
+        }
+    }
+
+    return NULL;
+}
+
+// Parses "n" or "n/total", leaving values not present untouched
+static void parse_number_pair(const char *str, int32_t *number, int32_t *total) {
+    if (!str) {
+        return;
+    }
+
+    int n, t;
+    int count = sscanf(str, "%d/%d", &n, &t);
+
+    if (count >= 1) {
+        *number = n;
+    }
+
+    if (count >= 2 && total) {
+        *total = t;
+    }
+}
+
+static int32_t get_number_metadata(
+    const AVFormatContext *avctx,
+    int stream_index,
+    const char **keys
+) {
+    int32_t result = 0;
+
+    for (; *keys && !result; ++keys) {
+        parse_number_pair(get_metadata(avctx, stream_index, *keys), &result, NULL);
+    }
+
+    return result;
+}
+
 static struct TrackInfo *try_get_track_info(
     const AVFormatContext *avctx,
     int stream_index,
@@ -140,7 +187,7 @@ static struct TrackInfo *try_get_track_info(
 
     const char *tmp = get_metadata(avctx, stream_index, "track");
     if (tmp) {
-        sscanf(tmp, "%d", &track_info->number);
+        parse_number_pair(tmp, &track_info->number, &track_info->track_total);
     } else {
         track_info->number = track_index;
     }
@@ -171,6 +218,58 @@ static struct TrackInfo *try_get_track_info(
         track_info->album_artist = copy_metadata(avctx, stream_index, "album artist");
     }
 
+    // Keys are matched case-insensitively, ID3v2 frames without a common name keep the
+    // frame id and TXXX frames their description
+    static const char *date_keys[] = { "date", "year", "TYER", NULL };
+    static const char *original_date_keys[] = {
+        "originaldate", "original_date", "TDOR", "TORY", "originalyear", NULL };
+    static const char *track_total_keys[] = { "tracktotal", "totaltracks", NULL };
+    static const char *disc_total_keys[] = { "disctotal", "totaldiscs", NULL };
+    static const char *composer_keys[] = { "composer", NULL };
+    static const char *performer_keys[] = { "performer", NULL };
+    static const char *label_keys[] = { "label", "publisher", "organization", NULL };
+    static const char *catalog_number_keys[] = { "catalognumber", "catalog number", NULL };
+    static const char *comment_keys[] = { "comment", "description", NULL };
+    static const char *musicbrainz_track_id_keys[] = {
+        "musicbrainz_trackid", "MusicBrainz Track Id", NULL };
+    static const char *musicbrainz_release_id_keys[] = {
+        "musicbrainz_albumid", "MusicBrainz Album Id", NULL };
+    static const char *musicbrainz_artist_id_keys[] = {
+        "musicbrainz_artistid", "MusicBrainz Artist Id", NULL };
+    static const char *compilation_keys[] = { "compilation", "TCMP", "cpil", NULL };
+
+    track_info->date = copy_first_metadata(avctx, stream_index, date_keys);
+    track_info->original_date = copy_first_metadata(avctx, stream_index, original_date_keys);
+
+    // Multiple genres are joined with ';' by FFmpeg
+    track_info->genre = copy_metadata(avctx, stream_index, "genre");
+
+    parse_number_pair(
+        get_metadata(avctx, stream_index, "disc"),
+        &track_info->disc_number,
+        &track_info->disc_total);
+
+    if (!track_info->disc_total) {
+        track_info->disc_total = get_number_metadata(avctx, stream_index, disc_total_keys);
+    }
+
+    if (!track_info->track_total) {
+        track_info->track_total = get_number_metadata(avctx, stream_index, track_total_keys);
+    }
+
+    track_info->composer = copy_first_metadata(avctx, stream_index, composer_keys);
+    track_info->performer = copy_first_metadata(avctx, stream_index, performer_keys);
+    track_info->label = copy_first_metadata(avctx, stream_index, label_keys);
+    track_info->catalog_number = copy_first_metadata(avctx, stream_index, catalog_number_keys);
+    track_info->comment = copy_first_metadata(avctx, stream_index, comment_keys);
+    track_info->musicbrainz_track_id =
+        copy_first_metadata(avctx, stream_index, musicbrainz_track_id_keys);
+    track_info->musicbrainz_release_id =
+        copy_first_metadata(avctx, stream_index, musicbrainz_release_id_keys);
+    track_info->musicbrainz_artist_id =
+        copy_first_metadata(avctx, stream_index, musicbrainz_artist_id_keys);
+    track_info->compilation = get_number_metadata(avctx, stream_index, compilation_keys) != 0;
+
     return track_info;
 }
 
@@ -225,6 +324,17 @@ static void track_info_free(struct TrackInfo *track_info) {
         free(track_info->artist);
         free(track_info->album);
         free(track_info->album_artist);
+        free(track_info->date);
+        free(track_info->original_date);
+        free(track_info->genre);
+        free(track_info->composer);
+        free(track_info->performer);
+        free(track_info->label);
+        free(track_info->catalog_number);
+        free(track_info->comment);
+        free(track_info->musicbrainz_track_id);
+        free(track_info->musicbrainz_release_id);
+        free(track_info->musicbrainz_artist_id);
 
         struct TrackInfo *prev = track_info;
         track_info = track_info->next;
@@ -309,3 +419,40 @@ fail:
 void media_image_data_free(uint8_t *data) {
     free(data);
 }
+
+char *media_lyrics_read(const char *path, int32_t stream_index) {
+    // ID3v2 USLT frames are exported as lyrics-<description>-<language>, others use plain
+    // tag names
+    static const char *keys[] = { "lyrics", "unsyncedlyrics", "uslt", NULL };
+
+    AVFormatContext *in_ctx = NULL;
+    int result = avformat_open_input(&in_ctx, path, NULL, NULL);
+    if (result < 0) {
+        lav_error("avformat_open_input", result);
+        return NULL;
+    }
+
+    char *lyrics = NULL;
+
+    if (in_ctx->nb_streams > (uint32_t)stream_index) {
+        for (const char **key = keys; *key && !lyrics; ++key) {
+            const AVDictionaryEntry *entry = av_dict_get(
+                in_ctx->streams[stream_index]->metadata, *key, NULL, AV_DICT_IGNORE_SUFFIX);
+
+            if (!entry) {
+                entry = av_dict_get(in_ctx->metadata, *key, NULL, AV_DICT_IGNORE_SUFFIX);
+            }
+
+            if (entry && entry->value[0]) {
+                lyrics = av_strdup(entry->value);
+            }
+        }
+    }
+
+    avformat_close_input(&in_ctx);
+    return lyrics;
+}
+
+void media_lyrics_free(char *lyrics) {
+    av_free(lyrics);
+}
@@ -14,6 +14,43 @@ unsafe fn convert_string(s: *const c_char) -> String {
     String::from_utf8_lossy(CStr::from_ptr(s).to_bytes()).into_owned()
 }
 
+unsafe fn convert_optional_string(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        return None;
+    }
+
+    let s = convert_string(s).trim().to_string();
+
+    if s.is_empty() {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+fn convert_optional_number(n: i32) -> Option<i64> {
+    if n > 0 {
+        Some(i64::from(n))
+    } else {
+        None
+    }
+}
+
+/// Takes the year from dates such as "2001", "2001-04-12" or "2001/04".
+fn parse_year(date: &str) -> Option<i64> {
+    let digits: String = date
+        .trim()
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+
+    if digits.len() == 4 {
+        digits.parse().ok()
+    } else {
+        None
+    }
+}
+
 pub fn media_info_from_path(path: &Path) -> Option<(Vec<Track>, Vec<Image>)> {
     let tmp_path = CString::new(path.as_os_str().as_bytes()).unwrap();
 
@@ -29,6 +66,7 @@ pub fn media_info_from_path(path: &Path) -> Option<(Vec<Track>, Vec<Image>)> {
     while !cur.is_null() {
         tracks.push(unsafe {
             let track_info = &(&(*cur));
+            let date = convert_optional_string(track_info.date);
 
             Track {
                 track_id: 0i64,
@@ -49,6 +87,22 @@ pub fn media_info_from_path(path: &Path) -> Option<(Vec<Track>, Vec<Image>)> {
                     Some(convert_string(track_info.album_artist).trim().to_string())
                 },
                 length: track_info.duration,
+                year: date.as_deref().and_then(parse_year),
+                date,
+                original_date: convert_optional_string(track_info.original_date),
+                genre: convert_optional_string(track_info.genre),
+                disc_number: convert_optional_number(track_info.disc_number),
+                disc_total: convert_optional_number(track_info.disc_total),
+                track_total: convert_optional_number(track_info.track_total),
+                composer: convert_optional_string(track_info.composer),
+                performer: convert_optional_string(track_info.performer),
+                label: convert_optional_string(track_info.label),
+                catalog_number: convert_optional_string(track_info.catalog_number),
+                comment: convert_optional_string(track_info.comment),
+                musicbrainz_track_id: convert_optional_string(track_info.musicbrainz_track_id),
+                musicbrainz_release_id: convert_optional_string(track_info.musicbrainz_release_id),
+                musicbrainz_artist_id: convert_optional_string(track_info.musicbrainz_artist_id),
+                compilation: track_info.compilation != 0,
             }
         });
 
@@ -106,3 +160,30 @@ pub fn media_image_data_read(path: &Path, stream_index: i32) -> Option<Vec<u8>>
 
     Some(result)
 }
+
+/// Reads lyrics embedded in the tags of a file, such as ID3v2 USLT frames.
+pub fn media_lyrics_read(path: &Path, stream_index: i32) -> Option<String> {
+    let tmp_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+
+    let lyrics = unsafe { musicd_c::media_lyrics_read(tmp_path.as_ptr(), stream_index) };
+    if lyrics.is_null() {
+        return None;
+    }
+
+    let result = unsafe { convert_string(lyrics) };
+
+    unsafe {
+        musicd_c::media_lyrics_free(lyrics);
+    }
+
+    Some(result)
+}
+
+#[test]
+fn test_parse_year() {
+    assert_eq!(parse_year("2001"), Some(2001));
+    assert_eq!(parse_year("2001-04-12"), Some(2001));
+    assert_eq!(parse_year(" 1999/05 "), Some(1999));
+    assert_eq!(parse_year("12.4.2001"), None);
+    assert_eq!(parse_year(""), None);
+}
@@ -26,6 +26,21 @@ struct TrackInfo {
     char *album_artist;
     double start;
     double length;
+    char *date;
+    char *original_date;
+    char *genre;
+    int32_t disc_number;
+    int32_t disc_total;
+    int32_t track_total;
+    char *composer;
+    char *performer;
+    char *label;
+    char *catalog_number;
+    char *comment;
+    char *musicbrainz_track_id;
+    char *musicbrainz_release_id;
+    char *musicbrainz_artist_id;
+    int32_t compilation;
 };
 
 struct ImageInfo {
@@ -42,7 +57,11 @@ struct AudioStreamOptions {
     int32_t track_index;
     double start;
     double length;
-    char *target_codec;
+    char *target_format;
+    int32_t copy;
+    int32_t bitrate;
+    int32_t sample_rate;
+    int32_t channels;
 };
 
 struct AudioStream {
@@ -53,7 +72,11 @@ struct AudioStream {
     AVIOContext *out_ioctx;
     AVFilterGraph *filter_graph;
     AVFilterContext *abuffer_ctx, *aformat_ctx, *abuffersink_ctx;
+    int64_t start_pts;
     int64_t end_pts;
+    int64_t first_pts;
+    double position;
+    int copy;
     int started;
     int finished;
     void *write_opaque;
@@ -80,6 +103,7 @@ int audio_stream_next(
     struct AudioStream *audio_stream,
     void *write_opaque,
     int (*write_callback)(void *opaque, uint8_t *buf, int len));
+double audio_stream_position(struct AudioStream *stream);
 void audio_stream_close(struct AudioStream *stream);
 
 int media_image_data_read(
@@ -87,4 +111,7 @@ int media_image_data_read(
     int32_t stream_index,
     uint8_t **out_data,
     size_t *out_len);
-void media_image_data_free(uint8_t *data);
\ No newline at end of file
+void media_image_data_free(uint8_t *data);
+
+char *media_lyrics_read(const char *path, int32_t stream_index);
+void media_lyrics_free(char *lyrics);
\ No newline at end of file
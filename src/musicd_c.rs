@@ -18,6 +18,21 @@ pub struct TrackInfo {
     pub album_artist: *const c_char,
     pub start: f64,
     pub duration: f64,
+    pub date: *const c_char,
+    pub original_date: *const c_char,
+    pub genre: *const c_char,
+    pub disc_number: i32,
+    pub disc_total: i32,
+    pub track_total: i32,
+    pub composer: *const c_char,
+    pub performer: *const c_char,
+    pub label: *const c_char,
+    pub catalog_number: *const c_char,
+    pub comment: *const c_char,
+    pub musicbrainz_track_id: *const c_char,
+    pub musicbrainz_release_id: *const c_char,
+    pub musicbrainz_artist_id: *const c_char,
+    pub compilation: i32,
 }
 
 #[repr(C)]
@@ -36,7 +51,11 @@ pub struct AudioStreamOptions {
     pub track_index: i32,
     pub start: f64,
     pub length: f64,
-    pub target_codec: *const c_char,
+    pub target_format: *const c_char,
+    pub copy: i32,
+    pub bitrate: i32,
+    pub sample_rate: i32,
+    pub channels: i32,
 }
 
 pub enum LogLevel {
@@ -59,6 +78,7 @@ extern "C" {
         opaque: *const c_void,
         callback: extern "C" fn(opaque: *const c_void, buf: *const u8, len: c_int) -> c_int,
     ) -> c_int;
+    pub fn audio_stream_position(audio_stream: *const c_void) -> f64;
     pub fn audio_stream_close(audio_stream: *const c_void);
 
     pub fn media_image_data_read(
@@ -68,4 +88,7 @@ extern "C" {
         out_len: *mut usize,
     ) -> c_int;
     pub fn media_image_data_free(data: *mut u8);
+
+    pub fn media_lyrics_read(path: *const c_char, stream_index: i32) -> *mut c_char;
+    pub fn media_lyrics_free(lyrics: *mut c_char);
 }
@@ -5,12 +5,13 @@ use rusqlite::types::ToSql;
 use rusqlite::{Connection, Statement};
 use serde::Serialize;
 
-use crate::http_util::HttpQuery;
+use crate::http_util::{escape_html, HttpQuery};
 use crate::index::{Index, NodeType};
 
 struct QueryOptions {
     clauses: Vec<String>,
     values: Vec<Box<dyn ToSql>>,
+    group_string: Option<String>,
     order_string: Option<String>,
     limit: Option<i64>,
     offset: Option<i64>,
@@ -21,6 +22,7 @@ impl QueryOptions {
         QueryOptions {
             clauses: Vec::new(),
             values: Vec::new(),
+            group_string: None,
             order_string: None,
             limit: None,
             offset: None,
@@ -40,11 +42,12 @@ impl QueryOptions {
         self.values.push(Box::new(value));
     }
 
-    pub fn filter_values(&mut self, clause: &str, value: Vec<Box<dyn ToSql>>) {
-        self.clauses.push(clause.to_string());
-
-        for v in value {
-            self.values.push(Box::new(v));
+    /// Limits the results to items under the roots `index` is restricted to, with `clause`
+    /// having `{}` in place of the subquery selecting the node ids.
+    pub fn filter_roots(&mut self, index: &Index, clause: &str) {
+        if let Some((node_ids, values)) = root_node_ids(index) {
+            self.clauses.push(clause.replace("{}", &node_ids));
+            self.values.extend(values);
         }
     }
 
@@ -60,6 +63,10 @@ impl QueryOptions {
         }
     }
 
+    pub fn group_string(&mut self, group_string: &str) {
+        self.group_string = Some(group_string.to_string());
+    }
+
     pub fn order_string(&mut self, order_string: &str) {
         self.order_string = Some(order_string.to_string());
     }
@@ -107,6 +114,11 @@ impl QueryOptions {
             sql += &self.clauses.join(" AND ");
         }
 
+        if let Some(group) = self.group_string {
+            sql += " GROUP BY ";
+            sql += &group;
+        }
+
         if let Some(order) = self.order_string {
             sql += " ORDER BY ";
             sql += &order;
@@ -128,17 +140,66 @@ impl QueryOptions {
     }
 }
 
+/// Subquery selecting the ids of the nodes under the roots `index` is restricted to, with its
+/// values. Returns `None` if the index isn't restricted.
+pub fn root_node_ids(index: &Index) -> Option<(String, Vec<Box<dyn ToSql>>)> {
+    if !index.is_restricted() {
+        return None;
+    }
+
+    let mut conditions = Vec::new();
+    let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+
+    for root in index.roots().iter() {
+        let name = root.name.as_bytes().to_vec();
+        let prefix = [name.as_slice(), b"/"].concat();
+
+        // Paths are blobs, compared byte by byte
+        conditions.push("Node.path = ? OR substr(Node.path, 1, ?) = ?");
+        values.push(Box::new(name));
+        values.push(Box::new(prefix.len() as i64));
+        values.push(Box::new(prefix));
+    }
+
+    if conditions.is_empty() {
+        conditions.push("0");
+    }
+
+    Some((
+        format!(
+            "SELECT Node.node_id FROM Node WHERE {}",
+            conditions.join(" OR ")
+        ),
+        values,
+    ))
+}
+
+/// Turns free-form input into an FTS5 query matching all words as prefixes, in any order.
+pub fn fts_match_query(search: &str) -> Option<String> {
+    let terms: Vec<String> = search
+        .split_whitespace()
+        .filter(|w| w.chars().any(|c| c.is_alphanumeric()))
+        .map(|w| format!("\"{}\"*", w.replace('"', "\"\"")))
+        .collect();
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
 #[derive(Serialize)]
 pub struct NodeItem {
-    node_id: i64,
-    parent_id: Option<i64>,
-    node_type: NodeType,
-    name: String,
-    path: String,
-    track_count: i64,
-    image_count: i64,
-    all_track_count: i64,
-    all_image_count: i64,
+    pub node_id: i64,
+    pub parent_id: Option<i64>,
+    pub node_type: NodeType,
+    pub name: String,
+    pub path: String,
+    pub track_count: i64,
+    pub image_count: i64,
+    pub all_track_count: i64,
+    pub all_image_count: i64,
 }
 
 pub fn query_nodes(
@@ -159,6 +220,8 @@ pub fn query_nodes(
         }
     }
 
+    opts.filter_roots(index, "Node.node_id IN ({})");
+
     opts.bind_range(&query);
 
     let conn = index.connection();
@@ -255,16 +318,98 @@ pub fn query_nodes(
 
 #[derive(Serialize)]
 pub struct TrackItem {
-    track_id: i64,
-    node_id: i64,
-    number: i64,
-    title: String,
-    artist_id: i64,
-    artist_name: String,
-    album_id: i64,
-    album_name: String,
-    length: f64,
-    node_path: String,
+    pub track_id: i64,
+    pub node_id: i64,
+    pub number: i64,
+    pub title: String,
+    pub artist_id: i64,
+    pub artist_name: String,
+    pub album_id: i64,
+    pub album_name: String,
+    pub length: f64,
+    pub date: Option<String>,
+    pub year: Option<i64>,
+    pub original_date: Option<String>,
+    pub genre: Option<String>,
+    pub disc_number: Option<i64>,
+    pub disc_total: Option<i64>,
+    pub track_total: Option<i64>,
+    pub composer: Option<String>,
+    pub performer: Option<String>,
+    pub label: Option<String>,
+    pub catalog_number: Option<String>,
+    pub comment: Option<String>,
+    pub musicbrainz_track_id: Option<String>,
+    pub musicbrainz_release_id: Option<String>,
+    pub musicbrainz_artist_id: Option<String>,
+    pub node_path: String,
+}
+
+impl TrackItem {
+    /// Columns read by `from_row`, selecting from `Track`
+    pub const COLUMNS: &'static str = "Track.track_id,
+        Track.node_id,
+        Track.number,
+        Track.title,
+        Track.artist_id,
+        Track.artist_name,
+        Track.album_id,
+        Track.album_name,
+        Track.length,
+        Track.date,
+        Track.year,
+        Track.original_date,
+        Track.genre,
+        Track.disc_number,
+        Track.disc_total,
+        Track.track_total,
+        Track.composer,
+        Track.performer,
+        Track.label,
+        Track.catalog_number,
+        Track.comment,
+        Track.musicbrainz_track_id,
+        Track.musicbrainz_release_id,
+        Track.musicbrainz_artist_id,
+
+        (
+            SELECT Node.path
+            FROM Node
+            WHERE Node.node_id = Track.node_id
+        ) AS node_path";
+
+    /// Reads an item selected with `COLUMNS`, starting at column `offset`.
+    pub fn from_row(row: &rusqlite::Row, offset: usize) -> Result<TrackItem, rusqlite::Error> {
+        let path: Vec<u8> = row.get(offset + 24)?;
+
+        Ok(TrackItem {
+            track_id: row.get(offset)?,
+            node_id: row.get(offset + 1)?,
+            number: row.get(offset + 2)?,
+            title: row.get(offset + 3)?,
+            artist_id: row.get(offset + 4)?,
+            artist_name: row.get(offset + 5)?,
+            album_id: row.get(offset + 6)?,
+            album_name: row.get(offset + 7)?,
+            length: row.get(offset + 8)?,
+            date: row.get(offset + 9)?,
+            year: row.get(offset + 10)?,
+            original_date: row.get(offset + 11)?,
+            genre: row.get(offset + 12)?,
+            disc_number: row.get(offset + 13)?,
+            disc_total: row.get(offset + 14)?,
+            track_total: row.get(offset + 15)?,
+            composer: row.get(offset + 16)?,
+            performer: row.get(offset + 17)?,
+            label: row.get(offset + 18)?,
+            catalog_number: row.get(offset + 19)?,
+            comment: row.get(offset + 20)?,
+            musicbrainz_track_id: row.get(offset + 21)?,
+            musicbrainz_release_id: row.get(offset + 22)?,
+            musicbrainz_artist_id: row.get(offset + 23)?,
+            node_path: OsStr::from_bytes(&path).to_string_lossy().to_string(),
+        })
+    }
 }
 
 pub fn query_tracks(
@@ -277,7 +422,11 @@ pub fn query_tracks(
     opts.bind_filter_i64(&query, "node_id", "Track.node_id = ?");
     opts.bind_filter_i64(&query, "number", "Track.number = ?");
     opts.bind_filter_str(&query, "title", "Track.title LIKE ? COLLATE NOCASE");
-    opts.bind_filter_i64(&query, "artist_id", "Track.artist_id = ?");
+    opts.bind_filter_i64(
+        query,
+        "artist_id",
+        "Track.track_id IN (SELECT TrackArtist.track_id FROM TrackArtist WHERE TrackArtist.artist_id = ?)",
+    );
     opts.bind_filter_str(
         &query,
         "artist_name",
@@ -290,19 +439,57 @@ pub fn query_tracks(
         "Track.album_name LIKE ? COLLATE NOCASE",
     );
 
-    if let Some(search) = query.get_str("search") {
-        let mut values: Vec<Box<dyn ToSql>> = Vec::new();
-        values.push(Box::new(format!("%{}%", search)));
-        values.push(Box::new(format!("%{}%", search)));
-        values.push(Box::new(format!("%{}%", search)));
+    opts.bind_filter_str(query, "date", "Track.date LIKE ?");
+    opts.bind_filter_i64(query, "year", "Track.year = ?");
+    opts.bind_filter_i64(query, "min_year", "Track.year >= ?");
+    opts.bind_filter_i64(query, "max_year", "Track.year <= ?");
+    opts.bind_filter_str(query, "original_date", "Track.original_date LIKE ?");
+    opts.bind_filter_str(query, "genre", "Track.genre LIKE ? COLLATE NOCASE");
+    opts.bind_filter_i64(
+        query,
+        "genre_id",
+        "Track.track_id IN (SELECT TrackGenre.track_id FROM TrackGenre WHERE TrackGenre.genre_id = ?)",
+    );
+    opts.bind_filter_i64(query, "disc_number", "Track.disc_number = ?");
+    opts.bind_filter_i64(query, "disc_total", "Track.disc_total = ?");
+    opts.bind_filter_i64(query, "track_total", "Track.track_total = ?");
+    opts.bind_filter_str(query, "composer", "Track.composer LIKE ? COLLATE NOCASE");
+    opts.bind_filter_str(query, "performer", "Track.performer LIKE ? COLLATE NOCASE");
+    opts.bind_filter_str(query, "label", "Track.label LIKE ? COLLATE NOCASE");
+    opts.bind_filter_str(
+        query,
+        "catalog_number",
+        "Track.catalog_number LIKE ? COLLATE NOCASE",
+    );
+    opts.bind_filter_str(query, "comment", "Track.comment LIKE ? COLLATE NOCASE");
+    opts.bind_filter_str(
+        query,
+        "musicbrainz_track_id",
+        "Track.musicbrainz_track_id = ?",
+    );
+    opts.bind_filter_str(
+        query,
+        "musicbrainz_release_id",
+        "Track.musicbrainz_release_id = ?",
+    );
+    opts.bind_filter_str(
+        query,
+        "musicbrainz_artist_id",
+        "Track.musicbrainz_artist_id = ?",
+    );
 
-        opts.filter_values(
-            "(Track.title LIKE ? OR Track.artist_name LIKE ? OR Track.album_name LIKE ?)",
-            values,
+    if let Some(search) = query.get_str("search").and_then(fts_match_query) {
+        opts.filter_value(
+            "Track.track_id IN (SELECT rowid FROM TrackSearch WHERE TrackSearch MATCH ?)",
+            search,
         );
     }
 
-    opts.order_string("Track.album_name, Track.number, Track.title");
+    opts.filter_roots(index, "Track.node_id IN ({})");
+
+    opts.order_string(
+        "Track.album_name, Track.album_id, Track.disc_number, Track.number, Track.title",
+    );
 
     opts.bind_range(&query);
 
@@ -312,44 +499,342 @@ pub fn query_tracks(
 
     let (mut st, values) = opts.into_items_query(
         &conn,
+        &format!(
+            "SELECT
+                {}
+
+            FROM Track",
+            TrackItem::COLUMNS
+        ),
+    )?;
+
+    let mut rows = st.query(&values)?;
+
+    let mut items: Vec<TrackItem> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        items.push(TrackItem::from_row(row, 0)?);
+    }
+
+    Ok((total, items))
+}
+
+#[derive(Serialize)]
+pub struct ListItem {
+    pub list_id: i64,
+    pub name: String,
+    pub track_count: i64,
+}
+
+/// Lists the playlists of a user.
+pub fn query_lists(
+    index: &Index,
+    query: &HttpQuery,
+    user_id: i64,
+) -> Result<(i64, Vec<ListItem>), rusqlite::Error> {
+    let mut opts = QueryOptions::new();
+
+    opts.filter_value("StoreList.user_id = ?", user_id);
+    opts.bind_filter_i64(query, "list_id", "StoreList.list_id = ?");
+    opts.bind_filter_str(query, "name", "StoreList.name LIKE ? COLLATE NOCASE");
+
+    opts.order_string("StoreList.name COLLATE NOCASE");
+
+    opts.bind_range(query);
+
+    let conn = index.connection();
+
+    let total = opts.get_total(conn, "SELECT COUNT(StoreList.list_id) FROM StoreList")?;
+
+    let (mut st, values) = opts.into_items_query(conn,
         "SELECT
-            Track.track_id,
-            Track.node_id,
-            Track.number,
-            Track.title,
-            Track.artist_id,
-            Track.artist_name,
-            Track.album_id,
-            Track.album_name,
-            Track.length,
+            StoreList.list_id,
+            StoreList.name,
+            (SELECT count(*) FROM StoreListTrack WHERE StoreListTrack.list_id = StoreList.list_id) AS track_count
+        FROM StoreList")?;
 
-            (
-                SELECT Node.path
-                FROM Node
-                WHERE Node.node_id = Track.node_id
-            ) AS node_path
+    let mut rows = st.query(&values)?;
+
+    let mut items: Vec<ListItem> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        items.push(ListItem {
+            list_id: row.get(0)?,
+            name: row.get(1)?,
+            track_count: row.get(2)?,
+        });
+    }
+
+    Ok((total, items))
+}
+
+#[derive(Serialize)]
+pub struct ListTrackItem {
+    pub list_id: i64,
+    pub sort_index: i64,
+    #[serde(flatten)]
+    pub track: TrackItem,
+}
+
+pub fn query_list_tracks(
+    index: &Index,
+    query: &HttpQuery,
+    user_id: i64,
+) -> Result<(i64, Vec<ListTrackItem>), rusqlite::Error> {
+    let mut opts = QueryOptions::new();
+
+    opts.filter_value(
+        "StoreListTrack.list_id IN (SELECT list_id FROM StoreList WHERE user_id = ?)",
+        user_id,
+    );
+    opts.bind_filter_i64(query, "list_id", "StoreListTrack.list_id = ?");
+
+    opts.order_string("StoreListTrack.list_id, StoreListTrack.sort_index");
+
+    opts.bind_range(query);
+
+    let conn = index.connection();
+
+    let total = opts.get_total(
+        conn,
+        "SELECT COUNT(StoreListTrack.store_track_id)
+        FROM StoreListTrack
+        INNER JOIN StoreTrack ON StoreTrack.store_track_id = StoreListTrack.store_track_id",
+    )?;
+
+    let (mut st, values) = opts.into_items_query(
+        conn,
+        &format!(
+            "SELECT
+                StoreListTrack.list_id,
+                StoreListTrack.sort_index,
+                {}
+
+            FROM StoreListTrack
+            INNER JOIN StoreTrack ON StoreTrack.store_track_id = StoreListTrack.store_track_id
+            INNER JOIN Track ON Track.track_id = StoreTrack.track_id",
+            TrackItem::COLUMNS
+        ),
+    )?;
+
+    let mut rows = st.query(&values)?;
+
+    let mut items: Vec<ListTrackItem> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        items.push(ListTrackItem {
+            list_id: row.get(0)?,
+            sort_index: row.get(1)?,
+            track: TrackItem::from_row(row, 2)?,
+        });
+    }
+
+    Ok((total, items))
+}
+
+#[derive(Serialize)]
+pub struct HistoryItem {
+    pub history_id: i64,
+    pub played: i64,
+    #[serde(flatten)]
+    pub track: TrackItem,
+}
+
+/// Plays of a user, most recent first.
+pub fn query_history(
+    index: &Index,
+    query: &HttpQuery,
+    user_id: i64,
+) -> Result<(i64, Vec<HistoryItem>), rusqlite::Error> {
+    let mut opts = QueryOptions::new();
+
+    opts.filter_value("StoreHistory.user_id = ?", user_id);
+    opts.bind_filter_i64(query, "track_id", "Track.track_id = ?");
+    opts.bind_filter_i64(
+        query,
+        "artist_id",
+        "Track.track_id IN (SELECT TrackArtist.track_id FROM TrackArtist WHERE TrackArtist.artist_id = ?)",
+    );
+    opts.bind_filter_i64(query, "album_id", "Track.album_id = ?");
+    opts.bind_filter_i64(query, "since", "StoreHistory.played >= ?");
+    opts.bind_filter_i64(query, "until", "StoreHistory.played < ?");
+
+    opts.order_string("StoreHistory.played DESC, StoreHistory.history_id DESC");
 
-        FROM Track",
+    opts.bind_range(query);
+
+    let conn = index.connection();
+
+    let total = opts.get_total(
+        conn,
+        "SELECT COUNT(StoreHistory.history_id)
+        FROM StoreHistory
+        INNER JOIN StoreTrack ON StoreTrack.store_track_id = StoreHistory.store_track_id
+        INNER JOIN Track ON Track.track_id = StoreTrack.track_id",
+    )?;
+
+    let (mut st, values) = opts.into_items_query(
+        conn,
+        &format!(
+            "SELECT
+                StoreHistory.history_id,
+                StoreHistory.played,
+                {}
+
+            FROM StoreHistory
+            INNER JOIN StoreTrack ON StoreTrack.store_track_id = StoreHistory.store_track_id
+            INNER JOIN Track ON Track.track_id = StoreTrack.track_id",
+            TrackItem::COLUMNS
+        ),
     )?;
 
     let mut rows = st.query(&values)?;
 
-    let mut items: Vec<TrackItem> = Vec::new();
+    let mut items: Vec<HistoryItem> = Vec::new();
 
     while let Some(row) = rows.next()? {
-        let path: Vec<u8> = row.get(9)?;
+        items.push(HistoryItem {
+            history_id: row.get(0)?,
+            played: row.get(1)?,
+            track: TrackItem::from_row(row, 2)?,
+        });
+    }
 
-        items.push(TrackItem {
-            track_id: row.get(0)?,
-            node_id: row.get(1)?,
-            number: row.get(2)?,
-            title: row.get(3)?,
-            artist_id: row.get(4)?,
-            artist_name: row.get(5)?,
-            album_id: row.get(6)?,
-            album_name: row.get(7)?,
-            length: row.get(8)?,
-            node_path: OsStr::from_bytes(&path).to_string_lossy().to_string(),
+    Ok((total, items))
+}
+
+#[derive(Serialize)]
+pub struct PlayedTrackItem {
+    pub play_count: i64,
+    pub last_play: i64,
+    #[serde(flatten)]
+    pub track: TrackItem,
+}
+
+/// Tracks with plays in the history of a user, most played first or with `order=last_play`
+/// most recently played first. Plays are counted only within `since` and `until` if given.
+pub fn query_played_tracks(
+    index: &Index,
+    query: &HttpQuery,
+    user_id: i64,
+) -> Result<(i64, Vec<PlayedTrackItem>), rusqlite::Error> {
+    let mut opts = QueryOptions::new();
+
+    opts.filter_value("StoreHistory.user_id = ?", user_id);
+    opts.bind_filter_i64(
+        query,
+        "artist_id",
+        "Track.track_id IN (SELECT TrackArtist.track_id FROM TrackArtist WHERE TrackArtist.artist_id = ?)",
+    );
+    opts.bind_filter_i64(query, "album_id", "Track.album_id = ?");
+    opts.bind_filter_i64(query, "since", "StoreHistory.played >= ?");
+    opts.bind_filter_i64(query, "until", "StoreHistory.played < ?");
+
+    opts.group_string("Track.track_id");
+
+    opts.order_string(match query.get_str("order") {
+        Some("last_play") => "last_play DESC, play_count DESC",
+        _ => "play_count DESC, last_play DESC",
+    });
+
+    opts.bind_range(query);
+
+    let conn = index.connection();
+
+    let total = opts.get_total(
+        conn,
+        "SELECT COUNT(DISTINCT Track.track_id)
+        FROM StoreHistory
+        INNER JOIN StoreTrack ON StoreTrack.store_track_id = StoreHistory.store_track_id
+        INNER JOIN Track ON Track.track_id = StoreTrack.track_id",
+    )?;
+
+    let (mut st, values) = opts.into_items_query(
+        conn,
+        &format!(
+            "SELECT
+                count(StoreHistory.history_id) AS play_count,
+                max(StoreHistory.played) AS last_play,
+                {}
+
+            FROM StoreHistory
+            INNER JOIN StoreTrack ON StoreTrack.store_track_id = StoreHistory.store_track_id
+            INNER JOIN Track ON Track.track_id = StoreTrack.track_id",
+            TrackItem::COLUMNS
+        ),
+    )?;
+
+    let mut rows = st.query(&values)?;
+
+    let mut items: Vec<PlayedTrackItem> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        items.push(PlayedTrackItem {
+            play_count: row.get(0)?,
+            last_play: row.get(1)?,
+            track: TrackItem::from_row(row, 2)?,
+        });
+    }
+
+    Ok((total, items))
+}
+
+#[derive(Serialize)]
+pub struct RatedTrackItem {
+    pub rating: i64,
+    #[serde(flatten)]
+    pub track: TrackItem,
+}
+
+/// Tracks rated by a user, highest rated first, at least `min_rating` if given.
+pub fn query_rated_tracks(
+    index: &Index,
+    query: &HttpQuery,
+    user_id: i64,
+) -> Result<(i64, Vec<RatedTrackItem>), rusqlite::Error> {
+    let mut opts = QueryOptions::new();
+
+    opts.filter_value("StoreRating.user_id = ?", user_id);
+    opts.bind_filter_i64(query, "track_id", "Track.track_id = ?");
+    opts.bind_filter_i64(query, "album_id", "Track.album_id = ?");
+    opts.bind_filter_i64(query, "min_rating", "StoreRating.rating >= ?");
+
+    opts.order_string("StoreRating.rating DESC, Track.title COLLATE NOCASE");
+
+    opts.bind_range(query);
+
+    let conn = index.connection();
+
+    let total = opts.get_total(
+        conn,
+        "SELECT COUNT(Track.track_id)
+        FROM StoreRating
+        INNER JOIN StoreTrack ON StoreTrack.store_track_id = StoreRating.store_track_id
+        INNER JOIN Track ON Track.track_id = StoreTrack.track_id",
+    )?;
+
+    let (mut st, values) = opts.into_items_query(
+        conn,
+        &format!(
+            "SELECT
+                StoreRating.rating,
+                {}
+
+            FROM StoreRating
+            INNER JOIN StoreTrack ON StoreTrack.store_track_id = StoreRating.store_track_id
+            INNER JOIN Track ON Track.track_id = StoreTrack.track_id",
+            TrackItem::COLUMNS
+        ),
+    )?;
+
+    let mut rows = st.query(&values)?;
+
+    let mut items: Vec<RatedTrackItem> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        items.push(RatedTrackItem {
+            rating: row.get(0)?,
+            track: TrackItem::from_row(row, 1)?,
         });
     }
 
@@ -358,9 +843,15 @@ pub fn query_tracks(
 
 #[derive(Serialize)]
 pub struct ArtistItem {
-    artist_id: i64,
-    name: String,
-    track_count: i64,
+    pub artist_id: i64,
+    pub name: String,
+    /// Largest of the artist images
+    pub image_id: Option<i64>,
+    /// Tracks the artist is credited on in any role
+    pub track_count: i64,
+    pub featured_track_count: i64,
+    /// Total length of the tracks
+    pub length: f64,
 }
 
 pub fn query_artists(
@@ -371,9 +862,31 @@ pub fn query_artists(
 
     opts.bind_filter_i64(&query, "artist_id", "Artist.artist_id = ?");
     opts.bind_filter_str(&query, "name", "Artist.name LIKE ? COLLATE NOCASE");
-    opts.bind_filter_str(&query, "search", "Artist.name LIKE ? COLLATE NOCASE");
+    opts.bind_filter_str(
+        query,
+        "role",
+        "Artist.artist_id IN (SELECT TrackArtist.artist_id FROM TrackArtist WHERE TrackArtist.role = ?)",
+    );
+
+    if let Some(search) = query.get_str("search").and_then(fts_match_query) {
+        opts.filter_value(
+            "Artist.artist_id IN (SELECT rowid FROM ArtistSearch WHERE ArtistSearch MATCH ?)",
+            search,
+        );
+    }
+
+    opts.filter_roots(
+        index,
+        "Artist.artist_id IN
+            (
+                SELECT TrackArtist.artist_id
+                FROM TrackArtist
+                INNER JOIN Track ON Track.track_id = TrackArtist.track_id
+                WHERE Track.node_id IN ({})
+            )",
+    );
 
-    opts.order_string("Artist.name");
+    opts.order_string("Artist.key");
 
     opts.bind_range(&query);
 
@@ -385,7 +898,26 @@ pub fn query_artists(
         "SELECT
             Artist.artist_id,
             Artist.name,
-            (SELECT count(Track.track_id) FROM Track WHERE Track.artist_id = Artist.artist_id) AS track_count
+            (
+                SELECT ArtistImage.image_id FROM ArtistImage
+                INNER JOIN Image ON Image.image_id = ArtistImage.image_id
+                WHERE ArtistImage.artist_id = Artist.artist_id
+                ORDER BY Image.width * Image.height DESC
+                LIMIT 1
+            ) AS image_id,
+            (SELECT count(DISTINCT TrackArtist.track_id) FROM TrackArtist WHERE TrackArtist.artist_id = Artist.artist_id) AS track_count,
+            (
+                SELECT count(TrackArtist.track_id) FROM TrackArtist
+                WHERE TrackArtist.artist_id = Artist.artist_id AND TrackArtist.role = 'featured'
+            ) AS featured_track_count,
+            (
+                SELECT total(Track.length) FROM Track
+                WHERE Track.track_id IN
+                    (
+                        SELECT TrackArtist.track_id FROM TrackArtist
+                        WHERE TrackArtist.artist_id = Artist.artist_id
+                    )
+            ) AS length
         FROM Artist")?;
 
     let mut rows = st.query(&values)?;
@@ -396,7 +928,10 @@ pub fn query_artists(
         items.push(ArtistItem {
             artist_id: row.get(0)?,
             name: row.get(1)?,
-            track_count: row.get(2)?,
+            image_id: row.get(2)?,
+            track_count: row.get(3)?,
+            featured_track_count: row.get(4)?,
+            length: row.get(5)?,
         });
     }
 
@@ -405,12 +940,13 @@ pub fn query_artists(
 
 #[derive(Serialize)]
 pub struct AlbumItem {
-    album_id: i64,
-    name: String,
-    artist_id: Option<i64>,
-    artist_name: Option<String>,
-    image_id: Option<i64>,
-    track_count: i64,
+    pub album_id: i64,
+    pub name: String,
+    pub artist_id: Option<i64>,
+    pub artist_name: Option<String>,
+    pub image_id: Option<i64>,
+    pub compilation: bool,
+    pub track_count: i64,
 }
 
 pub fn query_albums(
@@ -427,15 +963,31 @@ pub fn query_albums(
         "artist_name",
         "Album.artist_name LIKE ? COLLATE NOCASE",
     );
+    opts.bind_filter_i64(query, "compilation", "Album.compilation = ?");
+    opts.bind_filter_i64(
+        query,
+        "genre_id",
+        "Album.album_id IN
+            (
+                SELECT Track.album_id
+                FROM Track
+                INNER JOIN TrackGenre ON TrackGenre.track_id = Track.track_id
+                WHERE TrackGenre.genre_id = ?
+            )",
+    );
 
-    if let Some(search) = query.get_str("search") {
-        let mut values: Vec<Box<dyn ToSql>> = Vec::new();
-        values.push(Box::new(format!("%{}%", search)));
-        values.push(Box::new(format!("%{}%", search)));
-
-        opts.filter_values("(Album.name LIKE ? OR Album.artist_name LIKE ?)", values);
+    if let Some(search) = query.get_str("search").and_then(fts_match_query) {
+        opts.filter_value(
+            "Album.album_id IN (SELECT rowid FROM AlbumSearch WHERE AlbumSearch MATCH ?)",
+            search,
+        );
     }
 
+    opts.filter_roots(
+        index,
+        "Album.album_id IN (SELECT Track.album_id FROM Track WHERE Track.node_id IN ({}))",
+    );
+
     opts.order_string("Album.artist_name, Album.name");
 
     opts.bind_range(&query);
@@ -451,6 +1003,7 @@ pub fn query_albums(
             Album.artist_id,
             Album.artist_name,
             Album.image_id,
+            Album.compilation,
             (SELECT count(Track.track_id) FROM Track WHERE Track.album_id = Album.album_id) AS track_count
         FROM Album")?;
 
@@ -465,7 +1018,75 @@ pub fn query_albums(
             artist_id: row.get(2)?,
             artist_name: row.get(3)?,
             image_id: row.get(4)?,
-            track_count: row.get(5)?,
+            compilation: row.get(5)?,
+            track_count: row.get(6)?,
+        });
+    }
+
+    Ok((total, items))
+}
+
+#[derive(Serialize)]
+pub struct GenreItem {
+    pub genre_id: i64,
+    pub name: String,
+    pub track_count: i64,
+    pub album_count: i64,
+}
+
+pub fn query_genres(
+    index: &Index,
+    query: &HttpQuery,
+) -> Result<(i64, Vec<GenreItem>), rusqlite::Error> {
+    let mut opts = QueryOptions::new();
+
+    opts.bind_filter_i64(query, "genre_id", "Genre.genre_id = ?");
+    opts.bind_filter_str(query, "name", "Genre.name LIKE ? COLLATE NOCASE");
+
+    opts.filter_roots(
+        index,
+        "Genre.genre_id IN
+            (
+                SELECT TrackGenre.genre_id
+                FROM TrackGenre
+                INNER JOIN Track ON Track.track_id = TrackGenre.track_id
+                WHERE Track.node_id IN ({})
+            )",
+    );
+
+    opts.order_string("Genre.name");
+
+    opts.bind_range(query);
+
+    let conn = index.connection();
+
+    let total = opts.get_total(conn, "SELECT COUNT(Genre.genre_id) FROM Genre")?;
+
+    let (mut st, values) = opts.into_items_query(
+        conn,
+        "SELECT
+            Genre.genre_id,
+            Genre.name,
+            (SELECT count(TrackGenre.track_id) FROM TrackGenre WHERE TrackGenre.genre_id = Genre.genre_id) AS track_count,
+            (
+                SELECT count(DISTINCT Track.album_id)
+                FROM TrackGenre
+                INNER JOIN Track ON Track.track_id = TrackGenre.track_id
+                WHERE TrackGenre.genre_id = Genre.genre_id
+            ) AS album_count
+        FROM Genre",
+    )?;
+
+    let mut rows = st.query(&values)?;
+
+    let mut items: Vec<GenreItem> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        items.push(GenreItem {
+            genre_id: row.get(0)?,
+            name: row.get(1)?,
+            track_count: row.get(2)?,
+            album_count: row.get(3)?,
         });
     }
 
@@ -474,9 +1095,9 @@ pub fn query_albums(
 
 #[derive(Serialize)]
 pub struct ImageItem {
-    image_id: i64,
-    node_id: i64,
-    description: String,
+    pub image_id: i64,
+    pub node_id: i64,
+    pub description: String,
 }
 
 pub fn query_images(
@@ -489,6 +1110,7 @@ pub fn query_images(
     opts.bind_filter_i64(&query, "node_id", "Image.node_id = ?");
     opts.bind_filter_str(&query, "description", "Image.description = ?");
     opts.bind_filter_i64(&query, "album_id", "(SELECT album_id FROM AlbumImage WHERE AlbumImage.album_id = ? AND AlbumImage.image_id = Image.image_id LIMIT 1) IS NOT NULL");
+    opts.filter_roots(index, "Image.node_id IN ({})");
 
     opts.bind_range(&query);
 
@@ -519,3 +1141,457 @@ pub fn query_images(
 
     Ok((total, items))
 }
+
+#[derive(Serialize)]
+pub struct AlbumImageItem {
+    pub image_id: i64,
+    pub node_id: i64,
+    pub stream_index: Option<i64>,
+    pub description: String,
+    pub width: i64,
+    pub height: i64,
+    /// Earliest cover pattern the image matches
+    pub pattern: Option<String>,
+    /// Chosen as the album cover in the store
+    pub chosen: bool,
+    pub node_path: String,
+}
+
+/// Lists the cover candidates of an album in order of preference.
+pub fn query_album_images(
+    index: &Index,
+    query: &HttpQuery,
+) -> Result<(i64, Vec<AlbumImageItem>), rusqlite::Error> {
+    let mut opts = QueryOptions::new();
+
+    opts.bind_filter_i64(query, "album_id", "AlbumImage.album_id = ?");
+    opts.filter_roots(
+        index,
+        "AlbumImage.image_id IN (SELECT Image.image_id FROM Image WHERE Image.node_id IN ({}))",
+    );
+
+    opts.order_string(
+        "StoreAlbumCover.image_id IS NULL ASC,
+        pattern_order IS NULL ASC,
+        pattern_order ASC,
+        Image.description COLLATE NOCASE ASC",
+    );
+
+    opts.bind_range(query);
+
+    let conn = index.connection();
+
+    let total = opts.get_total(conn, "SELECT COUNT(AlbumImage.image_id) FROM AlbumImage")?;
+
+    let (mut st, values) = opts.into_items_query(
+        conn,
+        "SELECT
+            Image.image_id,
+            Image.node_id,
+            Image.stream_index,
+            Image.description,
+            Image.width,
+            Image.height,
+            (
+                SELECT pattern.pattern
+                FROM AlbumImagePattern pattern
+                WHERE Image.description LIKE pattern.pattern
+                ORDER BY pattern.rowid
+                LIMIT 1
+            ),
+            StoreAlbumCover.image_id IS NOT NULL,
+            Node.path,
+            (
+                SELECT min(pattern.rowid)
+                FROM AlbumImagePattern pattern
+                WHERE Image.description LIKE pattern.pattern
+            ) AS pattern_order
+        FROM AlbumImage
+        INNER JOIN Image ON Image.image_id = AlbumImage.image_id
+        INNER JOIN Node ON Node.node_id = Image.node_id
+        LEFT OUTER JOIN StoreAlbumCover ON
+            StoreAlbumCover.album_id = AlbumImage.album_id AND
+            StoreAlbumCover.image_id = Image.image_id",
+    )?;
+
+    let mut rows = st.query(&values)?;
+
+    let mut items: Vec<AlbumImageItem> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        let path: Vec<u8> = row.get(8)?;
+
+        items.push(AlbumImageItem {
+            image_id: row.get(0)?,
+            node_id: row.get(1)?,
+            stream_index: row.get(2)?,
+            description: row.get(3)?,
+            width: row.get(4)?,
+            height: row.get(5)?,
+            pattern: row.get(6)?,
+            chosen: row.get(7)?,
+            node_path: OsStr::from_bytes(&path).to_string_lossy().to_string(),
+        });
+    }
+
+    Ok((total, items))
+}
+
+#[derive(Serialize)]
+pub struct ScanItem {
+    pub scan_id: i64,
+    pub full: bool,
+    pub started: i64,
+    pub finished: i64,
+    pub interrupted: bool,
+    pub directories: i64,
+    pub files: i64,
+    pub tracks_added: i64,
+    pub tracks_updated: i64,
+    pub tracks_removed: i64,
+    pub images_added: i64,
+    pub images_updated: i64,
+    pub images_removed: i64,
+    pub errors: i64,
+}
+
+pub fn query_scans(
+    index: &Index,
+    query: &HttpQuery,
+) -> Result<(i64, Vec<ScanItem>), rusqlite::Error> {
+    let mut opts = QueryOptions::new();
+
+    opts.bind_filter_i64(query, "scan_id", "Scan.scan_id = ?");
+    opts.bind_filter_i64(query, "full", "Scan.full = ?");
+
+    opts.order_string("Scan.scan_id DESC");
+
+    opts.bind_range(query);
+
+    let conn = index.connection();
+
+    let total = opts.get_total(conn, "SELECT COUNT(Scan.scan_id) FROM Scan")?;
+
+    let (mut st, values) = opts.into_items_query(
+        conn,
+        "SELECT
+            Scan.scan_id,
+            Scan.full,
+            Scan.started,
+            Scan.finished,
+            Scan.interrupted,
+            Scan.directories,
+            Scan.files,
+            Scan.tracks_added,
+            Scan.tracks_updated,
+            Scan.tracks_removed,
+            Scan.images_added,
+            Scan.images_updated,
+            Scan.images_removed,
+            Scan.errors
+        FROM Scan",
+    )?;
+
+    let mut rows = st.query(&values)?;
+
+    let mut items: Vec<ScanItem> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        items.push(ScanItem {
+            scan_id: row.get(0)?,
+            full: row.get(1)?,
+            started: row.get(2)?,
+            finished: row.get(3)?,
+            interrupted: row.get(4)?,
+            directories: row.get(5)?,
+            files: row.get(6)?,
+            tracks_added: row.get(7)?,
+            tracks_updated: row.get(8)?,
+            tracks_removed: row.get(9)?,
+            images_added: row.get(10)?,
+            images_updated: row.get(11)?,
+            images_removed: row.get(12)?,
+            errors: row.get(13)?,
+        });
+    }
+
+    Ok((total, items))
+}
+
+/// Placeholders for the highlight markers, so that the column text can be escaped first
+const HIGHLIGHT_START: &str = "\u{e000}";
+const HIGHLIGHT_END: &str = "\u{e001}";
+
+/// Escapes column text highlighted with the placeholders for HTML, and puts in the markers.
+fn highlight_html(text: &str, start: &str, end: &str) -> String {
+    escape_html(text)
+        .replace(HIGHLIGHT_START, start)
+        .replace(HIGHLIGHT_END, end)
+}
+
+#[derive(Serialize)]
+pub struct SearchHighlight {
+    pub name: String,
+    pub artist_name: Option<String>,
+    pub album_name: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SearchItem {
+    /// `track`, `album` or `artist`
+    pub item_type: String,
+    pub item_id: i64,
+    /// Lower is better
+    pub rank: f64,
+    pub name: String,
+    pub artist_id: Option<i64>,
+    pub artist_name: Option<String>,
+    pub album_id: Option<i64>,
+    pub album_name: Option<String>,
+    pub image_id: Option<i64>,
+    pub highlight: SearchHighlight,
+}
+
+pub fn query_search(
+    index: &Index,
+    query: &HttpQuery,
+) -> Result<(i64, Vec<SearchItem>), rusqlite::Error> {
+    let search = match query.get_str("query").and_then(fts_match_query) {
+        Some(s) => s,
+        None => return Ok((0, Vec::new())),
+    };
+
+    let item_types: Vec<&str> = match query.get_str("item_type") {
+        Some(t) => t.split(',').collect(),
+        None => vec!["track", "album", "artist"],
+    };
+
+    let highlight_start = query.get_str("highlight_start").unwrap_or("<b>");
+    let highlight_end = query.get_str("highlight_end").unwrap_or("</b>");
+    let highlight = |text: &str| highlight_html(text, highlight_start, highlight_end);
+
+    let roots = root_node_ids(index);
+
+    // Condition limiting an item type to the allowed roots, `{}` being the node ids
+    let root_filter = |clause: &str| match &roots {
+        Some((node_ids, _)) => format!("AND {}", clause.replace("{}", node_ids)),
+        None => String::new(),
+    };
+
+    let root_values: Vec<&dyn ToSql> = match &roots {
+        Some((_, values)) => values.iter().map(|v| v.as_ref()).collect(),
+        None => Vec::new(),
+    };
+
+    let mut counts: Vec<String> = Vec::new();
+    // Select and the number of highlighted columns in it
+    let mut selects: Vec<(String, usize)> = Vec::new();
+
+    if item_types.contains(&"track") {
+        let filter = root_filter(
+            "TrackSearch.rowid IN (SELECT Track.track_id FROM Track WHERE Track.node_id IN ({}))",
+        );
+
+        counts.push(format!(
+            "SELECT count(*) FROM TrackSearch WHERE TrackSearch MATCH ? {}",
+            filter
+        ));
+        selects.push((
+            format!(
+                "SELECT
+                    'track',
+                    TrackSearch.rowid,
+                    bm25(TrackSearch, 4.0, 2.0, 1.0),
+                    TrackSearch.title,
+                    Track.artist_id,
+                    TrackSearch.artist_name,
+                    Track.album_id,
+                    TrackSearch.album_name,
+                    (SELECT Album.image_id FROM Album WHERE Album.album_id = Track.album_id),
+                    highlight(TrackSearch, 0, ?, ?),
+                    highlight(TrackSearch, 1, ?, ?),
+                    highlight(TrackSearch, 2, ?, ?)
+                FROM TrackSearch
+                INNER JOIN Track ON Track.track_id = TrackSearch.rowid
+                WHERE TrackSearch MATCH ? {}",
+                filter
+            ),
+            3,
+        ));
+    }
+
+    if item_types.contains(&"album") {
+        let filter = root_filter(
+            "AlbumSearch.rowid IN (SELECT Track.album_id FROM Track WHERE Track.node_id IN ({}))",
+        );
+
+        counts.push(format!(
+            "SELECT count(*) FROM AlbumSearch
+            WHERE AlbumSearch MATCH ?
+                AND EXISTS (SELECT 1 FROM Track WHERE Track.album_id = AlbumSearch.rowid)
+                {}",
+            filter
+        ));
+        selects.push((
+            format!(
+                "SELECT
+                    'album',
+                    AlbumSearch.rowid,
+                    bm25(AlbumSearch, 4.0, 2.0),
+                    AlbumSearch.name,
+                    Album.artist_id,
+                    AlbumSearch.artist_name,
+                    Album.album_id,
+                    NULL,
+                    Album.image_id,
+                    highlight(AlbumSearch, 0, ?, ?),
+                    highlight(AlbumSearch, 1, ?, ?),
+                    NULL
+                FROM AlbumSearch
+                INNER JOIN Album ON Album.album_id = AlbumSearch.rowid
+                WHERE AlbumSearch MATCH ?
+                    AND EXISTS (SELECT 1 FROM Track WHERE Track.album_id = AlbumSearch.rowid)
+                    {}",
+                filter
+            ),
+            2,
+        ));
+    }
+
+    if item_types.contains(&"artist") {
+        let filter = root_filter(
+            "ArtistSearch.rowid IN
+                (
+                    SELECT TrackArtist.artist_id
+                    FROM TrackArtist
+                    INNER JOIN Track ON Track.track_id = TrackArtist.track_id
+                    WHERE Track.node_id IN ({})
+                )",
+        );
+
+        counts.push(format!(
+            "SELECT count(*) FROM ArtistSearch
+            WHERE ArtistSearch MATCH ?
+                AND EXISTS (SELECT 1 FROM TrackArtist WHERE TrackArtist.artist_id = ArtistSearch.rowid)
+                {}",
+            filter
+        ));
+        selects.push((
+            format!(
+                "SELECT
+                    'artist',
+                    ArtistSearch.rowid,
+                    bm25(ArtistSearch, 4.0),
+                    ArtistSearch.name,
+                    ArtistSearch.rowid,
+                    NULL,
+                    NULL,
+                    NULL,
+                    NULL,
+                    highlight(ArtistSearch, 0, ?, ?),
+                    NULL,
+                    NULL
+                FROM ArtistSearch
+                WHERE ArtistSearch MATCH ?
+                    AND EXISTS (SELECT 1 FROM TrackArtist WHERE TrackArtist.artist_id = ArtistSearch.rowid)
+                    {}",
+                filter
+            ),
+            1,
+        ));
+    }
+
+    if selects.is_empty() {
+        return Ok((0, Vec::new()));
+    }
+
+    let conn = index.connection();
+
+    let total: i64 = {
+        let sql = format!("SELECT ({})", counts.join(") + ("));
+
+        let mut values: Vec<&dyn ToSql> = Vec::new();
+        for _ in counts.iter() {
+            values.push(&search);
+            values.extend(&root_values);
+        }
+
+        conn.query_row(&sql, &values, |row| row.get(0))?
+    };
+
+    // Highlight markers come before the match expression and root filter in each select
+    let mut values: Vec<&dyn ToSql> = Vec::new();
+
+    for (_, highlights) in selects.iter() {
+        for _ in 0..*highlights {
+            values.push(&HIGHLIGHT_START);
+            values.push(&HIGHLIGHT_END);
+        }
+
+        values.push(&search);
+        values.extend(&root_values);
+    }
+
+    let limit = query.get_i64("limit").unwrap_or(50);
+    let offset = query.get_i64("offset").unwrap_or(0);
+
+    values.push(&limit);
+    values.push(&offset);
+
+    let sql: Vec<&str> = selects.iter().map(|s| s.0.as_str()).collect();
+
+    let mut st = conn.prepare(&format!(
+        "{} ORDER BY 3 LIMIT ? OFFSET ?",
+        sql.join(" UNION ALL ")
+    ))?;
+
+    let mut rows = st.query(&values)?;
+
+    let mut items: Vec<SearchItem> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        items.push(SearchItem {
+            item_type: row.get(0)?,
+            item_id: row.get(1)?,
+            rank: row.get(2)?,
+            name: row.get(3)?,
+            artist_id: row.get(4)?,
+            artist_name: row.get(5)?,
+            album_id: row.get(6)?,
+            album_name: row.get(7)?,
+            image_id: row.get(8)?,
+            highlight: SearchHighlight {
+                name: highlight(&row.get::<_, String>(9)?),
+                artist_name: row.get::<_, Option<String>>(10)?.as_deref().map(highlight),
+                album_name: row.get::<_, Option<String>>(11)?.as_deref().map(highlight),
+            },
+        });
+    }
+
+    Ok((total, items))
+}
+
+#[test]
+fn test_fts_match_query() {
+    assert_eq!(
+        fts_match_query("beatles  abbey"),
+        Some("\"beatles\"* \"abbey\"*".to_string())
+    );
+    assert_eq!(
+        fts_match_query("say \"hi\""),
+        Some("\"say\"* \"\"\"hi\"\"\"*".to_string())
+    );
+    assert_eq!(fts_match_query(" - "), None);
+}
+
+#[test]
+fn test_highlight_html() {
+    assert_eq!(
+        highlight_html(
+            "<img src=x onerror=alert(1)> \u{e000}Song\u{e001} & co",
+            "<b>",
+            "</b>"
+        ),
+        "&lt;img src=x onerror=alert(1)&gt; <b>Song</b> &amp; co"
+    );
+}
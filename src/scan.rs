@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::convert::From;
 use std::error::Error as StdError;
 use std::ffi::OsStr;
@@ -9,9 +10,17 @@ use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 use std::time::Instant;
 
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::config::Config;
 use crate::cue;
-use crate::index::{Image, Index, Node, NodeType, Track};
+use crate::index::{
+    artist_key, disc_directory_number, Artist, Image, Index, Node, NodeType, Track,
+    ARTIST_ROLE_FEATURED, ARTIST_ROLE_MAIN,
+};
 use crate::media;
+use crate::store::Store;
 
 #[derive(Debug)]
 pub enum Error {
@@ -50,45 +59,143 @@ impl StdError for Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Album without an album artist tag is a compilation with this many track artists by default
+const DEFAULT_COMPILATION_ARTISTS: i64 = 4;
+
+/// Patterns album covers are picked by when none are configured, in order of preference
+const DEFAULT_COVER_PATTERNS: &[&str] = &[
+    "album cover",
+    "albumcover",
+    "albumart",
+    "album",
+    "front",
+    "folder",
+    "front%",
+    "cover%",
+    "folder%",
+    "%front%",
+    "%cover%",
+    "%folder%",
+    "%albumart%",
+    "%album%",
+    "%jacket%",
+    "%card%",
+];
+
+/// Returns the configured album cover patterns, or the default ones.
+pub fn config_cover_patterns(config: &Config) -> Vec<String> {
+    match &config.cover_patterns {
+        Some(patterns) => patterns.clone(),
+        None => DEFAULT_COVER_PATTERNS
+            .iter()
+            .map(|p| p.to_string())
+            .collect(),
+    }
+}
+
+/// Scan settings taken from `Config`
+#[derive(Debug, Clone)]
+struct Settings {
+    /// Used unless overridden in the store
+    cover_patterns: Vec<String>,
+    compilation_artists: i64,
+    /// Artist names by `artist_key` of their aliases
+    artist_aliases: HashMap<String, String>,
+}
+
 pub struct ScanThread {
+    settings: Settings,
     stop: Arc<AtomicBool>,
+    status: Arc<Mutex<ScanStatus>>,
     join_handle: Mutex<Option<JoinHandle<ScanStat>>>,
 }
 
 impl ScanThread {
-    pub fn new() -> ScanThread {
+    pub fn new(config: &Config) -> ScanThread {
         ScanThread {
+            settings: Settings {
+                cover_patterns: config_cover_patterns(config),
+                compilation_artists: config
+                    .compilation_artists
+                    .unwrap_or(DEFAULT_COMPILATION_ARTISTS),
+                artist_aliases: config
+                    .artist_aliases
+                    .iter()
+                    .map(|(alias, name)| (artist_key(alias), name.to_string()))
+                    .collect(),
+            },
             stop: Arc::new(AtomicBool::new(false)),
+            status: Arc::new(Mutex::new(ScanStatus {
+                ..Default::default()
+            })),
             join_handle: Mutex::new(None),
         }
     }
 
     pub fn is_running(&self) -> bool {
-        self.join_handle.lock().unwrap().is_some()
+        self.status.lock().unwrap().running
     }
 
-    pub fn start(&self, index: Index) {
-        {
-            if self.join_handle.lock().unwrap().is_some() {
-                return;
-            }
-        }
+    /// Progress of the running scan, or the result of the last one.
+    pub fn status(&self) -> ScanStatus {
+        self.status.lock().unwrap().clone()
+    }
 
-        let stop = self.stop.clone();
+    /// Starts a full scan of all roots. Returns false if a scan is already running.
+    pub fn start(&self, index: Index, store: Store) -> bool {
+        self.spawn(index, store, true, |scan| scan.scan_core())
+    }
+
+    /// Starts a scan of the directories containing `paths`, which are index paths beginning
+    /// with a root name. Returns false if a scan is already running.
+    pub fn start_paths(&self, index: Index, store: Store, paths: Vec<PathBuf>) -> bool {
+        self.spawn(index, store, false, move |scan| scan.scan_paths(paths))
+    }
 
+    fn spawn<F>(&self, index: Index, store: Store, full: bool, f: F) -> bool
+    where
+        F: FnOnce(&mut Scan) + Send + 'static,
+    {
         let mut join_handle = self.join_handle.lock().unwrap();
 
+        if self.is_running() {
+            return false;
+        }
+
+        if let Some(handle) = join_handle.take() {
+            let stat = handle.join().unwrap();
+            trace!("previous scan: {:?}", stat);
+        }
+
+        *self.status.lock().unwrap() = ScanStatus {
+            running: true,
+            full,
+            started: Some(unix_time()),
+            ..Default::default()
+        };
+
+        let settings = self.settings.clone();
+        let stop = self.stop.clone();
+        let status = self.status.clone();
+
         self.stop.store(false, Ordering::Relaxed);
 
         *join_handle = Some(std::thread::spawn(move || {
             let mut scan = Scan {
+                settings,
                 stop,
                 stop_detected: false,
                 index,
+                store,
+                status,
             };
 
-            scan.scan_core()
+            f(&mut scan);
+
+            scan.finish()
         }));
+
+        true
     }
 
     pub fn stop(&self) {
@@ -97,15 +204,149 @@ impl ScanThread {
         self.stop.store(true, Ordering::Relaxed);
 
         if let Some(handle) = join_handle.take() {
-            handle.join().unwrap();
+            let stat = handle.join().unwrap();
+            debug!("stopped scan: {:?}", stat);
         }
     }
 }
 
+pub fn unix_time() -> i64 {
+    match std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH) {
+        Ok(n) => n.as_secs() as i64,
+        Err(_) => 0,
+    }
+}
+
+/// Markers of featured artists in artist credits and titles
+const FEATURED_MARKERS: &[&str] = &[
+    " feat. ",
+    " feat ",
+    " ft. ",
+    " ft ",
+    " featuring ",
+    "(feat. ",
+    "(ft. ",
+    "(featuring ",
+    "[feat. ",
+    "[ft. ",
+];
+
+/// Separators of artists credited jointly
+const JOINT_SEPARATORS: &[&str] = &[" & ", " x ", " vs. ", " vs ", " / ", ";"];
+
+/// Separators of featured artists, which are less likely to be part of a name
+const FEATURED_SEPARATORS: &[&str] = &[" & ", " and ", ", ", " x ", " / ", ";"];
+
+/// Returns the start and end of the first featured artists marker in `text`.
+fn find_featured_marker(text: &str) -> Option<(usize, usize)> {
+    let lower = text.to_ascii_lowercase();
+
+    FEATURED_MARKERS
+        .iter()
+        .filter_map(|m| lower.find(m).map(|i| (i, i + m.len())))
+        .min()
+}
+
+/// Splits `text` with any of `separators`, ignoring ASCII case.
+fn split_ignore_case<'a>(text: &'a str, separators: &[&str]) -> Vec<&'a str> {
+    let lower = text.to_ascii_lowercase();
+    let mut parts = Vec::new();
+    let mut pos = 0;
+
+    while let Some((start, end)) = separators
+        .iter()
+        .filter_map(|s| lower[pos..].find(s).map(|i| (pos + i, pos + i + s.len())))
+        .min()
+    {
+        parts.push(&text[pos..start]);
+        pos = end;
+    }
+
+    parts.push(&text[pos..]);
+
+    parts
+        .into_iter()
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+/// Splits an artist credit such as "A & B feat. C" into artists and their roles. Jointly
+/// credited artists are only split if `is_known` holds for each of them, as the separators are
+/// just as often part of a group name like "Simon & Garfunkel".
+fn split_artist_credit<F>(credit: &str, is_known: F) -> Result<Vec<(&str, &'static str)>>
+where
+    F: Fn(&str) -> Result<bool>,
+{
+    let (main, featured) = match find_featured_marker(credit) {
+        Some((start, end)) => (
+            &credit[..start],
+            credit[end..].trim_end_matches(&[')', ']'][..]),
+        ),
+        None => (credit, ""),
+    };
+
+    let mut main_names = split_ignore_case(main, JOINT_SEPARATORS);
+
+    if main_names.len() > 1 {
+        for name in main_names.iter() {
+            if !is_known(name)? {
+                main_names = split_ignore_case(main, &[]);
+                break;
+            }
+        }
+    }
+
+    Ok(main_names
+        .into_iter()
+        .map(|n| (n, ARTIST_ROLE_MAIN))
+        .chain(
+            split_ignore_case(featured, FEATURED_SEPARATORS)
+                .into_iter()
+                .map(|n| (n, ARTIST_ROLE_FEATURED)),
+        )
+        .collect())
+}
+
+/// Returns artists featured in a title such as "Song (feat. A & B)".
+fn title_featured_artists(title: &str) -> Vec<&str> {
+    let lower = title.to_ascii_lowercase();
+
+    // Only bracketed markers, "feat" alone may well be part of a title
+    let end = match FEATURED_MARKERS
+        .iter()
+        .filter(|m| m.starts_with(&['(', '['][..]))
+        .filter_map(|m| lower.find(m).map(|i| i + m.len()))
+        .min()
+    {
+        Some(e) => e,
+        None => return Vec::new(),
+    };
+
+    let rest = &title[end..];
+
+    match rest.find(&[')', ']'][..]) {
+        Some(close) => split_ignore_case(&rest[..close], FEATURED_SEPARATORS),
+        None => Vec::new(),
+    }
+}
+
+/// Splits a genre tag such as "Rock; Pop/Jazz" into separate genres.
+fn split_genres(genre: &str) -> Vec<&str> {
+    genre
+        .split(&[';', '/'][..])
+        .map(|g| g.trim())
+        .filter(|g| !g.is_empty())
+        .collect()
+}
+
 struct Scan {
+    settings: Settings,
     stop: Arc<AtomicBool>,
     stop_detected: bool,
     index: Index,
+    store: Store,
+    status: Arc<Mutex<ScanStatus>>,
 }
 
 enum NodeArg<'a> {
@@ -120,23 +361,79 @@ struct ScanNode<'a> {
     modified: i64,
 }
 
-#[derive(Debug, Default)]
-struct ScanStat {
-    tracks: i32,
-    images: i32,
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ScanStat {
+    pub directories: i64,
+    pub files: i64,
+    pub tracks_added: i64,
+    pub tracks_updated: i64,
+    pub tracks_removed: i64,
+    pub images_added: i64,
+    pub images_updated: i64,
+    pub images_removed: i64,
+    pub errors: i64,
 }
 
 impl ScanStat {
     fn add(&mut self, other: &ScanStat) {
-        self.tracks += other.tracks;
-        self.images += other.images;
+        self.directories += other.directories;
+        self.files += other.files;
+        self.tracks_added += other.tracks_added;
+        self.tracks_updated += other.tracks_updated;
+        self.tracks_removed += other.tracks_removed;
+        self.images_added += other.images_added;
+        self.images_updated += other.images_updated;
+        self.images_removed += other.images_removed;
+        self.errors += other.errors;
+    }
+
+    /// Accounts for tracks and images cleared from a node before processing it again, counting
+    /// the ones added back as updated.
+    fn replace(&mut self, tracks_removed: i64, images_removed: i64) {
+        let tracks_updated = tracks_removed.min(self.tracks_added);
+        self.tracks_added -= tracks_updated;
+        self.tracks_updated += tracks_updated;
+        self.tracks_removed += tracks_removed - tracks_updated;
+
+        let images_updated = images_removed.min(self.images_added);
+        self.images_added -= images_updated;
+        self.images_updated += images_updated;
+        self.images_removed += images_removed - images_updated;
     }
 
     fn changed(&self) -> bool {
-        self.tracks > 0 || self.images > 0
+        self.tracks_added > 0
+            || self.tracks_updated > 0
+            || self.tracks_removed > 0
+            || self.images_added > 0
+            || self.images_updated > 0
+            || self.images_removed > 0
     }
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanError {
+    pub path: String,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ScanStatus {
+    pub running: bool,
+    /// False for scans of changed directories only
+    pub full: bool,
+    pub started: Option<i64>,
+    pub finished: Option<i64>,
+    pub root: Option<String>,
+    /// Directory being scanned
+    pub path: Option<String>,
+    pub stat: ScanStat,
+    /// First `MAX_ERRORS` errors
+    pub errors: Vec<ScanError>,
+}
+
+const MAX_ERRORS: usize = 1000;
+
 impl Scan {
     fn interrupted(&mut self) -> bool {
         let stop = self.stop.load(Ordering::Relaxed);
@@ -149,49 +446,119 @@ impl Scan {
         stop
     }
 
-    fn scan_core(&mut self) -> ScanStat {
-        info!("started");
+    fn report<F>(&self, f: F)
+    where
+        F: FnOnce(&mut ScanStatus),
+    {
+        f(&mut self.status.lock().unwrap());
+    }
 
-        let mut stat = ScanStat {
-            ..Default::default()
-        };
+    fn report_error(&self, fs_path: &Path, error: &str) {
+        error!("'{}': {}", fs_path.to_string_lossy(), error);
 
-        if self
-            .index
-            .connection()
-            .execute_batch("DELETE FROM AlbumImagePattern;")
-            .is_err()
-        {
-            return stat;
+        self.report(|s| {
+            s.stat.errors += 1;
+
+            if s.errors.len() < MAX_ERRORS {
+                s.errors.push(ScanError {
+                    path: fs_path.to_string_lossy().to_string(),
+                    error: error.to_string(),
+                });
+            }
+        });
+    }
+
+    /// Saves the scan to history and marks it finished.
+    fn finish(&mut self) -> ScanStat {
+        let status = self.status.lock().unwrap().clone();
+        let finished = unix_time();
+
+        let stat = status.stat;
+
+        if let Err(e) = self.index.connection().execute(
+            "INSERT INTO Scan (full, started, finished, interrupted, directories, files, tracks_added, tracks_updated, tracks_removed, images_added, images_updated, images_removed, errors)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                status.full,
+                status.started.unwrap_or(finished),
+                finished,
+                self.stop_detected,
+                stat.directories,
+                stat.files,
+                stat.tracks_added,
+                stat.tracks_updated,
+                stat.tracks_removed,
+                stat.images_added,
+                stat.images_updated,
+                stat.images_removed,
+                stat.errors,
+            ],
+        ) {
+            error!("can't save scan history: {}", e.description());
+        }
+
+        // Tracks that were recreated need to be matched with the store again
+        if stat.changed() {
+            if let Err(e) = self.index.delete_unused_genres() {
+                error!("can't delete unused genres: {}", e.description());
+            }
+
+            if let Err(e) = self.index.delete_unused_artists() {
+                error!("can't delete unused artists: {}", e.description());
+            }
+
+            if let Err(e) = self.store.synchronize() {
+                error!("can't synchronize store: {}", e.description());
+            }
         }
 
+        self.report(|s| {
+            s.running = false;
+            s.finished = Some(finished);
+            s.root = None;
+            s.path = None;
+        });
+
+        stat
+    }
+
+    /// Reports errors not reported where they occurred, which are all but `OtherError`.
+    fn report_scan_error(&self, fs_path: &Path, error: &Error) {
+        if let Error::OtherError = error {
+            return;
+        }
+
+        self.report_error(fs_path, &error.to_string());
+    }
+
+    fn delete_node(&self, node: &Node) -> Result<()> {
+        let (tracks, images) = self.index.count_node_items(node.node_id)?;
+
+        self.index.delete_node(node.node_id)?;
+
+        self.report(|s| {
+            s.stat.tracks_removed += tracks;
+            s.stat.images_removed += images;
+        });
+
+        Ok(())
+    }
+
+    fn scan_core(&mut self) {
+        info!("started");
+
+        let cover_patterns = match self.store.cover_patterns() {
+            Ok(Some(patterns)) => patterns,
+            Ok(None) => self.settings.cover_patterns.clone(),
+            Err(_) => return,
+        };
+
         if self
             .index
-            .connection()
-            .execute_batch(
-                "
-                INSERT INTO AlbumImagePattern (pattern)
-                VALUES
-                    ('album cover'),
-                    ('albumcover'),
-                    ('albumart'),
-                    ('album'),
-                    ('front'),
-                    ('folder'),
-                    ('front%'),
-                    ('cover%'),
-                    ('folder%'),
-                    ('%front%'),
-                    ('%cover%'),
-                    ('%folder%'),
-                    ('%albumart%'),
-                    ('%album%'),
-                    ('%jacket%'),
-                    ('%card%')",
-            )
+            .set_album_image_patterns(&cover_patterns)
             .is_err()
         {
-            return stat;
+            return;
         }
 
         let roots: Vec<(String, PathBuf)> = self
@@ -205,31 +572,106 @@ impl Scan {
 
         for (name, path) in roots {
             if self.interrupted() {
-                return stat;
+                return;
             }
 
             debug!("root '{}' = '{}'", name, path.to_string_lossy());
 
-            match self.scan_node_unprepared(None, Path::new(OsStr::from_bytes(name.as_bytes()))) {
-                Ok(s) => {
-                    if let Some(s) = s {
-                        stat.add(&s);
-                    }
-                }
-                Err(e) => {
-                    error!(
-                        "can't scan root '{}' -> '{}': {}",
-                        name,
-                        path.to_string_lossy(),
-                        e.description()
-                    );
-                }
+            self.report(|s| s.root = Some(name.to_string()));
+
+            if let Err(e) =
+                self.scan_node_unprepared(None, Path::new(OsStr::from_bytes(name.as_bytes())))
+            {
+                self.report_scan_error(&path, &e);
             }
         }
 
-        info!("done in {}s: {:?}", start_instant.elapsed().as_secs(), stat);
+        info!(
+            "done in {}s: {:?}",
+            start_instant.elapsed().as_secs(),
+            self.status.lock().unwrap().stat
+        );
+    }
 
-        stat
+    fn scan_paths(&mut self, paths: Vec<PathBuf>) {
+        info!("started for {} changed paths", paths.len());
+
+        // Entries are created, deleted and modified through their parent directory, which is
+        // what gets scanned. Sorting puts ancestors first, so nested directories can be left
+        // out as they are scanned as part of their ancestor.
+        let mut directories: Vec<&Path> = paths
+            .iter()
+            .map(|p| match p.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => p,
+            })
+            .collect();
+
+        directories.sort();
+
+        let mut scan_directories: Vec<&Path> = Vec::new();
+
+        for directory in directories {
+            if !scan_directories.iter().any(|d| directory.starts_with(d)) {
+                scan_directories.push(directory);
+            }
+        }
+
+        let start_instant = Instant::now();
+
+        for directory in scan_directories {
+            if self.interrupted() {
+                return;
+            }
+
+            let root = directory
+                .iter()
+                .next()
+                .map(|r| r.to_string_lossy().to_string());
+
+            self.report(|s| s.root = root);
+
+            if let Err(e) = self.scan_directory_path(directory) {
+                self.report_scan_error(directory, &e);
+            }
+        }
+
+        info!(
+            "done in {}s: {:?}",
+            start_instant.elapsed().as_secs(),
+            self.status.lock().unwrap().stat
+        );
+    }
+
+    /// Scans the deepest directory node along `path` that is already in the index.
+    fn scan_directory_path(&mut self, path: &Path) -> Result<Option<ScanStat>> {
+        let mut nodes: Vec<Node> = Vec::new();
+
+        for name in path.iter() {
+            let parent_id = nodes.last().map(|n| n.node_id);
+
+            match self.index.node_by_name(parent_id, Path::new(name))? {
+                Some(node) if node.node_type == NodeType::Directory => nodes.push(node),
+                _ => break,
+            }
+        }
+
+        let mut node = match nodes.pop() {
+            Some(n) => n,
+            None => {
+                debug!("no directory node for '{}'", path.to_string_lossy());
+                return Ok(None);
+            }
+        };
+
+        // Entries may have changed within the same second as the last scan, so the directory
+        // is read regardless of its modification time
+        node.modified = 0;
+
+        let parent = nodes.pop();
+        let scan_node = self.prepare_node(parent.as_ref(), NodeArg::Node(node))?;
+
+        self.scan_node(scan_node)
     }
 
     fn scan_node_unprepared(
@@ -250,11 +692,14 @@ impl Scan {
         } = scan_node;
 
         let result = if node.node_type == NodeType::Directory {
+            self.report(|s| s.stat.directories += 1);
+
             let result = self.process_directory_node(&node, &fs_path, node.modified != modified)?;
 
             if let Some(result) = &result {
                 if result.changed() {
-                    self.index.process_node_updates(node.node_id)?;
+                    self.index
+                        .process_node_updates(node.node_id, self.settings.compilation_artists)?;
                 }
             }
 
@@ -263,10 +708,7 @@ impl Scan {
             let parent = match parent {
                 Some(n) => n,
                 None => {
-                    error!(
-                        "root node '{}' isn't directory",
-                        node.name.to_string_lossy()
-                    );
+                    self.report_error(&fs_path, "root node isn't directory");
                     return Err(Error::OtherError);
                 }
             };
@@ -275,13 +717,27 @@ impl Scan {
                 // TODO should this trigger master rescan?
                 None
             } else {
-                self.index.clear_node(node.node_id)?;
+                let (tracks_removed, images_removed) = self.index.clear_node(node.node_id)?;
 
-                self.process_file_node(parent, &node, &fs_path)?
+                let mut stat = self
+                    .process_file_node(parent, &node, &fs_path)?
+                    .unwrap_or_default();
+
+                stat.replace(tracks_removed as i64, images_removed as i64);
+
+                self.report(|s| s.stat.add(&stat));
+
+                Some(stat)
             };
 
+            self.report(|s| s.stat.files += 1);
+
             Ok(result)
         } else {
+            if node.node_type == NodeType::File {
+                self.report(|s| s.stat.files += 1);
+            }
+
             Ok(None)
         };
 
@@ -317,7 +773,7 @@ impl Scan {
         let fs_path = match self.index.map_fs_path(&path) {
             Some(p) => p,
             None => {
-                error!("can't map path '{}'", path.display());
+                self.report_error(&path, "can't map path");
                 return Err(Error::OtherError);
             }
         };
@@ -329,14 +785,14 @@ impl Scan {
         let metadata = match fs::metadata(&fs_path) {
             Ok(m) => m,
             Err(e) => {
-                error!(
-                    "metadata error '{}': {}",
-                    fs_path.to_string_lossy(),
-                    e.description()
-                );
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    debug!("'{}' removed", fs_path.to_string_lossy());
+                } else {
+                    self.report_error(&fs_path, &format!("metadata error: {}", e));
+                }
 
                 if let Some(node) = node {
-                    self.index.delete_node(node.node_id)?;
+                    self.delete_node(&node)?;
                 }
 
                 return Err(Error::OtherError);
@@ -349,10 +805,10 @@ impl Scan {
         {
             Ok(n) => n.as_secs(),
             Err(_) => {
-                error!("invalid modified '{}'", fs_path.to_string_lossy());
+                self.report_error(&fs_path, "invalid modified");
 
                 if let Some(node) = node {
-                    self.index.delete_node(node.node_id)?;
+                    self.delete_node(&node)?;
                 }
 
                 return Err(Error::OtherError);
@@ -375,7 +831,7 @@ impl Scan {
                     n.node_type,
                     node_type
                 );
-                self.index.delete_node(n.node_id)?;
+                self.delete_node(n)?;
 
                 node = None;
             }
@@ -419,6 +875,8 @@ impl Scan {
     ) -> Result<Option<ScanStat>> {
         debug!("directory '{}'", fs_path.to_string_lossy());
 
+        self.report(|s| s.path = Some(fs_path.to_string_lossy().to_string()));
+
         let mut stat = ScanStat {
             ..Default::default()
         };
@@ -439,10 +897,15 @@ impl Scan {
                 fs_entries.remove(pos);
             }
 
-            if let Ok(scan_node) = self.prepare_node(Some(node), NodeArg::Node(index_node)) {
-                if let Ok(Some(node_stat)) = self.scan_node(scan_node) {
-                    stat.add(&node_stat);
-                }
+            let entry_fs_path = fs_path.join(&index_node.name);
+
+            match self
+                .prepare_node(Some(node), NodeArg::Node(index_node))
+                .and_then(|scan_node| self.scan_node(scan_node))
+            {
+                Ok(Some(node_stat)) => stat.add(&node_stat),
+                Ok(None) => {}
+                Err(e) => self.report_scan_error(&entry_fs_path, &e),
             }
         }
 
@@ -451,8 +914,10 @@ impl Scan {
                 return Ok(Some(stat));
             }
 
-            if let Ok(Some(node_stat)) = self.scan_node_unprepared(Some(&node), Path::new(&entry)) {
-                stat.add(&node_stat);
+            match self.scan_node_unprepared(Some(node), Path::new(&entry)) {
+                Ok(Some(node_stat)) => stat.add(&node_stat),
+                Ok(None) => {}
+                Err(e) => self.report_scan_error(&fs_path.join(&entry), &e),
             }
         }
 
@@ -538,6 +1003,7 @@ impl Scan {
             };
 
             let mut tracks: Vec<Track> = Vec::new();
+            let track_total = file.tracks.len() as i64;
 
             for cue_track in file.tracks {
                 tracks.push(Track {
@@ -559,6 +1025,19 @@ impl Scan {
                     },
                     start: Some(cue_track.start as f64),
                     length: 0f64,
+                    // Tags of the file apply to the whole album
+                    date: file_track.date.clone(),
+                    year: file_track.year,
+                    original_date: file_track.original_date.clone(),
+                    genre: file_track.genre.clone(),
+                    disc_number: file_track.disc_number,
+                    disc_total: file_track.disc_total,
+                    track_total: Some(track_total),
+                    label: file_track.label.clone(),
+                    catalog_number: file_track.catalog_number.clone(),
+                    musicbrainz_release_id: file_track.musicbrainz_release_id.clone(),
+                    compilation: file_track.compilation,
+                    ..Default::default()
                 });
             }
 
@@ -570,39 +1049,26 @@ impl Scan {
                 last_start = start;
             }
 
-            self.index.clear_node(file_node.node.node_id)?;
+            let (tracks_removed, images_removed) = self.index.clear_node(file_node.node.node_id)?;
+
+            let mut file_stat = ScanStat {
+                ..Default::default()
+            };
 
             for track in tracks.iter_mut() {
-                track.artist_id = match self.index.artist_by_name(&track.artist_name)? {
-                    Some(a) => a,
-                    None => self.index.create_artist(&track.artist_name)?,
-                }
-                .artist_id;
-
-                track.album_id = match self
-                    .index
-                    .find_album(file_node.node.node_id, &track.album_name)?
-                {
-                    Some(a) => a,
-                    None => self.index.create_album(&track.album_name)?,
-                }
-                .album_id;
-
-                if let Some(album_artist_name) = &track.album_artist_name {
-                    track.album_artist_id = Some(
-                        match self.index.artist_by_name(&album_artist_name)? {
-                            Some(a) => a,
-                            None => self.index.create_artist(&album_artist_name)?,
-                        }
-                        .artist_id,
-                    );
-                }
+                let credits = self.resolve_artists(track)?;
+                self.resolve_album(&file_node.node, track)?;
 
-                self.index.create_track(&track)?;
+                let track = self.index.create_track(track)?;
+                self.link_artists(&track, &credits)?;
+                self.link_genres(&track)?;
 
-                stat.tracks += 1;
+                file_stat.tracks_added += 1;
             }
 
+            file_stat.replace(tracks_removed as i64, images_removed as i64);
+            stat.add(&file_stat);
+
             self.index
                 .set_node_master(file_node.node.node_id, node.node_id)?;
             self.index
@@ -612,6 +1078,135 @@ impl Scan {
         Ok(Some(stat))
     }
 
+    /// Finds or creates an artist, mapping configured aliases to their artist.
+    fn resolve_artist(&self, name: &str) -> Result<Artist> {
+        let name = match self.settings.artist_aliases.get(&artist_key(name)) {
+            Some(n) => n,
+            None => name,
+        };
+
+        Ok(match self.index.artist_by_name(name)? {
+            Some(a) => a,
+            None => self.index.create_artist(name)?,
+        })
+    }
+
+    /// Whether `name` is an artist in the index or in the configured aliases.
+    fn is_known_artist(&self, name: &str) -> Result<bool> {
+        let key = artist_key(name);
+
+        if self.settings.artist_aliases.contains_key(&key)
+            || self
+                .settings
+                .artist_aliases
+                .values()
+                .any(|n| artist_key(n) == key)
+        {
+            return Ok(true);
+        }
+
+        Ok(self.index.artist_by_name(name)?.is_some())
+    }
+
+    /// Resolves the artists credited on a track with their roles, setting the artist and album
+    /// artist of the track. Credits matching a configured alias aren't split.
+    fn resolve_artists(&self, track: &mut Track) -> Result<Vec<(i64, &'static str)>> {
+        let names = if self
+            .settings
+            .artist_aliases
+            .contains_key(&artist_key(&track.artist_name))
+        {
+            vec![(track.artist_name.as_str(), ARTIST_ROLE_MAIN)]
+        } else {
+            split_artist_credit(&track.artist_name, |name| self.is_known_artist(name))?
+        };
+
+        let title_names = title_featured_artists(&track.title)
+            .into_iter()
+            .map(|n| (n, ARTIST_ROLE_FEATURED));
+
+        let mut credits: Vec<(i64, &'static str)> = Vec::new();
+
+        for (name, role) in names.into_iter().chain(title_names) {
+            let credit = (self.resolve_artist(name)?.artist_id, role);
+
+            if !credits.contains(&credit) {
+                credits.push(credit);
+            }
+        }
+
+        track.artist_id = match credits.iter().find(|c| c.1 == ARTIST_ROLE_MAIN) {
+            Some(c) => c.0,
+            None => {
+                let artist_id = self.resolve_artist(&track.artist_name)?.artist_id;
+                credits.insert(0, (artist_id, ARTIST_ROLE_MAIN));
+                artist_id
+            }
+        };
+
+        track.album_artist_id = match &track.album_artist_name {
+            Some(n) => Some(self.resolve_artist(n)?.artist_id),
+            None => None,
+        };
+
+        Ok(credits)
+    }
+
+    fn link_artists(&self, track: &Track, credits: &[(i64, &str)]) -> Result<()> {
+        for (artist_id, role) in credits {
+            self.index
+                .link_track_artist(track.track_id, *artist_id, role)?;
+        }
+
+        Ok(())
+    }
+
+    /// Finds or creates the album of a track in `node`. Tracks in disc directories such as
+    /// `CD2` without a disc number tag get the number from the directory name.
+    fn resolve_album(&self, node: &Node, track: &mut Track) -> Result<()> {
+        let disc_directory = node
+            .path
+            .parent()
+            .and_then(|p| p.file_name())
+            .and_then(|n| disc_directory_number(Path::new(n)));
+
+        if track.disc_number.is_none() {
+            track.disc_number = disc_directory;
+        }
+
+        let multi_disc = disc_directory.is_some()
+            || track.disc_number.unwrap_or(1) > 1
+            || track.disc_total.unwrap_or(1) > 1;
+
+        track.album_id = match self.index.find_album(track, multi_disc)? {
+            Some(a) => a,
+            None => self.index.create_album(&track.album_name)?,
+        }
+        .album_id;
+
+        Ok(())
+    }
+
+    /// Links the track to each genre in its genre tag, creating the genres as needed.
+    fn link_genres(&self, track: &Track) -> Result<()> {
+        let genre = match &track.genre {
+            Some(g) => g,
+            None => return Ok(()),
+        };
+
+        for name in split_genres(genre) {
+            let genre_id = match self.index.genre_by_name(name)? {
+                Some(g) => g,
+                None => self.index.create_genre(name)?,
+            }
+            .genre_id;
+
+            self.index.link_track_genre(track.track_id, genre_id)?;
+        }
+
+        Ok(())
+    }
+
     // This list is what extensions image crate recognizes
     const IMAGE_EXTENSIONS: &'static [&'static str] = &[
         "jpg", "jpeg", "png", "gif", "webp", "tif", "tiff", "tga", "bmp", "ico", "hdr", "pbm",
@@ -633,11 +1228,7 @@ impl Scan {
         let dimensions = match image::image_dimensions(fs_path) {
             Ok(i) => i,
             Err(e) => {
-                error!(
-                    "can't open image file '{}': {}",
-                    fs_path.to_string_lossy(),
-                    e.description()
-                );
+                self.report_error(fs_path, &format!("can't open image file: {}", e));
                 return Ok(None);
             }
         };
@@ -660,7 +1251,7 @@ impl Scan {
         })?;
 
         Ok(Some(ScanStat {
-            images: 1,
+            images_added: 1,
             ..Default::default()
         }))
     }
@@ -680,31 +1271,14 @@ impl Scan {
         for track in tracks.iter_mut() {
             track.node_id = node.node_id;
 
-            track.artist_id = match self.index.artist_by_name(&track.artist_name)? {
-                Some(a) => a,
-                None => self.index.create_artist(&track.artist_name)?,
-            }
-            .artist_id;
+            let credits = self.resolve_artists(track)?;
+            self.resolve_album(node, track)?;
 
-            track.album_id = match self.index.find_album(node.node_id, &track.album_name)? {
-                Some(a) => a,
-                None => self.index.create_album(&track.album_name)?,
-            }
-            .album_id;
+            let track = self.index.create_track(track)?;
+            self.link_artists(&track, &credits)?;
+            self.link_genres(&track)?;
 
-            if let Some(album_artist_name) = &track.album_artist_name {
-                track.album_artist_id = Some(
-                    match self.index.artist_by_name(&album_artist_name)? {
-                        Some(a) => a,
-                        None => self.index.create_artist(&album_artist_name)?,
-                    }
-                    .artist_id,
-                );
-            }
-
-            self.index.create_track(track)?;
-
-            stat.tracks += 1;
+            stat.tracks_added += 1;
         }
 
         for image in images.iter_mut() {
@@ -712,9 +1286,49 @@ impl Scan {
 
             self.index.create_image(image)?;
 
-            stat.images += 1;
+            stat.images_added += 1;
         }
 
         Ok(Some(stat))
     }
 }
+
+#[test]
+fn test_split_genres() {
+    assert_eq!(split_genres("Rock"), vec!["Rock"]);
+    assert_eq!(
+        split_genres("Rock; Pop/Jazz ;"),
+        vec!["Rock", "Pop", "Jazz"]
+    );
+    assert!(split_genres(" ").is_empty());
+}
+
+#[test]
+fn test_split_artist_credit() {
+    let known = |name: &str| Ok(["A", "B", "Simon"].contains(&name));
+    let split = |credit| split_artist_credit(credit, known).unwrap();
+
+    assert_eq!(split("A"), vec![("A", "main")]);
+    assert_eq!(
+        split("A & B feat. C, D and E"),
+        vec![
+            ("A", "main"),
+            ("B", "main"),
+            ("C", "featured"),
+            ("D", "featured"),
+            ("E", "featured")
+        ]
+    );
+    assert_eq!(split("A (Feat. B)"), vec![("A", "main"), ("B", "featured")]);
+    assert_eq!(split("Lil Nas X"), vec![("Lil Nas X", "main")]);
+    assert_eq!(
+        split("Simon & Garfunkel"),
+        vec![("Simon & Garfunkel", "main")]
+    );
+    assert_eq!(
+        split("Earth, Wind & Fire feat. A"),
+        vec![("Earth, Wind & Fire", "main"), ("A", "featured")]
+    );
+    assert_eq!(title_featured_artists("Song (ft. A & B)"), vec!["A", "B"]);
+    assert!(title_featured_artists("Song").is_empty());
+}
@@ -1,4 +1,12 @@
-pub const SCHEMA_VERSION: u32 = 1;
+pub const SCHEMA_VERSION: u32 = 20;
+
+/// Upgrades a database from the previous version to `version`. All databases share
+/// `SCHEMA_VERSION`, versions without changes to a database have no step for it.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
 
 pub const META_SCHEMA: &str = "
 CREATE TABLE IF NOT EXISTS Musicd (
@@ -44,6 +52,22 @@ CREATE TABLE Track (
     album_artist_id INTEGER,
     album_artist_name TEXT,
     length REAL NOT NULL,
+    date TEXT,
+    year INTEGER,
+    original_date TEXT,
+    genre TEXT,
+    disc_number INTEGER,
+    disc_total INTEGER,
+    track_total INTEGER,
+    composer TEXT,
+    performer TEXT,
+    label TEXT,
+    catalog_number TEXT,
+    comment TEXT,
+    musicbrainz_track_id TEXT,
+    musicbrainz_release_id TEXT,
+    musicbrainz_artist_id TEXT,
+    compilation INTEGER NOT NULL DEFAULT 0,
     FOREIGN KEY(node_id) REFERENCES Node(node_id) ON DELETE CASCADE,
     FOREIGN KEY(artist_id) REFERENCES Artist(artist_id),
     FOREIGN KEY(album_id) REFERENCES Album(album_id),
@@ -53,6 +77,7 @@ CREATE INDEX Track_node_id ON Track (node_id);
 CREATE INDEX Track_artist_id ON Track (artist_id);
 CREATE INDEX Track_album_id ON Track (album_id);
 CREATE INDEX Track_album_artist_id ON Track (album_artist_id);
+CREATE INDEX Track_year ON Track (year);
     
 CREATE TABLE Image (
     image_id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -67,7 +92,20 @@ CREATE INDEX Image_node_id ON Image (node_id);
     
 CREATE TABLE Artist (
     artist_id INTEGER PRIMARY KEY AUTOINCREMENT,
-    name TEXT NOT NULL);
+    name TEXT NOT NULL,
+    key TEXT NOT NULL DEFAULT '');
+
+CREATE INDEX Artist_key ON Artist (key);
+
+CREATE TABLE TrackArtist (
+    track_id INTEGER NOT NULL,
+    artist_id INTEGER NOT NULL,
+    role TEXT NOT NULL,
+    PRIMARY KEY(track_id, artist_id, role),
+    FOREIGN KEY(track_id) REFERENCES Track(track_id) ON DELETE CASCADE,
+    FOREIGN KEY(artist_id) REFERENCES Artist(artist_id) ON DELETE CASCADE);
+
+CREATE INDEX TrackArtist_artist_id ON TrackArtist (artist_id);
     
 CREATE TABLE Album (
     album_id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -75,6 +113,7 @@ CREATE TABLE Album (
     artist_id INTEGER,
     artist_name TEXT,
     image_id INTEGER,
+    compilation INTEGER NOT NULL DEFAULT 0,
     FOREIGN KEY(image_id) REFERENCES Image(image_id) ON DELETE SET NULL);
 
 CREATE INDEX Album_artist_id ON Album (album_id);
@@ -89,9 +128,73 @@ CREATE TABLE AlbumImage (
 CREATE TABLE AlbumImagePattern (
     pattern TEXT);
 
+CREATE TABLE ArtistImage (
+    artist_id INTEGER NOT NULL,
+    image_id INTEGER NOT NULL,
+    PRIMARY KEY(artist_id, image_id),
+    FOREIGN KEY(artist_id) REFERENCES Artist(artist_id) ON DELETE CASCADE,
+    FOREIGN KEY(image_id) REFERENCES Image(image_id) ON DELETE CASCADE);
+
+CREATE TABLE Genre (
+    genre_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL);
+
+CREATE TABLE TrackGenre (
+    track_id INTEGER NOT NULL,
+    genre_id INTEGER NOT NULL,
+    PRIMARY KEY(track_id, genre_id),
+    FOREIGN KEY(track_id) REFERENCES Track(track_id) ON DELETE CASCADE,
+    FOREIGN KEY(genre_id) REFERENCES Genre(genre_id) ON DELETE CASCADE);
+
+CREATE INDEX TrackGenre_genre_id ON TrackGenre (genre_id);
+
+CREATE VIRTUAL TABLE TrackSearch USING fts5(
+    title,
+    artist_name,
+    album_name,
+    tokenize = 'unicode61 remove_diacritics 2');
+
+CREATE VIRTUAL TABLE AlbumSearch USING fts5(
+    name,
+    artist_name,
+    tokenize = 'unicode61 remove_diacritics 2');
+
+CREATE VIRTUAL TABLE ArtistSearch USING fts5(
+    name,
+    tokenize = 'unicode61 remove_diacritics 2');
+
+CREATE TRIGGER Track_delete_search AFTER DELETE ON Track BEGIN
+    DELETE FROM TrackSearch WHERE rowid = old.track_id;
+END;
+
+CREATE TRIGGER Album_delete_search AFTER DELETE ON Album BEGIN
+    DELETE FROM AlbumSearch WHERE rowid = old.album_id;
+END;
+
+CREATE TRIGGER Artist_delete_search AFTER DELETE ON Artist BEGIN
+    DELETE FROM ArtistSearch WHERE rowid = old.artist_id;
+END;
+
+CREATE TABLE Scan (
+    scan_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    full INTEGER NOT NULL,
+    started INTEGER NOT NULL,
+    finished INTEGER NOT NULL,
+    interrupted INTEGER NOT NULL,
+    directories INTEGER NOT NULL,
+    files INTEGER NOT NULL,
+    tracks_added INTEGER NOT NULL,
+    tracks_updated INTEGER NOT NULL,
+    tracks_removed INTEGER NOT NULL,
+    images_added INTEGER NOT NULL,
+    images_updated INTEGER NOT NULL,
+    images_removed INTEGER NOT NULL,
+    errors INTEGER NOT NULL);
+
 CREATE TABLE TrackLyrics (
     track_id INTEGER PRIMARY KEY,
     lyrics TEXT,
+    synced TEXT,
     provider TEXT,
     source TEXT,
     modified INTEGER NOT NULL,
@@ -106,13 +209,38 @@ CREATE TABLE StoreTrack (
     
 CREATE TABLE StoreList (
     list_id INTEGER PRIMARY KEY,
-    name TEXT NOT NULL);
+    name TEXT NOT NULL,
+    user_id INTEGER);
 
 CREATE TABLE StoreListTrack (
     list_id INTEGER NOT NULL,
     store_track_id INTEGER NOT NULL,
+    sort_index INTEGER,
     FOREIGN KEY(list_id) REFERENCES StoreList(list_id) ON DELETE CASCADE,
     FOREIGN KEY(store_track_id) REFERENCES StoreTrack(store_track_id) ON DELETE CASCADE);
+
+CREATE TABLE StoreHistory (
+    history_id INTEGER PRIMARY KEY,
+    store_track_id INTEGER NOT NULL,
+    played INTEGER NOT NULL,
+    user_id INTEGER,
+    FOREIGN KEY(store_track_id) REFERENCES StoreTrack(store_track_id) ON DELETE CASCADE);
+
+CREATE INDEX StoreHistory_store_track_id ON StoreHistory (store_track_id);
+CREATE INDEX StoreHistory_played ON StoreHistory (played);
+
+CREATE TABLE StoreRating (
+    user_id INTEGER NOT NULL,
+    store_track_id INTEGER NOT NULL,
+    rating INTEGER NOT NULL,
+    PRIMARY KEY(user_id, store_track_id),
+    FOREIGN KEY(store_track_id) REFERENCES StoreTrack(store_track_id) ON DELETE CASCADE);
+
+CREATE TABLE StoreAlbumCover (
+    album_id INTEGER PRIMARY KEY,
+    image_id INTEGER NOT NULL,
+    FOREIGN KEY(album_id) REFERENCES Album(album_id) ON DELETE CASCADE,
+    FOREIGN KEY(image_id) REFERENCES Image(image_id) ON DELETE CASCADE);
 ";
 
 pub const STORE_SCHEMA: &str = "
@@ -125,9 +253,35 @@ CREATE TABLE Track (
     play_count INTEGER,
     last_play INTEGER);
 
+CREATE TABLE User (
+    user_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    password TEXT NOT NULL,
+    role TEXT NOT NULL,
+    subsonic_password TEXT);
+
+CREATE TABLE UserRoot (
+    user_id INTEGER NOT NULL,
+    root_name TEXT NOT NULL,
+    PRIMARY KEY(user_id, root_name),
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE);
+
+CREATE TABLE Session (
+    session_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    api_key INTEGER NOT NULL,
+    name TEXT,
+    created INTEGER NOT NULL,
+    expires INTEGER,
+    last_used INTEGER,
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE);
+
 CREATE TABLE List (
     list_id INTEGER PRIMARY KEY AUTOINCREMENT,
-    name TEXT NOT NULL);
+    name TEXT NOT NULL,
+    user_id INTEGER,
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE);
 
 CREATE TABLE ListTrack (
     list_id INTEGER NOT NULL,
@@ -135,4 +289,406 @@ CREATE TABLE ListTrack (
     sort_index INTEGER,
     FOREIGN KEY(list_id) REFERENCES List(list_id) ON DELETE CASCADE,
     FOREIGN KEY(store_track_id) REFERENCES Track(store_track_id) ON DELETE CASCADE);
+
+CREATE TABLE History (
+    history_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    store_track_id INTEGER NOT NULL,
+    played INTEGER NOT NULL,
+    user_id INTEGER,
+    FOREIGN KEY(store_track_id) REFERENCES Track(store_track_id) ON DELETE CASCADE,
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE);
+
+CREATE INDEX History_played ON History (played);
+
+CREATE TABLE Rating (
+    user_id INTEGER NOT NULL,
+    store_track_id INTEGER NOT NULL,
+    rating INTEGER NOT NULL,
+    PRIMARY KEY(user_id, store_track_id),
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE,
+    FOREIGN KEY(store_track_id) REFERENCES Track(store_track_id) ON DELETE CASCADE);
+
+CREATE TABLE Setting (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL);
+
+CREATE TABLE AlbumCover (
+    album_name TEXT NOT NULL,
+    artist_name TEXT,
+    image_path TEXT NOT NULL,
+    stream_index INTEGER);
+
+CREATE TABLE Share (
+    share_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    store_track_id INTEGER,
+    album_name TEXT,
+    artist_name TEXT,
+    list_id INTEGER,
+    password TEXT,
+    created INTEGER NOT NULL,
+    expires INTEGER,
+    max_plays INTEGER,
+    play_count INTEGER NOT NULL DEFAULT 0,
+    album_store_track_id INTEGER,
+    recipient_id INTEGER,
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE,
+    FOREIGN KEY(store_track_id) REFERENCES Track(store_track_id) ON DELETE CASCADE,
+    FOREIGN KEY(list_id) REFERENCES List(list_id) ON DELETE CASCADE,
+    FOREIGN KEY(album_store_track_id) REFERENCES Track(store_track_id) ON DELETE CASCADE,
+    FOREIGN KEY(recipient_id) REFERENCES User(user_id) ON DELETE SET NULL);
+
+CREATE INDEX Share_recipient_id ON Share (recipient_id);
 ";
+
+pub const CACHE_MIGRATIONS: &[Migration] = &[];
+
+pub const INDEX_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2,
+        description: "add scan history",
+        sql: "
+CREATE TABLE Scan (
+    scan_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    full INTEGER NOT NULL,
+    started INTEGER NOT NULL,
+    finished INTEGER NOT NULL,
+    interrupted INTEGER NOT NULL,
+    directories INTEGER NOT NULL,
+    files INTEGER NOT NULL,
+    tracks_added INTEGER NOT NULL,
+    tracks_updated INTEGER NOT NULL,
+    tracks_removed INTEGER NOT NULL,
+    images_added INTEGER NOT NULL,
+    images_updated INTEGER NOT NULL,
+    images_removed INTEGER NOT NULL,
+    errors INTEGER NOT NULL);
+",
+    },
+    Migration {
+        version: 3,
+        description: "add full-text search tables",
+        sql: "
+CREATE VIRTUAL TABLE TrackSearch USING fts5(
+    title,
+    artist_name,
+    album_name,
+    tokenize = 'unicode61 remove_diacritics 2');
+
+CREATE VIRTUAL TABLE AlbumSearch USING fts5(
+    name,
+    artist_name,
+    tokenize = 'unicode61 remove_diacritics 2');
+
+CREATE VIRTUAL TABLE ArtistSearch USING fts5(
+    name,
+    tokenize = 'unicode61 remove_diacritics 2');
+
+CREATE TRIGGER Track_delete_search AFTER DELETE ON Track BEGIN
+    DELETE FROM TrackSearch WHERE rowid = old.track_id;
+END;
+
+CREATE TRIGGER Album_delete_search AFTER DELETE ON Album BEGIN
+    DELETE FROM AlbumSearch WHERE rowid = old.album_id;
+END;
+
+CREATE TRIGGER Artist_delete_search AFTER DELETE ON Artist BEGIN
+    DELETE FROM ArtistSearch WHERE rowid = old.artist_id;
+END;
+
+INSERT INTO TrackSearch (rowid, title, artist_name, album_name)
+SELECT track_id, title, artist_name, album_name FROM Track;
+
+INSERT INTO AlbumSearch (rowid, name, artist_name)
+SELECT album_id, name, artist_name FROM Album;
+
+INSERT INTO ArtistSearch (rowid, name)
+SELECT artist_id, name FROM Artist;
+",
+    },
+    Migration {
+        version: 4,
+        description: "add playlist track order",
+        sql: "
+ALTER TABLE StoreListTrack ADD COLUMN sort_index INTEGER;
+",
+    },
+    Migration {
+        version: 5,
+        description: "add play history",
+        sql: "
+CREATE TABLE StoreHistory (
+    history_id INTEGER PRIMARY KEY,
+    store_track_id INTEGER NOT NULL,
+    played INTEGER NOT NULL,
+    FOREIGN KEY(store_track_id) REFERENCES StoreTrack(store_track_id) ON DELETE CASCADE);
+
+CREATE INDEX StoreHistory_store_track_id ON StoreHistory (store_track_id);
+CREATE INDEX StoreHistory_played ON StoreHistory (played);
+",
+    },
+    Migration {
+        version: 6,
+        description: "add synchronized lyrics",
+        sql: "
+ALTER TABLE TrackLyrics ADD COLUMN synced TEXT;
+",
+    },
+    Migration {
+        version: 7,
+        description: "add extended tags, rescanning everything",
+        sql: "
+ALTER TABLE Track ADD COLUMN date TEXT;
+ALTER TABLE Track ADD COLUMN year INTEGER;
+ALTER TABLE Track ADD COLUMN original_date TEXT;
+ALTER TABLE Track ADD COLUMN genre TEXT;
+ALTER TABLE Track ADD COLUMN disc_number INTEGER;
+ALTER TABLE Track ADD COLUMN disc_total INTEGER;
+ALTER TABLE Track ADD COLUMN track_total INTEGER;
+ALTER TABLE Track ADD COLUMN composer TEXT;
+ALTER TABLE Track ADD COLUMN performer TEXT;
+ALTER TABLE Track ADD COLUMN label TEXT;
+ALTER TABLE Track ADD COLUMN catalog_number TEXT;
+ALTER TABLE Track ADD COLUMN comment TEXT;
+ALTER TABLE Track ADD COLUMN musicbrainz_track_id TEXT;
+ALTER TABLE Track ADD COLUMN musicbrainz_release_id TEXT;
+ALTER TABLE Track ADD COLUMN musicbrainz_artist_id TEXT;
+
+CREATE INDEX Track_year ON Track (year);
+
+UPDATE Node SET modified = 0;
+",
+    },
+    Migration {
+        version: 8,
+        description: "add genres, rescanning everything",
+        sql: "
+CREATE TABLE Genre (
+    genre_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL);
+
+CREATE TABLE TrackGenre (
+    track_id INTEGER NOT NULL,
+    genre_id INTEGER NOT NULL,
+    PRIMARY KEY(track_id, genre_id),
+    FOREIGN KEY(track_id) REFERENCES Track(track_id) ON DELETE CASCADE,
+    FOREIGN KEY(genre_id) REFERENCES Genre(genre_id) ON DELETE CASCADE);
+
+CREATE INDEX TrackGenre_genre_id ON TrackGenre (genre_id);
+
+UPDATE Node SET modified = 0;
+",
+    },
+    Migration {
+        version: 9,
+        description: "merge multi-disc albums, rescanning everything",
+        sql: "
+UPDATE Node SET modified = 0;
+",
+    },
+    Migration {
+        version: 10,
+        description: "add compilations, rescanning everything",
+        sql: "
+ALTER TABLE Track ADD COLUMN compilation INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE Album ADD COLUMN compilation INTEGER NOT NULL DEFAULT 0;
+
+UPDATE Node SET modified = 0;
+",
+    },
+    Migration {
+        version: 11,
+        description: "add artist normalization and credits, rescanning everything",
+        sql: "
+ALTER TABLE Artist ADD COLUMN key TEXT NOT NULL DEFAULT '';
+
+UPDATE Artist SET key =
+    CASE
+        WHEN lower(trim(name)) LIKE 'the %' THEN trim(substr(lower(trim(name)), 5))
+        WHEN lower(trim(name)) LIKE '%, the' THEN substr(lower(trim(name)), 1, length(trim(name)) - 5)
+        ELSE lower(trim(name))
+    END;
+
+CREATE INDEX Artist_key ON Artist (key);
+
+CREATE TABLE TrackArtist (
+    track_id INTEGER NOT NULL,
+    artist_id INTEGER NOT NULL,
+    role TEXT NOT NULL,
+    PRIMARY KEY(track_id, artist_id, role),
+    FOREIGN KEY(track_id) REFERENCES Track(track_id) ON DELETE CASCADE,
+    FOREIGN KEY(artist_id) REFERENCES Artist(artist_id) ON DELETE CASCADE);
+
+CREATE INDEX TrackArtist_artist_id ON TrackArtist (artist_id);
+
+UPDATE Node SET modified = 0;
+",
+    },
+    Migration {
+        version: 12,
+        description: "add artist images, rescanning everything",
+        sql: "
+CREATE TABLE ArtistImage (
+    artist_id INTEGER NOT NULL,
+    image_id INTEGER NOT NULL,
+    PRIMARY KEY(artist_id, image_id),
+    FOREIGN KEY(artist_id) REFERENCES Artist(artist_id) ON DELETE CASCADE,
+    FOREIGN KEY(image_id) REFERENCES Image(image_id) ON DELETE CASCADE);
+
+UPDATE Node SET modified = 0;
+",
+    },
+    Migration {
+        version: 13,
+        description: "add album cover overrides",
+        sql: "
+CREATE TABLE StoreAlbumCover (
+    album_id INTEGER PRIMARY KEY,
+    image_id INTEGER NOT NULL,
+    FOREIGN KEY(album_id) REFERENCES Album(album_id) ON DELETE CASCADE,
+    FOREIGN KEY(image_id) REFERENCES Image(image_id) ON DELETE CASCADE);
+",
+    },
+    Migration {
+        version: 14,
+        description: "add users",
+        sql: "
+ALTER TABLE StoreList ADD COLUMN user_id INTEGER;
+ALTER TABLE StoreHistory ADD COLUMN user_id INTEGER;
+
+CREATE TABLE StoreRating (
+    user_id INTEGER NOT NULL,
+    store_track_id INTEGER NOT NULL,
+    rating INTEGER NOT NULL,
+    PRIMARY KEY(user_id, store_track_id),
+    FOREIGN KEY(store_track_id) REFERENCES StoreTrack(store_track_id) ON DELETE CASCADE);
+",
+    },
+];
+
+pub const STORE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 5,
+        description: "add play history",
+        sql: "
+CREATE TABLE History (
+    history_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    store_track_id INTEGER NOT NULL,
+    played INTEGER NOT NULL,
+    FOREIGN KEY(store_track_id) REFERENCES Track(store_track_id) ON DELETE CASCADE);
+
+CREATE INDEX History_played ON History (played);
+",
+    },
+    Migration {
+        version: 13,
+        description: "add settings and album cover overrides",
+        sql: "
+CREATE TABLE Setting (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL);
+
+CREATE TABLE AlbumCover (
+    album_name TEXT NOT NULL,
+    artist_name TEXT,
+    image_path TEXT NOT NULL,
+    stream_index INTEGER);
+",
+    },
+    Migration {
+        version: 14,
+        description: "add users, existing playlists and plays go to the first user",
+        sql: "
+CREATE TABLE User (
+    user_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    password TEXT NOT NULL,
+    role TEXT NOT NULL);
+
+CREATE TABLE UserRoot (
+    user_id INTEGER NOT NULL,
+    root_name TEXT NOT NULL,
+    PRIMARY KEY(user_id, root_name),
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE);
+
+ALTER TABLE List ADD COLUMN user_id INTEGER REFERENCES User(user_id) ON DELETE CASCADE;
+ALTER TABLE History ADD COLUMN user_id INTEGER REFERENCES User(user_id) ON DELETE CASCADE;
+
+CREATE TABLE Rating (
+    user_id INTEGER NOT NULL,
+    store_track_id INTEGER NOT NULL,
+    rating INTEGER NOT NULL,
+    PRIMARY KEY(user_id, store_track_id),
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE,
+    FOREIGN KEY(store_track_id) REFERENCES Track(store_track_id) ON DELETE CASCADE);
+",
+    },
+    Migration {
+        version: 15,
+        description: "add login sessions and API keys",
+        sql: "
+CREATE TABLE Session (
+    session_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    api_key INTEGER NOT NULL,
+    name TEXT,
+    created INTEGER NOT NULL,
+    expires INTEGER,
+    last_used INTEGER,
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE);
+",
+    },
+    Migration {
+        version: 16,
+        description: "add shares",
+        sql: "
+CREATE TABLE Share (
+    share_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    store_track_id INTEGER,
+    album_name TEXT,
+    artist_name TEXT,
+    list_id INTEGER,
+    password TEXT,
+    created INTEGER NOT NULL,
+    expires INTEGER,
+    max_plays INTEGER,
+    play_count INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE,
+    FOREIGN KEY(store_track_id) REFERENCES Track(store_track_id) ON DELETE CASCADE,
+    FOREIGN KEY(list_id) REFERENCES List(list_id) ON DELETE CASCADE);
+",
+    },
+    Migration {
+        version: 17,
+        description: "add Subsonic passwords",
+        sql: "
+ALTER TABLE User ADD COLUMN subsonic_password TEXT;
+",
+    },
+    Migration {
+        version: 18,
+        description: "refer to shared albums by one of their tracks",
+        sql: "
+ALTER TABLE Share ADD COLUMN album_store_track_id INTEGER
+    REFERENCES Track(store_track_id) ON DELETE CASCADE;
+",
+    },
+    Migration {
+        version: 19,
+        description: "add share recipients",
+        sql: "
+ALTER TABLE Share ADD COLUMN recipient_id INTEGER REFERENCES User(user_id) ON DELETE SET NULL;
+
+CREATE INDEX Share_recipient_id ON Share (recipient_id);
+",
+    },
+    Migration {
+        version: 20,
+        description: "hash session tokens with SHA-256, ending sessions and revoking API keys",
+        sql: "
+DELETE FROM Session;
+",
+    },
+];
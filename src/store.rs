@@ -1,10 +1,18 @@
 use std::error::Error as StdError;
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 
-use rusqlite::{params, Connection, Result, NO_PARAMS};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rusqlite::{params, Connection, OptionalExtension, Result, NO_PARAMS};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 
 use crate::db_meta;
-use crate::index::Index;
+use crate::http_util::{decode_hex, encode_hex};
+use crate::index::{Album, Image, Index, Track};
 use crate::schema;
 
 #[derive(Debug, Clone)]
@@ -18,6 +26,171 @@ struct StoreTrack {
     last_play: Option<i64>,
 }
 
+#[derive(Debug, Clone)]
+pub struct List {
+    pub list_id: i64,
+    pub name: String,
+    /// Owner, None only for lists from before users were added
+    pub user_id: Option<i64>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Can do everything, including scanning, settings and managing users
+    Admin,
+    /// Can browse and play the library and keep playlists, plays and ratings
+    Listener,
+    /// Can only open the shares addressed to them
+    ShareOnly,
+}
+
+impl Role {
+    pub fn from_str(s: &str) -> Option<Role> {
+        match s {
+            "admin" => Some(Role::Admin),
+            "listener" => Some(Role::Listener),
+            "share_only" => Some(Role::ShareOnly),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Listener => "listener",
+            Role::ShareOnly => "share_only",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct User {
+    pub user_id: i64,
+    pub name: String,
+    pub role: Role,
+    /// Names of the roots the user can access, all if empty
+    pub roots: Vec<String>,
+}
+
+/// Login session or API key of a user
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    pub session_id: i64,
+    pub user_id: i64,
+    pub api_key: bool,
+    /// Name of an API key, or the user agent a session was created with
+    pub name: Option<String>,
+    pub created: i64,
+    pub expires: Option<i64>,
+    pub last_used: Option<i64>,
+}
+
+/// What a share gives access to
+pub enum SharedItem<'a> {
+    Track(&'a Track),
+    Album(&'a Album),
+    List(i64),
+}
+
+/// Who a share is for and how long it lasts
+pub struct ShareOptions<'a> {
+    /// User the share is addressed to
+    pub recipient_id: Option<i64>,
+    pub password: Option<&'a str>,
+    pub expires: Option<i64>,
+    pub max_plays: Option<i64>,
+}
+
+/// Track, album or playlist shared with people without a user, or with share-only users. Items are referred to by their
+/// index ids, None if the item no longer is in the index.
+#[derive(Debug, Clone, Serialize)]
+pub struct Share {
+    pub share_id: i64,
+    pub user_id: i64,
+    pub track_id: Option<i64>,
+    pub album_id: Option<i64>,
+    pub list_id: Option<i64>,
+    /// User the share is addressed to, who can find it in their received shares
+    pub recipient_id: Option<i64>,
+    pub has_password: bool,
+    pub created: i64,
+    pub expires: Option<i64>,
+    pub max_plays: Option<i64>,
+    pub play_count: i64,
+    #[serde(skip)]
+    password: Option<String>,
+}
+
+/// Name of the user created on first start, who takes over playlists and plays from before
+/// users were added
+pub const DEFAULT_USER_NAME: &str = "admin";
+
+fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &SaltString::encode_b64(&salt).unwrap())
+        .unwrap()
+        .to_string()
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(e) => {
+            error!("invalid password hash: {}", e);
+            false
+        }
+    }
+}
+
+/// Only hashes of session tokens are stored, so the database doesn't contain usable
+/// credentials. Tokens are random, so a fast hash is enough.
+fn hash_token(token: &str) -> String {
+    encode_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    encode_hex(&bytes)
+}
+
+fn sign(secret: &[u8], message: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+    mac.update(message.as_bytes());
+    mac
+}
+
+/// Message signed for the token of a share. Tokens of password protected shares only grant
+/// access once unlocked, which gives a token signed together with the password hash, so that
+/// changing the password invalidates it.
+fn share_message(share: &Share, unlocked: bool) -> String {
+    match (&share.password, unlocked) {
+        (Some(password), true) => format!("share:{}:{}", share.share_id, password),
+        _ => format!("share:{}", share.share_id),
+    }
+}
+
+/// Message signed for the token of a play of a shared track, which is only given once the
+/// share is unlocked.
+fn share_play_message(share: &Share, track_id: i64, expires: i64) -> String {
+    format!(
+        "play:{}:{}:{}",
+        share_message(share, true),
+        track_id,
+        expires
+    )
+}
+
+/// Seconds between updates of `Session.last_used`, to not write on every request
+const SESSION_LAST_USED_INTERVAL: i64 = 60;
+
 pub struct StoreSource {
     db_path: PathBuf,
 }
@@ -34,7 +207,12 @@ impl StoreSource {
         let source = StoreSource { db_path };
 
         let mut store = source.get(index)?;
-        if !db_meta::ensure_schema(&mut store.conn, schema::STORE_SCHEMA)? {
+        if !db_meta::ensure_schema(
+            &mut store.conn,
+            &source.db_path,
+            schema::STORE_SCHEMA,
+            schema::STORE_MIGRATIONS,
+        )? {
             return Ok(None);
         }
 
@@ -71,9 +249,12 @@ impl Store {
         let index_conn = self.index.connection();
 
         index_conn.execute_batch(
-            "DELETE FROM StoreListTrack;
+            "DELETE FROM StoreHistory;
+            DELETE FROM StoreRating;
+            DELETE FROM StoreListTrack;
             DELETE FROM StoreList;
-            DELETE FROM StoreTrack;",
+            DELETE FROM StoreTrack;
+            DELETE FROM StoreAlbumCover;",
         )?;
 
         let mut st = store_conn.prepare(
@@ -125,49 +306,1453 @@ impl Store {
             ])?;
         }
 
+        let mut st = store_conn.prepare("SELECT list_id FROM List")?;
+
+        let list_ids = st
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<Result<Vec<i64>>>()?;
+
+        for list_id in list_ids {
+            self.synchronize_list(list_id)?;
+        }
+
+        let mut st = store_conn
+            .prepare("SELECT history_id, store_track_id, played, user_id FROM History")?;
+
+        let mut rows = st.query(NO_PARAMS)?;
+
+        let mut st = index_conn.prepare(
+            "INSERT INTO StoreHistory (history_id, store_track_id, played, user_id)
+            SELECT ?, store_track_id, ?, ?
+            FROM StoreTrack
+            WHERE store_track_id = ?",
+        )?;
+
+        while let Some(row) = rows.next()? {
+            let history_id: i64 = row.get(0)?;
+            let store_track_id: i64 = row.get(1)?;
+            let played: i64 = row.get(2)?;
+            let user_id: Option<i64> = row.get(3)?;
+
+            st.execute(params![history_id, played, user_id, store_track_id])?;
+        }
+
+        let mut st = store_conn.prepare("SELECT user_id, store_track_id, rating FROM Rating")?;
+
+        let mut rows = st.query(NO_PARAMS)?;
+
+        let mut st = index_conn.prepare(
+            "INSERT INTO StoreRating (user_id, store_track_id, rating)
+            SELECT ?, store_track_id, ?
+            FROM StoreTrack
+            WHERE store_track_id = ?",
+        )?;
+
+        while let Some(row) = rows.next()? {
+            let user_id: i64 = row.get(0)?;
+            let store_track_id: i64 = row.get(1)?;
+            let rating: i64 = row.get(2)?;
+
+            st.execute(params![user_id, rating, store_track_id])?;
+        }
+
+        let mut st =
+            store_conn.prepare("SELECT DISTINCT album_name, artist_name FROM AlbumCover")?;
+
+        let albums = st
+            .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(String, Option<String>)>>>()?;
+
+        for (album_name, artist_name) in albums {
+            self.synchronize_album_cover(&album_name, artist_name.as_deref())?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the store track for an index track, creating one if the track hasn't been
+    /// stored before.
+    pub fn store_track(&self, track: &Track) -> Result<i64> {
+        trace!("store track track_id={}", track.track_id);
+
+        let index_conn = self.index.connection();
+
+        let store_track_id: Option<i64> = index_conn
+            .query_row(
+                "SELECT store_track_id FROM StoreTrack WHERE track_id = ?",
+                [track.track_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        if let Some(store_track_id) = store_track_id {
+            return Ok(store_track_id);
+        }
+
+        let store_track: Option<(i64, Option<i64>, Option<i64>)> = self
+            .conn
+            .query_row(
+                "SELECT store_track_id, play_count, last_play
+                FROM Track
+                WHERE title = ? AND artist_name = ? AND album_name = ?",
+                params![track.title, track.artist_name, track.album_name],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+
+        let (store_track_id, play_count, last_play) = match store_track {
+            Some(t) => t,
+            None => {
+                self.conn.execute(
+                    "INSERT INTO Track (title, artist_name, album_name, length)
+                    VALUES (?, ?, ?, ?)",
+                    params![
+                        track.title,
+                        track.artist_name,
+                        track.album_name,
+                        track.length as i64
+                    ],
+                )?;
+
+                (self.conn.last_insert_rowid(), None, None)
+            }
+        };
+
+        index_conn.execute(
+            "INSERT OR IGNORE INTO
+                StoreTrack (store_track_id, track_id, play_count, last_play)
+            VALUES (?, ?, ?, ?)",
+            params![store_track_id, track.track_id, play_count, last_play],
+        )?;
+
+        debug!("store track {} = {}", track.track_id, store_track_id);
+
+        Ok(store_track_id)
+    }
+
+    /// Increments the play count of a track and adds the play to the history of a user,
+    /// `played` being a unix timestamp.
+    pub fn register_track_play(&mut self, user_id: i64, track: &Track, played: i64) -> Result<()> {
+        trace!(
+            "register track play user_id={} track_id={} played={}",
+            user_id,
+            track.track_id,
+            played
+        );
+
+        let store_track_id = self.store_track(track)?;
+
+        let tx = self.conn.transaction()?;
+
+        tx.execute(
+            "UPDATE Track
+            SET play_count = coalesce(play_count, 0) + 1, last_play = max(coalesce(last_play, 0), ?)
+            WHERE store_track_id = ?",
+            params![played, store_track_id],
+        )?;
+
+        tx.execute(
+            "INSERT INTO History (store_track_id, played, user_id) VALUES (?, ?, ?)",
+            params![store_track_id, played, user_id],
+        )?;
+
+        let history_id = tx.last_insert_rowid();
+
+        tx.commit()?;
+
+        let index_conn = self.index.connection();
+
+        index_conn.execute(
+            "UPDATE StoreTrack
+            SET play_count = coalesce(play_count, 0) + 1, last_play = max(coalesce(last_play, 0), ?)
+            WHERE store_track_id = ?",
+            params![played, store_track_id],
+        )?;
+
+        index_conn.execute(
+            "INSERT INTO StoreHistory (history_id, store_track_id, played, user_id)
+            VALUES (?, ?, ?, ?)",
+            params![history_id, store_track_id, played, user_id],
+        )?;
+
+        debug!("play {} at {} by {}", track.track_id, played, user_id);
+
         Ok(())
     }
 
-    // pub fn store_track(&mut self, track: &Track) -> Result<StoreTrack> {
-    //     let tx = self.conn.transaction()?;
+    /// Sets the rating of a track by a user, or removes it if None.
+    pub fn set_track_rating(
+        &mut self,
+        user_id: i64,
+        track: &Track,
+        rating: Option<i64>,
+    ) -> Result<()> {
+        trace!(
+            "set track rating user_id={} track_id={} rating={:?}",
+            user_id,
+            track.track_id,
+            rating
+        );
+
+        let store_track_id = self.store_track(track)?;
+
+        let index_conn = self.index.connection();
+
+        match rating {
+            Some(rating) => {
+                self.conn.execute(
+                    "INSERT OR REPLACE INTO Rating (user_id, store_track_id, rating) VALUES (?, ?, ?)",
+                    params![user_id, store_track_id, rating],
+                )?;
 
-    //     tx.commit()?;
+                index_conn.execute(
+                    "INSERT OR REPLACE INTO StoreRating (user_id, store_track_id, rating)
+                    VALUES (?, ?, ?)",
+                    params![user_id, store_track_id, rating],
+                )?;
+            }
+            None => {
+                self.conn.execute(
+                    "DELETE FROM Rating WHERE user_id = ? AND store_track_id = ?",
+                    params![user_id, store_track_id],
+                )?;
 
-    //     Ok(())
-    // }
+                index_conn.execute(
+                    "DELETE FROM StoreRating WHERE user_id = ? AND store_track_id = ?",
+                    params![user_id, store_track_id],
+                )?;
+            }
+        }
 
-    // pub fn register_track_play(&mut self, track: &Track) -> Result<()> {
-    //     let mut store_conn = &mut self.conn;
-    //     let mut index_conn = self.index.connection_mut();
+        Ok(())
+    }
+
+    pub fn list(&self, list_id: i64) -> Result<Option<List>> {
+        trace!("get list list_id={}", list_id);
+
+        self.conn
+            .query_row(
+                "SELECT list_id, name, user_id FROM List WHERE list_id = ?",
+                [list_id],
+                |row| {
+                    Ok(List {
+                        list_id: row.get(0)?,
+                        name: row.get(1)?,
+                        user_id: row.get(2)?,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    pub fn create_list(&mut self, user_id: i64, name: &str) -> Result<List> {
+        self.conn.execute(
+            "INSERT INTO List (name, user_id) VALUES (?, ?)",
+            params![name, user_id],
+        )?;
 
-    //     let mut store_tx = store_conn.transaction()?;
-    //     let mut index_tx = index_conn.transaction()?;
+        let list_id = self.conn.last_insert_rowid();
 
-    //     // let store_track_id: Option<i64> = index_tx
-    //     //     .query_row(
-    //     //         "SELECT store_track_id FROM StoreTrack WHERE track_id = ?",
-    //     //         &[track.track_id],
-    //     //         |row| row.get(0)
-    //     //     )
-    //     //     .optional()?;
+        self.synchronize_list(list_id)?;
 
-    //     // if let Some(track_id) = store_track_id {
-    //     //     store_tx
+        let result = self.list(list_id)?.unwrap();
+
+        debug!("create {:?}", result);
+
+        Ok(result)
+    }
+
+    pub fn rename_list(&mut self, list_id: i64, name: &str) -> Result<()> {
+        trace!("rename list list_id={} name={}", list_id, name);
+
+        self.conn.execute(
+            "UPDATE List SET name = ? WHERE list_id = ?",
+            params![name, list_id],
+        )?;
+
+        self.synchronize_list(list_id)
+    }
+
+    pub fn delete_list(&mut self, list_id: i64) -> Result<()> {
+        trace!("delete list list_id={}", list_id);
+
+        self.conn
+            .execute("DELETE FROM List WHERE list_id = ?", [list_id])?;
+
+        self.synchronize_list(list_id)
+    }
+
+    /// Inserts tracks at `sort_index`, or appends them if it's not given or out of range.
+    pub fn add_list_tracks(
+        &mut self,
+        list_id: i64,
+        tracks: &[Track],
+        sort_index: Option<i64>,
+    ) -> Result<()> {
+        trace!(
+            "add list tracks list_id={} sort_index={:?}",
+            list_id,
+            sort_index
+        );
+
+        let mut store_track_ids: Vec<i64> = Vec::new();
+
+        for track in tracks {
+            store_track_ids.push(self.store_track(track)?);
+        }
+
+        let tx = self.conn.transaction()?;
+
+        let count: i64 = tx.query_row(
+            "SELECT count(*) FROM ListTrack WHERE list_id = ?",
+            [list_id],
+            |row| row.get(0),
+        )?;
+
+        let sort_index = match sort_index {
+            Some(i) if i >= 0 && i < count => i,
+            _ => count,
+        };
+
+        tx.execute(
+            "UPDATE ListTrack
+            SET sort_index = sort_index + ?
+            WHERE list_id = ? AND sort_index >= ?",
+            params![store_track_ids.len() as i64, list_id, sort_index],
+        )?;
+
+        for (i, store_track_id) in store_track_ids.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO ListTrack (list_id, store_track_id, sort_index) VALUES (?, ?, ?)",
+                params![list_id, store_track_id, sort_index + i as i64],
+            )?;
+        }
+
+        tx.commit()?;
+
+        self.synchronize_list(list_id)
+    }
+
+    /// Returns false if there's no track at `sort_index`.
+    pub fn remove_list_track(&mut self, list_id: i64, sort_index: i64) -> Result<bool> {
+        trace!(
+            "remove list track list_id={} sort_index={}",
+            list_id,
+            sort_index
+        );
+
+        let tx = self.conn.transaction()?;
+
+        if tx.execute(
+            "DELETE FROM ListTrack WHERE list_id = ? AND sort_index = ?",
+            params![list_id, sort_index],
+        )? == 0
+        {
+            return Ok(false);
+        }
+
+        tx.execute(
+            "UPDATE ListTrack
+            SET sort_index = sort_index - 1
+            WHERE list_id = ? AND sort_index > ?",
+            params![list_id, sort_index],
+        )?;
+
+        tx.commit()?;
+
+        self.synchronize_list(list_id)?;
+
+        Ok(true)
+    }
+
+    /// Moves the track at `sort_index` to `to_index`, shifting the ones in between. Returns
+    /// false if either index is out of range.
+    pub fn move_list_track(
+        &mut self,
+        list_id: i64,
+        sort_index: i64,
+        to_index: i64,
+    ) -> Result<bool> {
+        trace!(
+            "move list track list_id={} sort_index={} to_index={}",
+            list_id,
+            sort_index,
+            to_index
+        );
+
+        let tx = self.conn.transaction()?;
+
+        let count: i64 = tx.query_row(
+            "SELECT count(*) FROM ListTrack WHERE list_id = ?",
+            [list_id],
+            |row| row.get(0),
+        )?;
+
+        if sort_index < 0 || sort_index >= count || to_index < 0 || to_index >= count {
+            return Ok(false);
+        }
+
+        tx.execute(
+            "UPDATE ListTrack SET sort_index = -1 WHERE list_id = ? AND sort_index = ?",
+            params![list_id, sort_index],
+        )?;
+
+        if sort_index < to_index {
+            tx.execute(
+                "UPDATE ListTrack
+                SET sort_index = sort_index - 1
+                WHERE list_id = ? AND sort_index > ? AND sort_index <= ?",
+                params![list_id, sort_index, to_index],
+            )?;
+        } else {
+            tx.execute(
+                "UPDATE ListTrack
+                SET sort_index = sort_index + 1
+                WHERE list_id = ? AND sort_index >= ? AND sort_index < ?",
+                params![list_id, to_index, sort_index],
+            )?;
+        }
+
+        tx.execute(
+            "UPDATE ListTrack SET sort_index = ? WHERE list_id = ? AND sort_index = -1",
+            params![to_index, list_id],
+        )?;
+
+        tx.commit()?;
+
+        self.synchronize_list(list_id)?;
+
+        Ok(true)
+    }
+
+    fn _get_user(&self, row: &rusqlite::Row) -> Result<User> {
+        let user_id: i64 = row.get(0)?;
+        let role: String = row.get(2)?;
+
+        let mut st = self
+            .conn
+            .prepare("SELECT root_name FROM UserRoot WHERE user_id = ? ORDER BY root_name")?;
+
+        let roots = st
+            .query_map([user_id], |row| row.get(0))?
+            .collect::<Result<Vec<String>>>()?;
+
+        Ok(User {
+            user_id,
+            name: row.get(1)?,
+            role: Role::from_str(&role).unwrap_or(Role::ShareOnly),
+            roots,
+        })
+    }
+
+    pub fn users(&self) -> Result<Vec<User>> {
+        trace!("get users");
+
+        let mut st = self
+            .conn
+            .prepare("SELECT user_id, name, role FROM User ORDER BY name COLLATE NOCASE")?;
+
+        let mut rows = st.query(NO_PARAMS)?;
+
+        let mut users = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            users.push(self._get_user(row)?);
+        }
+
+        Ok(users)
+    }
+
+    pub fn user(&self, user_id: i64) -> Result<Option<User>> {
+        trace!("get user user_id={}", user_id);
+
+        let mut st = self
+            .conn
+            .prepare("SELECT user_id, name, role FROM User WHERE user_id = ?")?;
+
+        let mut rows = st.query([user_id])?;
+
+        if let Some(row) = rows.next()? {
+            Ok(Some(self._get_user(row)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn user_by_name(&self, name: &str) -> Result<Option<User>> {
+        trace!("get user name={}", name);
+
+        let mut st = self
+            .conn
+            .prepare("SELECT user_id, name, role FROM User WHERE name = ?")?;
+
+        let mut rows = st.query(&[name])?;
+
+        if let Some(row) = rows.next()? {
+            Ok(Some(self._get_user(row)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The first admin, used when authentication is disabled.
+    pub fn default_user(&self) -> Result<Option<User>> {
+        let user_id: Option<i64> = self.conn.query_row(
+            "SELECT min(user_id) FROM User WHERE role = ?",
+            &[Role::Admin.as_str()],
+            |row| row.get(0),
+        )?;
+
+        match user_id {
+            Some(user_id) => self.user(user_id),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the user if the password matches.
+    pub fn authenticate(&self, name: &str, password: &str) -> Result<Option<User>> {
+        trace!("authenticate name={}", name);
+
+        let hash: Option<String> = self
+            .conn
+            .query_row("SELECT password FROM User WHERE name = ?", &[name], |row| {
+                row.get(0)
+            })
+            .optional()?;
+
+        match hash {
+            Some(hash) if verify_password(password, &hash) => self.user_by_name(name),
+            _ => {
+                debug!("authentication of '{}' failed", name);
+                Ok(None)
+            }
+        }
+    }
+
+    fn subsonic_password_by_name(&self, name: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT subsonic_password FROM User WHERE name = ?",
+                [name],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    /// Returns the user if `password` is their Subsonic password. Unlike the login password
+    /// it's stored as is, so it can be checked quickly on every request.
+    pub fn authenticate_subsonic_password(
+        &self,
+        name: &str,
+        password: &str,
+    ) -> Result<Option<User>> {
+        trace!("authenticate subsonic password name={}", name);
+
+        match self.subsonic_password_by_name(name)? {
+            Some(p) if p == password => self.user_by_name(name),
+            _ => {
+                debug!("subsonic password authentication of '{}' failed", name);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Returns the user if `token` is the MD5 hash of their Subsonic password followed by
+    /// `salt`, the token authentication of Subsonic clients.
+    pub fn authenticate_subsonic_token(
+        &self,
+        name: &str,
+        token: &str,
+        salt: &str,
+    ) -> Result<Option<User>> {
+        trace!("authenticate subsonic token name={}", name);
+
+        match self.subsonic_password_by_name(name)? {
+            Some(p)
+                if format!("{:x}", md5::compute(format!("{}{}", p, salt)))
+                    .eq_ignore_ascii_case(token) =>
+            {
+                self.user_by_name(name)
+            }
+            _ => {
+                debug!("subsonic token authentication of '{}' failed", name);
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn subsonic_password(&self, user_id: i64) -> Result<Option<String>> {
+        trace!("get subsonic password user_id={}", user_id);
+
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT subsonic_password FROM User WHERE user_id = ?",
+                [user_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    /// Generates a new Subsonic password for a user, replacing the previous one.
+    pub fn reset_subsonic_password(&mut self, user_id: i64) -> Result<String> {
+        trace!("reset subsonic password user_id={}", user_id);
+
+        let mut bytes = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let password = encode_hex(&bytes);
+
+        self.conn.execute(
+            "UPDATE User SET subsonic_password = ? WHERE user_id = ?",
+            params![password, user_id],
+        )?;
+
+        Ok(password)
+    }
+
+    pub fn delete_subsonic_password(&mut self, user_id: i64) -> Result<()> {
+        trace!("delete subsonic password user_id={}", user_id);
+
+        self.conn.execute(
+            "UPDATE User SET subsonic_password = NULL WHERE user_id = ?",
+            [user_id],
+        )?;
+
+        Ok(())
+    }
 
-    //     // }
+    pub fn create_user(&mut self, name: &str, password: &str, role: Role) -> Result<User> {
+        self.conn.execute(
+            "INSERT INTO User (name, password, role) VALUES (?, ?, ?)",
+            params![name, hash_password(password), role.as_str()],
+        )?;
+
+        let result = self.user(self.conn.last_insert_rowid())?.unwrap();
+
+        debug!("create {:?}", result);
+
+        Ok(result)
+    }
+
+    /// Updates the given fields of a user.
+    pub fn update_user(
+        &mut self,
+        user_id: i64,
+        password: Option<&str>,
+        role: Option<Role>,
+    ) -> Result<()> {
+        trace!("update user user_id={} role={:?}", user_id, role);
+
+        if let Some(password) = password {
+            self.conn.execute(
+                "UPDATE User SET password = ? WHERE user_id = ?",
+                params![hash_password(password), user_id],
+            )?;
+
+            self.conn
+                .execute("DELETE FROM Session WHERE user_id = ?", [user_id])?;
+        }
+
+        if let Some(role) = role {
+            self.conn.execute(
+                "UPDATE User SET role = ? WHERE user_id = ?",
+                params![role.as_str(), user_id],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Restricts a user to the named roots, or allows all roots if `roots` is empty.
+    pub fn set_user_roots(&mut self, user_id: i64, roots: &[String]) -> Result<()> {
+        trace!("set user roots user_id={} roots={:?}", user_id, roots);
+
+        let tx = self.conn.transaction()?;
+
+        tx.execute("DELETE FROM UserRoot WHERE user_id = ?", [user_id])?;
+
+        for root in roots {
+            tx.execute(
+                "INSERT OR IGNORE INTO UserRoot (user_id, root_name) VALUES (?, ?)",
+                params![user_id, root],
+            )?;
+        }
+
+        tx.commit()
+    }
+
+    /// Deletes a user with their playlists, plays and ratings.
+    pub fn delete_user(&mut self, user_id: i64) -> Result<()> {
+        trace!("delete user user_id={}", user_id);
+
+        let mut st = self
+            .conn
+            .prepare("SELECT list_id FROM List WHERE user_id = ?")?;
+
+        let list_ids = st
+            .query_map([user_id], |row| row.get(0))?
+            .collect::<Result<Vec<i64>>>()?;
+
+        self.conn
+            .execute("DELETE FROM User WHERE user_id = ?", [user_id])?;
+
+        for list_id in list_ids {
+            self.synchronize_list(list_id)?;
+        }
+
+        let index_conn = self.index.connection();
+
+        index_conn.execute("DELETE FROM StoreHistory WHERE user_id = ?", [user_id])?;
+        index_conn.execute("DELETE FROM StoreRating WHERE user_id = ?", [user_id])?;
+
+        Ok(())
+    }
+
+    /// Creates the default admin user with `password` if there are no users yet, giving it
+    /// the playlists and plays from before users were added.
+    pub fn ensure_default_user(&mut self, password: &str) -> Result<()> {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT count(*) FROM User", NO_PARAMS, |row| row.get(0))?;
+
+        if count > 0 {
+            return Ok(());
+        }
+
+        let user = self.create_user(DEFAULT_USER_NAME, password, Role::Admin)?;
+
+        info!("created user '{}'", user.name);
+
+        self.conn.execute(
+            "UPDATE List SET user_id = ? WHERE user_id IS NULL",
+            [user.user_id],
+        )?;
+        self.conn.execute(
+            "UPDATE History SET user_id = ? WHERE user_id IS NULL",
+            [user.user_id],
+        )?;
+
+        Ok(())
+    }
+
+    fn _get_session(row: &rusqlite::Row) -> Result<Session> {
+        Ok(Session {
+            session_id: row.get(0)?,
+            user_id: row.get(1)?,
+            api_key: row.get(2)?,
+            name: row.get(3)?,
+            created: row.get(4)?,
+            expires: row.get(5)?,
+            last_used: row.get(6)?,
+        })
+    }
+
+    /// Creates a session or API key for a user, returning it with its token. The token can't
+    /// be retrieved later.
+    pub fn create_session(
+        &mut self,
+        user_id: i64,
+        api_key: bool,
+        name: Option<&str>,
+        created: i64,
+        expires: Option<i64>,
+    ) -> Result<(Session, String)> {
+        trace!("create session user_id={} api_key={}", user_id, api_key);
+
+        self.conn
+            .execute("DELETE FROM Session WHERE expires <= ?", [created])?;
+
+        let token = generate_token();
+
+        self.conn.execute(
+            "INSERT INTO Session (user_id, token_hash, api_key, name, created, expires)
+            VALUES (?, ?, ?, ?, ?, ?)",
+            params![user_id, hash_token(&token), api_key, name, created, expires],
+        )?;
+
+        let session = self.session(self.conn.last_insert_rowid())?.unwrap();
+
+        debug!("create {:?}", session);
+
+        Ok((session, token))
+    }
+
+    pub fn session(&self, session_id: i64) -> Result<Option<Session>> {
+        self.conn
+            .query_row(
+                "SELECT session_id, user_id, api_key, name, created, expires, last_used
+                FROM Session
+                WHERE session_id = ?",
+                [session_id],
+                Store::_get_session,
+            )
+            .optional()
+    }
+
+    /// Sessions and API keys of a user that haven't expired at `now`.
+    pub fn sessions(&self, user_id: i64, now: i64) -> Result<Vec<Session>> {
+        trace!("get sessions user_id={}", user_id);
+
+        let mut st = self.conn.prepare(
+            "SELECT session_id, user_id, api_key, name, created, expires, last_used
+            FROM Session
+            WHERE user_id = ? AND (expires IS NULL OR expires > ?)
+            ORDER BY session_id",
+        )?;
+
+        let sessions = st
+            .query_map([user_id, now], Store::_get_session)?
+            .collect::<Result<Vec<Session>>>()?;
+
+        Ok(sessions)
+    }
+
+    /// Returns the session with `token` and its user if it hasn't expired at `now`.
+    pub fn authenticate_session(&self, token: &str, now: i64) -> Result<Option<(Session, User)>> {
+        let session = self
+            .conn
+            .query_row(
+                "SELECT session_id, user_id, api_key, name, created, expires, last_used
+                FROM Session
+                WHERE token_hash = ? AND (expires IS NULL OR expires > ?)",
+                params![hash_token(token), now],
+                Store::_get_session,
+            )
+            .optional()?;
+
+        let session = match session {
+            Some(s) => s,
+            None => {
+                debug!("unknown or expired session token");
+                return Ok(None);
+            }
+        };
+
+        if session.last_used.unwrap_or(0) + SESSION_LAST_USED_INTERVAL <= now {
+            self.conn.execute(
+                "UPDATE Session SET last_used = ? WHERE session_id = ?",
+                [now, session.session_id],
+            )?;
+        }
+
+        Ok(self.user(session.user_id)?.map(|user| (session, user)))
+    }
+
+    pub fn delete_session(&mut self, session_id: i64) -> Result<()> {
+        trace!("delete session session_id={}", session_id);
+
+        self.conn
+            .execute("DELETE FROM Session WHERE session_id = ?", [session_id])?;
+
+        Ok(())
+    }
+
+    fn _get_share(&self, row: &rusqlite::Row) -> Result<Share> {
+        let store_track_id: Option<i64> = row.get(2)?;
+        let album_name: Option<String> = row.get(3)?;
+        let artist_name: Option<String> = row.get(4)?;
+        let list_id: Option<i64> = row.get(5)?;
+        let password: Option<String> = row.get(6)?;
+        let album_store_track_id: Option<i64> = row.get(11)?;
+        let recipient_id: Option<i64> = row.get(12)?;
+
+        let index_conn = self.index.connection();
+
+        let track_id = match store_track_id {
+            Some(id) => index_conn
+                .query_row(
+                    "SELECT track_id FROM StoreTrack WHERE store_track_id = ?",
+                    [id],
+                    |row| row.get(0),
+                )
+                .optional()?,
+            None => None,
+        };
+
+        // Albums are shared through one of their tracks, which keeps apart albums with the
+        // same name and artist. Shares from before that refer to the album by name.
+        let album_id = match (album_store_track_id, album_name) {
+            (Some(id), _) => index_conn
+                .query_row(
+                    "SELECT Track.album_id
+                    FROM StoreTrack
+                    INNER JOIN Track ON Track.track_id = StoreTrack.track_id
+                    WHERE StoreTrack.store_track_id = ?",
+                    [id],
+                    |row| row.get(0),
+                )
+                .optional()?,
+            (None, Some(name)) => index_conn.query_row(
+                "SELECT min(album_id) FROM Album WHERE name = ? AND artist_name IS ?",
+                params![name, artist_name],
+                |row| row.get(0),
+            )?,
+            (None, None) => None,
+        };
+
+        Ok(Share {
+            share_id: row.get(0)?,
+            user_id: row.get(1)?,
+            track_id,
+            album_id,
+            list_id,
+            recipient_id,
+            has_password: password.is_some(),
+            created: row.get(7)?,
+            expires: row.get(8)?,
+            max_plays: row.get(9)?,
+            play_count: row.get(10)?,
+            password,
+        })
+    }
+
+    /// Shares `item` as restricted by `options`.
+    pub fn create_share(
+        &mut self,
+        user_id: i64,
+        item: SharedItem,
+        options: ShareOptions,
+        created: i64,
+    ) -> Result<Share> {
+        let (store_track_id, album_store_track_id, list_id) = match item {
+            SharedItem::Track(track) => (Some(self.store_track(track)?), None, None),
+            // An album without tracks has nothing to share
+            SharedItem::Album(album) => {
+                let track_id: Option<i64> = self
+                    .index
+                    .connection()
+                    .query_row(
+                        "SELECT track_id
+                        FROM Track
+                        WHERE album_id = ?
+                        ORDER BY disc_number, number, title
+                        LIMIT 1",
+                        [album.album_id],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+
+                let track = match track_id {
+                    Some(track_id) => self.index.track(track_id)?,
+                    None => None,
+                };
+
+                match track {
+                    Some(track) => (None, Some(self.store_track(&track)?), None),
+                    None => (None, None, None),
+                }
+            }
+            SharedItem::List(list_id) => (None, None, Some(list_id)),
+        };
+
+        self.conn.execute(
+            "INSERT INTO Share (
+                user_id,
+                store_track_id,
+                album_store_track_id,
+                list_id,
+                recipient_id,
+                password,
+                created,
+                expires,
+                max_plays
+            )
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                user_id,
+                store_track_id,
+                album_store_track_id,
+                list_id,
+                options.recipient_id,
+                options.password.map(hash_password),
+                created,
+                options.expires,
+                options.max_plays
+            ],
+        )?;
+
+        let result = self.share(self.conn.last_insert_rowid())?.unwrap();
+
+        debug!("create {:?}", result);
+
+        Ok(result)
+    }
+
+    pub fn share(&self, share_id: i64) -> Result<Option<Share>> {
+        let mut st = self.conn.prepare(
+            "SELECT
+                share_id, user_id, store_track_id, album_name, artist_name, list_id, password,
+                created, expires, max_plays, play_count, album_store_track_id, recipient_id
+            FROM Share
+            WHERE share_id = ?",
+        )?;
+
+        let mut rows = st.query([share_id])?;
+
+        if let Some(row) = rows.next()? {
+            Ok(Some(self._get_share(row)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn shares(&self, user_id: i64) -> Result<Vec<Share>> {
+        trace!("get shares user_id={}", user_id);
+
+        let mut st = self.conn.prepare(
+            "SELECT
+                share_id, user_id, store_track_id, album_name, artist_name, list_id, password,
+                created, expires, max_plays, play_count, album_store_track_id, recipient_id
+            FROM Share
+            WHERE user_id = ?
+            ORDER BY share_id",
+        )?;
+
+        let mut rows = st.query([user_id])?;
+
+        let mut shares = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            shares.push(self._get_share(row)?);
+        }
+
+        Ok(shares)
+    }
+
+    /// Shares addressed to a user that haven't expired at `now`.
+    pub fn received_shares(&self, user_id: i64, now: i64) -> Result<Vec<Share>> {
+        trace!("get received shares user_id={}", user_id);
+
+        let mut st = self.conn.prepare(
+            "SELECT
+                share_id, user_id, store_track_id, album_name, artist_name, list_id, password,
+                created, expires, max_plays, play_count, album_store_track_id, recipient_id
+            FROM Share
+            WHERE recipient_id = ? AND (expires IS NULL OR expires > ?)
+            ORDER BY share_id",
+        )?;
+
+        let mut rows = st.query([user_id, now])?;
+
+        let mut shares = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            shares.push(self._get_share(row)?);
+        }
+
+        Ok(shares)
+    }
+
+    pub fn delete_share(&mut self, share_id: i64) -> Result<()> {
+        trace!("delete share share_id={}", share_id);
+
+        self.conn
+            .execute("DELETE FROM Share WHERE share_id = ?", [share_id])?;
+
+        Ok(())
+    }
+
+    /// Counts a play of a share. Returns false if the share has no plays left.
+    pub fn register_share_play(&mut self, share_id: i64) -> Result<bool> {
+        trace!("register share play share_id={}", share_id);
+
+        let updated = self.conn.execute(
+            "UPDATE Share
+            SET play_count = play_count + 1
+            WHERE share_id = ? AND (max_plays IS NULL OR play_count < max_plays)",
+            [share_id],
+        )?;
+
+        Ok(updated > 0)
+    }
+
+    /// Index ids of the shared tracks, in album or playlist order.
+    pub fn share_track_ids(&self, share: &Share) -> Result<Vec<i64>> {
+        let index_conn = self.index.connection();
+
+        let (sql, id) = if let Some(track_id) = share.track_id {
+            return Ok(vec![track_id]);
+        } else if let Some(album_id) = share.album_id {
+            (
+                "SELECT track_id
+                FROM Track
+                WHERE album_id = ?
+                ORDER BY disc_number, number, title",
+                album_id,
+            )
+        } else if let Some(list_id) = share.list_id {
+            (
+                "SELECT StoreTrack.track_id
+                FROM StoreListTrack
+                INNER JOIN StoreTrack ON StoreTrack.store_track_id = StoreListTrack.store_track_id
+                WHERE StoreListTrack.list_id = ?
+                ORDER BY StoreListTrack.sort_index",
+                list_id,
+            )
+        } else {
+            return Ok(Vec::new());
+        };
+
+        let mut st = index_conn.prepare(sql)?;
+
+        let track_ids = st
+            .query_map([id], |row| row.get(0))?
+            .collect::<Result<Vec<i64>>>()?;
+
+        Ok(track_ids)
+    }
+
+    /// Key the share tokens are signed with, created on first use. Of concurrent first uses
+    /// only one creates it, the others reading it back.
+    fn share_secret(&self) -> Result<Vec<u8>> {
+        if let Some(secret) = self.setting("share_secret")?.and_then(|s| decode_hex(&s)) {
+            return Ok(secret);
+        }
+
+        self.conn.execute(
+            "INSERT OR IGNORE INTO Setting (key, value) VALUES ('share_secret', ?)",
+            [generate_token()],
+        )?;
+
+        match self.setting("share_secret")?.and_then(|s| decode_hex(&s)) {
+            Some(secret) => Ok(secret),
+            None => Err(rusqlite::Error::QueryReturnedNoRows),
+        }
+    }
+
+    /// Token granting access to a share, `unlocked` for one not needing the password.
+    pub fn share_token(&self, share: &Share, unlocked: bool) -> Result<String> {
+        let signature = sign(&self.share_secret()?, &share_message(share, unlocked))
+            .finalize()
+            .into_bytes();
+
+        Ok(format!("{}.{}", share.share_id, encode_hex(&signature)))
+    }
+
+    /// Returns the share of a token if it hasn't expired at `now`, and whether the token is
+    /// unlocked, that is the share has no password or the token was given for the password.
+    pub fn share_by_token(&self, token: &str, now: i64) -> Result<Option<(Share, bool)>> {
+        let mut parts = token.splitn(2, '.');
+
+        let share_id: Option<i64> = parts.next().and_then(|id| id.parse().ok());
+        let signature = parts.next().and_then(decode_hex);
+
+        let (share_id, signature) = match (share_id, signature) {
+            (Some(id), Some(signature)) => (id, signature),
+            _ => return Ok(None),
+        };
+
+        let share = match self.share(share_id)? {
+            Some(s) if s.expires.is_none_or(|e| e > now) => s,
+            _ => {
+                debug!("unknown or expired share {}", share_id);
+                return Ok(None);
+            }
+        };
+
+        let secret = self.share_secret()?;
+
+        for &unlocked in &[true, false] {
+            if sign(&secret, &share_message(&share, unlocked))
+                .verify_slice(&signature)
+                .is_ok()
+            {
+                let unlocked = unlocked || share.password.is_none();
+                return Ok(Some((share, unlocked)));
+            }
+        }
+
+        debug!("invalid signature for share {}", share_id);
+
+        Ok(None)
+    }
+
+    /// Token for a play of a shared track started through `register_share_play`, which lets
+    /// the play fetch the track as often as it needs, for example to seek, until `expires`.
+    pub fn share_play_token(&self, share: &Share, track_id: i64, expires: i64) -> Result<String> {
+        let signature = sign(
+            &self.share_secret()?,
+            &share_play_message(share, track_id, expires),
+        )
+        .finalize()
+        .into_bytes();
+
+        Ok(format!("{}.{}", expires, encode_hex(&signature)))
+    }
+
+    /// Whether `token` is a play token of the share for `track_id` that hasn't expired at
+    /// `now`.
+    pub fn verify_share_play_token(
+        &self,
+        share: &Share,
+        track_id: i64,
+        token: &str,
+        now: i64,
+    ) -> Result<bool> {
+        let mut parts = token.splitn(2, '.');
+
+        let expires: Option<i64> = parts.next().and_then(|e| e.parse().ok());
+        let signature = parts.next().and_then(decode_hex);
+
+        let (expires, signature) = match (expires, signature) {
+            (Some(expires), Some(signature)) if expires > now => (expires, signature),
+            _ => return Ok(false),
+        };
+
+        Ok(sign(
+            &self.share_secret()?,
+            &share_play_message(share, track_id, expires),
+        )
+        .verify_slice(&signature)
+        .is_ok())
+    }
+
+    /// Returns an unlocked token for the share if `password` matches.
+    pub fn unlock_share(&self, share: &Share, password: &str) -> Result<Option<String>> {
+        match &share.password {
+            Some(hash) if !verify_password(password, hash) => Ok(None),
+            _ => Ok(Some(self.share_token(share, true)?)),
+        }
+    }
+
+    fn setting(&self, key: &str) -> Result<Option<String>> {
+        self.conn
+            .query_row("SELECT value FROM Setting WHERE key = ?", &[key], |row| {
+                row.get(0)
+            })
+            .optional()
+    }
+
+    fn set_setting(&self, key: &str, value: Option<&str>) -> Result<()> {
+        match value {
+            Some(value) => self.conn.execute(
+                "INSERT OR REPLACE INTO Setting (key, value) VALUES (?, ?)",
+                &[key, value],
+            )?,
+            None => self
+                .conn
+                .execute("DELETE FROM Setting WHERE key = ?", &[key])?,
+        };
+
+        debug!("setting {} = {:?}", key, value);
+
+        Ok(())
+    }
+
+    /// Returns the album cover patterns set with `set_cover_patterns`, which take precedence
+    /// over the configured ones.
+    pub fn cover_patterns(&self) -> Result<Option<Vec<String>>> {
+        trace!("get cover patterns");
+
+        Ok(self
+            .setting("cover_patterns")?
+            .and_then(|value| match serde_json::from_str(&value) {
+                Ok(patterns) => Some(patterns),
+                Err(e) => {
+                    warn!("invalid cover patterns '{}': {}", value, e);
+                    None
+                }
+            }))
+    }
+
+    /// Sets the album cover patterns, or reverts to the configured ones if None. Albums keep
+    /// their covers until `Index::update_album_images`.
+    pub fn set_cover_patterns(&mut self, patterns: Option<&[String]>) -> Result<()> {
+        trace!("set cover patterns {:?}", patterns);
+
+        let value = patterns.map(|p| serde_json::to_string(p).unwrap());
+
+        self.set_setting("cover_patterns", value.as_deref())
+    }
+
+    /// Chooses the cover of an album over the one picked by the cover patterns, or removes
+    /// the choice if `image` is None. The choice applies to the album by its name and artist,
+    /// so it survives rescans as long as the image file stays in place.
+    pub fn set_album_cover(&mut self, album: &Album, image: Option<&Image>) -> Result<()> {
+        trace!(
+            "set album cover album_id={} image_id={:?}",
+            album.album_id,
+            image.map(|i| i.image_id)
+        );
+
+        let image_node = match image {
+            Some(image) => match self.index.node(image.node_id)? {
+                Some(node) => Some((node, image.stream_index)),
+                None => return Ok(()),
+            },
+            None => None,
+        };
+
+        let tx = self.conn.transaction()?;
+
+        tx.execute(
+            "DELETE FROM AlbumCover WHERE album_name = ? AND artist_name IS ?",
+            params![album.name, album.artist_name],
+        )?;
+
+        if let Some((node, stream_index)) = &image_node {
+            tx.execute(
+                "INSERT INTO AlbumCover (album_name, artist_name, image_path, stream_index)
+                VALUES (?, ?, ?, ?)",
+                params![
+                    album.name,
+                    album.artist_name,
+                    node.path.as_os_str().as_bytes(),
+                    stream_index
+                ],
+            )?;
+        }
+
+        tx.commit()?;
+
+        self.synchronize_album_cover(&album.name, album.artist_name.as_deref())
+    }
+
+    /// Copies the chosen cover of the albums named `album_name` by `artist_name` to the index
+    /// and updates their covers.
+    fn synchronize_album_cover(&self, album_name: &str, artist_name: Option<&str>) -> Result<()> {
+        trace!(
+            "synchronize album cover album_name={} artist_name={:?}",
+            album_name,
+            artist_name
+        );
+
+        let index_conn = self.index.connection();
+
+        index_conn.execute(
+            "DELETE FROM StoreAlbumCover
+            WHERE album_id IN (SELECT album_id FROM Album WHERE name = ? AND artist_name IS ?)",
+            params![album_name, artist_name],
+        )?;
+
+        let cover: Option<(Vec<u8>, Option<i64>)> = self
+            .conn
+            .query_row(
+                "SELECT image_path, stream_index
+                FROM AlbumCover
+                WHERE album_name = ? AND artist_name IS ?",
+                params![album_name, artist_name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        if let Some((image_path, stream_index)) = cover {
+            index_conn.execute(
+                "INSERT OR IGNORE INTO StoreAlbumCover (album_id, image_id)
+                SELECT Album.album_id, Image.image_id
+                FROM Album, Image
+                INNER JOIN Node ON Node.node_id = Image.node_id
+                WHERE
+                    Album.name = ? AND
+                    Album.artist_name IS ? AND
+                    Node.path = ? AND
+                    Image.stream_index IS ?",
+                params![album_name, artist_name, image_path, stream_index],
+            )?;
+        }
+
+        let mut st =
+            index_conn.prepare("SELECT album_id FROM Album WHERE name = ? AND artist_name IS ?")?;
+
+        let album_ids = st
+            .query_map(params![album_name, artist_name], |row| row.get(0))?
+            .collect::<Result<Vec<i64>>>()?;
+
+        for album_id in album_ids {
+            self.index.update_album_images(Some(album_id))?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies a list to the index, where its tracks can be queried. Entries whose tracks
+    /// aren't in the index are left out until a later synchronization finds them.
+    fn synchronize_list(&self, list_id: i64) -> Result<()> {
+        trace!("synchronize list list_id={}", list_id);
+
+        let index_conn = self.index.connection();
+
+        index_conn.execute("DELETE FROM StoreList WHERE list_id = ?", [list_id])?;
+
+        let list = match self.list(list_id)? {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+
+        index_conn.execute(
+            "INSERT INTO StoreList (list_id, name, user_id) VALUES (?, ?, ?)",
+            params![list.list_id, list.name, list.user_id],
+        )?;
+
+        let mut st = self.conn.prepare(
+            "SELECT store_track_id, sort_index
+            FROM ListTrack
+            WHERE list_id = ?",
+        )?;
+
+        let mut rows = st.query([list_id])?;
+
+        let mut st = index_conn.prepare(
+            "INSERT INTO StoreListTrack (list_id, store_track_id, sort_index)
+            SELECT ?, store_track_id, ?
+            FROM StoreTrack
+            WHERE store_track_id = ?",
+        )?;
+
+        while let Some(row) = rows.next()? {
+            let store_track_id: i64 = row.get(0)?;
+            let sort_index: Option<i64> = row.get(1)?;
+
+            st.execute(params![list_id, sort_index, store_track_id])?;
+        }
+
+        Ok(())
+    }
+}
 
-    //     //store_tx.commit()?;
+#[test]
+fn test_share_signature() {
+    let mut share = Share {
+        share_id: 1,
+        user_id: 1,
+        track_id: Some(1),
+        album_id: None,
+        list_id: None,
+        recipient_id: None,
+        has_password: false,
+        created: 0,
+        expires: None,
+        max_plays: None,
+        play_count: 0,
+        password: None,
+    };
 
-    //     Ok(())
-    // }
+    assert_eq!(share_message(&share, true), share_message(&share, false));
 
-    // pub fn create_list(&mut self, name: &str) -> Result<i64> {
-    //     self.conn.execute("INSERT INTO List (name) VALUES (?)", &[name])?;
+    share.password = Some("hash".to_string());
+    assert_ne!(share_message(&share, true), share_message(&share, false));
 
-    //     let list_id = self.conn.last_insert_rowid();
+    let signature = sign(b"secret", &share_message(&share, true))
+        .finalize()
+        .into_bytes();
+    let hex = encode_hex(&signature);
 
-    //     self.index.connection().execute("INSERT INTO StoreList (list_id, name) VALUES (?, ?)", params![list_id, name])?;
+    assert!(sign(b"secret", &share_message(&share, true))
+        .verify_slice(&decode_hex(&hex).unwrap())
+        .is_ok());
+    assert!(sign(b"secret", &share_message(&share, false))
+        .verify_slice(&decode_hex(&hex).unwrap())
+        .is_err());
 
-    //     Ok(list_id)
-    // }
+    assert_ne!(
+        share_play_message(&share, 1, 100),
+        share_play_message(&share, 2, 100)
+    );
+    assert_ne!(
+        share_play_message(&share, 1, 100),
+        share_play_message(&share, 1, 200)
+    );
 }
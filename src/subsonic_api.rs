@@ -0,0 +1,739 @@
+use std::collections::HashMap;
+
+use hyper::{Body, Response};
+use serde_json::{json, Value};
+
+use crate::audio_stream;
+use crate::http_api::{self, ApiRequest, Error};
+use crate::http_util::{decode_hex, escape_html, HttpQuery};
+use crate::query::{self, AlbumItem, ArtistItem, TrackItem};
+use crate::store::{Role, User};
+use crate::MUSICD_VERSION;
+
+/// Subsonic REST API version this implementation follows.
+const API_VERSION: &str = "1.16.1";
+
+const ERROR_GENERIC: i64 = 0;
+const ERROR_MISSING_PARAMETER: i64 = 10;
+const ERROR_WRONG_CREDENTIALS: i64 = 40;
+const ERROR_NOT_AUTHORIZED: i64 = 50;
+const ERROR_NOT_FOUND: i64 = 70;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Format {
+    Xml,
+    Json,
+}
+
+pub async fn process_request(mut r: ApiRequest) -> Result<Response<Body>, hyper::Error> {
+    let format = match r.query.get_str("f") {
+        Some("json") => Format::Json,
+        _ => Format::Xml,
+    };
+
+    let path = r.request.uri().path();
+    let method = path["/rest/".len()..].trim_end_matches(".view").to_string();
+
+    match check_auth(&r).await {
+        Ok(user) => r.user = Some(user),
+        Err(code) => {
+            return Ok(failed(
+                format,
+                code,
+                match code {
+                    ERROR_MISSING_PARAMETER => "Required parameter is missing",
+                    ERROR_NOT_AUTHORIZED => "User is not authorized for the given operation",
+                    ERROR_GENERIC => "Internal error",
+                    _ => "Wrong username or password",
+                },
+            ));
+        }
+    }
+
+    let result = match method.as_str() {
+        "ping" => Ok(ok(format, json!({}))),
+        "getMusicFolders" => rest_get_music_folders(&r, format),
+        "getIndexes" => rest_get_indexes(&r, format),
+        "getArtists" => rest_get_artists(&r, format),
+        "getArtist" => rest_get_artist(&r, format),
+        "getAlbum" => rest_get_album(&r, format),
+        "getSong" => rest_get_song(&r, format),
+        "search3" => rest_search3(&r, format),
+        "getLyrics" => rest_get_lyrics(&r, format).await,
+        "stream" => rest_stream(&mut r, format),
+        "getCoverArt" => rest_get_cover_art(&mut r, format),
+        _ => Ok(failed(format, ERROR_NOT_FOUND, "Unknown method")),
+    };
+
+    match result {
+        Ok(res) => Ok(res),
+        Err(e) => {
+            error!("subsonic {} failed: {}", method, e);
+            Ok(failed(format, ERROR_GENERIC, "Internal error"))
+        }
+    }
+}
+
+/// Checks the `t`/`s` token pair or the plain `p` password (optionally `enc:` hex encoded) of
+/// user `u`. Tokens are made from the Subsonic password of the user, which `p` can also be
+/// besides the login password. Authentication is skipped with the default user if the server
+/// password is empty.
+async fn check_auth(r: &ApiRequest) -> Result<User, i64> {
+    let user = if r.musicd.password.is_empty() {
+        r.musicd.store().default_user()
+    } else {
+        let name = match r.query.get_str("u") {
+            Some(u) => u,
+            None => return Err(ERROR_MISSING_PARAMETER),
+        };
+
+        match (
+            r.query.get_str("t"),
+            r.query.get_str("s"),
+            r.query.get_str("p"),
+        ) {
+            (Some(t), Some(s), _) => r.musicd.store().authenticate_subsonic_token(name, t, s),
+            (_, _, Some(p)) => {
+                let p = if let Some(hex) = p.strip_prefix("enc:") {
+                    match decode_hex(hex).and_then(|p| String::from_utf8(p).ok()) {
+                        Some(p) => p,
+                        None => return Err(ERROR_WRONG_CREDENTIALS),
+                    }
+                } else {
+                    p.to_string()
+                };
+
+                authenticate_password(r, name, p).await
+            }
+            _ => return Err(ERROR_MISSING_PARAMETER),
+        }
+    };
+
+    match user {
+        Ok(Some(user)) if user.role == Role::ShareOnly => Err(ERROR_NOT_AUTHORIZED),
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(ERROR_WRONG_CREDENTIALS),
+        Err(e) => {
+            error!("subsonic authentication failed: {}", e);
+            Err(ERROR_GENERIC)
+        }
+    }
+}
+
+/// Checks `password` against the Subsonic password of user `name`, then the login password.
+/// Verifying the login password is slow by design, so it's done on the blocking pool.
+async fn authenticate_password(
+    r: &ApiRequest,
+    name: &str,
+    password: String,
+) -> rusqlite::Result<Option<User>> {
+    if let Some(user) = r
+        .musicd
+        .store()
+        .authenticate_subsonic_password(name, &password)?
+    {
+        return Ok(Some(user));
+    }
+
+    let musicd = r.musicd.clone();
+    let name = name.to_string();
+
+    tokio::task::spawn_blocking(move || musicd.store().authenticate(&name, &password))
+        .await
+        .unwrap_or_else(|e| {
+            error!("password verification failed: {}", e);
+            Ok(None)
+        })
+}
+
+fn response(format: Format, status: &str, mut content: Value) -> Response<Body> {
+    let root = content.as_object_mut().unwrap();
+    root.insert("status".to_string(), json!(status));
+    root.insert("version".to_string(), json!(API_VERSION));
+    root.insert("type".to_string(), json!("musicd2"));
+    root.insert("serverVersion".to_string(), json!(MUSICD_VERSION));
+    root.insert("openSubsonic".to_string(), json!(true));
+
+    match format {
+        Format::Json => http_api::json_ok(&json!({ "subsonic-response": content }).to_string()),
+        Format::Xml => {
+            root.insert("xmlns".to_string(), json!("http://subsonic.org/restapi"));
+
+            let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+            write_xml_element(&mut xml, "subsonic-response", &content);
+
+            Response::builder()
+                .header("Content-Type", "text/xml; charset=utf-8")
+                .body(xml.into())
+                .unwrap()
+        }
+    }
+}
+
+fn ok(format: Format, content: Value) -> Response<Body> {
+    response(format, "ok", content)
+}
+
+fn failed(format: Format, code: i64, message: &str) -> Response<Body> {
+    response(
+        format,
+        "failed",
+        json!({
+            "error": {
+                "code": code,
+                "message": message,
+            }
+        }),
+    )
+}
+
+fn xml_scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Converts the JSON form of a response into the XML form. Scalar fields become attributes,
+/// objects become child elements and arrays become repeated child elements. A field named
+/// `value` becomes the text content of the element, as in the Subsonic JSON format.
+fn write_xml_element(out: &mut String, name: &str, value: &Value) {
+    out.push('<');
+    out.push_str(name);
+
+    let map = match value {
+        Value::Object(map) => map,
+        _ => {
+            out.push('>');
+            out.push_str(&escape_html(&xml_scalar(value).unwrap_or_default()));
+            out.push_str(&format!("</{}>", name));
+            return;
+        }
+    };
+
+    for (key, value) in map {
+        if key == "value" {
+            continue;
+        }
+
+        if let Some(s) = xml_scalar(value) {
+            out.push_str(&format!(" {}=\"{}\"", key, escape_html(&s)));
+        }
+    }
+
+    let text = map.get("value").and_then(xml_scalar);
+    let has_children = map.values().any(|v| v.is_object() || v.is_array());
+
+    if text.is_none() && !has_children {
+        out.push_str("/>");
+        return;
+    }
+
+    out.push('>');
+
+    for (key, value) in map {
+        match value {
+            Value::Object(_) => write_xml_element(out, key, value),
+            Value::Array(items) => {
+                for item in items {
+                    write_xml_element(out, key, item);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(text) = text {
+        out.push_str(&escape_html(&text));
+    }
+
+    out.push_str(&format!("</{}>", name));
+}
+
+fn content_type_for(path: &str) -> (&str, &'static str) {
+    let suffix = match path.rfind('.') {
+        Some(i) => &path[i + 1..],
+        None => "",
+    };
+
+    let content_type = match suffix.to_ascii_lowercase().as_str() {
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "m4a" | "mp4" | "aac" => "audio/mp4",
+        "wav" => "audio/wav",
+        "wma" => "audio/x-ms-wma",
+        _ => "application/octet-stream",
+    };
+
+    (suffix, content_type)
+}
+
+fn artist_value(artist: &ArtistItem, album_counts: &HashMap<i64, i64>) -> Value {
+    let mut value = json!({
+        "id": artist.artist_id.to_string(),
+        "name": artist.name,
+        "albumCount": album_counts.get(&artist.artist_id).cloned().unwrap_or(0),
+    });
+
+    if let Some(image_id) = artist.image_id {
+        value
+            .as_object_mut()
+            .unwrap()
+            .insert("coverArt".to_string(), json!(image_id.to_string()));
+    }
+
+    value
+}
+
+fn album_value(album: &AlbumItem) -> Value {
+    let mut value = json!({
+        "id": album.album_id.to_string(),
+        "name": album.name,
+        "songCount": album.track_count,
+    });
+
+    let map = value.as_object_mut().unwrap();
+
+    if let Some(artist_id) = album.artist_id {
+        map.insert("artistId".to_string(), json!(artist_id.to_string()));
+    }
+
+    if let Some(artist_name) = &album.artist_name {
+        map.insert("artist".to_string(), json!(artist_name));
+    }
+
+    if let Some(image_id) = album.image_id {
+        map.insert("coverArt".to_string(), json!(image_id.to_string()));
+    }
+
+    value
+}
+
+fn song_value(track: &TrackItem, image_id: Option<i64>) -> Value {
+    let (suffix, content_type) = content_type_for(&track.node_path);
+
+    let mut value = json!({
+        "id": track.track_id.to_string(),
+        "parent": track.album_id.to_string(),
+        "isDir": false,
+        "title": track.title,
+        "album": track.album_name,
+        "artist": track.artist_name,
+        "track": track.number,
+        "duration": track.length.round() as i64,
+        "albumId": track.album_id.to_string(),
+        "artistId": track.artist_id.to_string(),
+        "type": "music",
+        "suffix": suffix,
+        "contentType": content_type,
+        "path": track.node_path,
+    });
+
+    let object = value.as_object_mut().unwrap();
+
+    if let Some(image_id) = image_id {
+        object.insert("coverArt".to_string(), json!(image_id.to_string()));
+    }
+
+    if let Some(year) = track.year {
+        object.insert("year".to_string(), json!(year));
+    }
+
+    if let Some(genre) = &track.genre {
+        object.insert("genre".to_string(), json!(genre));
+    }
+
+    if let Some(disc_number) = track.disc_number {
+        object.insert("discNumber".to_string(), json!(disc_number));
+    }
+
+    value
+}
+
+/// Builds song entries, looking up the cover of each album only once.
+fn song_values(r: &ApiRequest, tracks: &[TrackItem]) -> Result<Vec<Value>, Error> {
+    let index = r.index();
+
+    let mut album_images: HashMap<i64, Option<i64>> = HashMap::new();
+    let mut result = Vec::new();
+
+    for track in tracks {
+        let image_id = match album_images.get(&track.album_id) {
+            Some(image_id) => *image_id,
+            None => {
+                let image_id = index.album(track.album_id)?.and_then(|a| a.image_id);
+                album_images.insert(track.album_id, image_id);
+                image_id
+            }
+        };
+
+        result.push(song_value(track, image_id));
+    }
+
+    Ok(result)
+}
+
+fn album_counts(r: &ApiRequest) -> Result<HashMap<i64, i64>, Error> {
+    let index = r.index();
+
+    let (root_filter, values) = match query::root_node_ids(&index) {
+        Some((node_ids, values)) => (
+            format!(
+                "AND album_id IN (SELECT Track.album_id FROM Track WHERE Track.node_id IN ({}))",
+                node_ids
+            ),
+            values,
+        ),
+        None => (String::new(), Vec::new()),
+    };
+
+    let mut st = index.connection().prepare(&format!(
+        "SELECT artist_id, COUNT(album_id)
+        FROM Album
+        WHERE artist_id IS NOT NULL {}
+        GROUP BY artist_id",
+        root_filter
+    ))?;
+
+    let mut rows = st.query(&values)?;
+    let mut result = HashMap::new();
+
+    while let Some(row) = rows.next()? {
+        result.insert(row.get(0)?, row.get(1)?);
+    }
+
+    Ok(result)
+}
+
+/// Groups artists by the first letter of their name, as used by `getIndexes` and
+/// `getArtists`.
+fn artist_index(r: &ApiRequest) -> Result<Vec<Value>, Error> {
+    let (_, artists) = query::query_artists(&r.index(), &HttpQuery::new())?;
+    let album_counts = album_counts(r)?;
+
+    let mut groups: Vec<(String, Vec<Value>)> = Vec::new();
+
+    for artist in artists.iter().filter(|a| !a.name.is_empty()) {
+        let first = artist
+            .name
+            .chars()
+            .next()
+            .unwrap()
+            .to_uppercase()
+            .to_string();
+        let key = if first.chars().all(char::is_alphabetic) {
+            first
+        } else {
+            "#".to_string()
+        };
+
+        match groups.iter_mut().find(|g| g.0 == key) {
+            Some(group) => group.1.push(artist_value(artist, &album_counts)),
+            None => groups.push((key, vec![artist_value(artist, &album_counts)])),
+        }
+    }
+
+    groups.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(groups
+        .into_iter()
+        .map(|(name, artists)| json!({ "name": name, "artist": artists }))
+        .collect())
+}
+
+fn get_id(r: &ApiRequest) -> Option<i64> {
+    r.query.get_i64("id")
+}
+
+fn rest_get_music_folders(r: &ApiRequest, format: Format) -> Result<Response<Body>, Error> {
+    let folders: Vec<Value> = r
+        .index()
+        .roots()
+        .iter()
+        .enumerate()
+        .map(|(i, root)| json!({ "id": i + 1, "name": root.name }))
+        .collect();
+
+    Ok(ok(
+        format,
+        json!({
+            "musicFolders": {
+                "musicFolder": folders
+            }
+        }),
+    ))
+}
+
+fn rest_get_indexes(r: &ApiRequest, format: Format) -> Result<Response<Body>, Error> {
+    Ok(ok(
+        format,
+        json!({
+            "indexes": {
+                "lastModified": 0,
+                "ignoredArticles": "",
+                "index": artist_index(r)?
+            }
+        }),
+    ))
+}
+
+fn rest_get_artists(r: &ApiRequest, format: Format) -> Result<Response<Body>, Error> {
+    Ok(ok(
+        format,
+        json!({
+            "artists": {
+                "ignoredArticles": "",
+                "index": artist_index(r)?
+            }
+        }),
+    ))
+}
+
+fn rest_get_artist(r: &ApiRequest, format: Format) -> Result<Response<Body>, Error> {
+    let artist_id = match get_id(r) {
+        Some(id) => id,
+        None => return Ok(failed(format, ERROR_MISSING_PARAMETER, "Missing id")),
+    };
+
+    let index = r.index();
+
+    let mut query = HttpQuery::new();
+    query.set("artist_id", &artist_id.to_string());
+
+    let (_, artists) = query::query_artists(&index, &query)?;
+    let artist = match artists.first() {
+        Some(a) => a,
+        None => return Ok(failed(format, ERROR_NOT_FOUND, "Artist not found")),
+    };
+
+    let (_, albums) = query::query_albums(&index, &query)?;
+
+    let mut value = artist_value(artist, &album_counts(r)?);
+    value.as_object_mut().unwrap().insert(
+        "album".to_string(),
+        Value::Array(albums.iter().map(album_value).collect()),
+    );
+
+    Ok(ok(format, json!({ "artist": value })))
+}
+
+fn rest_get_album(r: &ApiRequest, format: Format) -> Result<Response<Body>, Error> {
+    let album_id = match get_id(r) {
+        Some(id) => id,
+        None => return Ok(failed(format, ERROR_MISSING_PARAMETER, "Missing id")),
+    };
+
+    let index = r.index();
+
+    let mut query = HttpQuery::new();
+    query.set("album_id", &album_id.to_string());
+
+    let (_, albums) = query::query_albums(&index, &query)?;
+    let album = match albums.first() {
+        Some(a) => a,
+        None => return Ok(failed(format, ERROR_NOT_FOUND, "Album not found")),
+    };
+
+    let (_, tracks) = query::query_tracks(&index, &query)?;
+
+    let duration: f64 = tracks.iter().map(|t| t.length).sum();
+
+    let mut value = album_value(album);
+    let map = value.as_object_mut().unwrap();
+    map.insert("duration".to_string(), json!(duration.round() as i64));
+    map.insert(
+        "song".to_string(),
+        Value::Array(
+            tracks
+                .iter()
+                .map(|t| song_value(t, album.image_id))
+                .collect(),
+        ),
+    );
+
+    Ok(ok(format, json!({ "album": value })))
+}
+
+fn rest_get_song(r: &ApiRequest, format: Format) -> Result<Response<Body>, Error> {
+    let track_id = match get_id(r) {
+        Some(id) => id,
+        None => return Ok(failed(format, ERROR_MISSING_PARAMETER, "Missing id")),
+    };
+
+    let mut query = HttpQuery::new();
+    query.set("track_id", &track_id.to_string());
+
+    let (_, tracks) = query::query_tracks(&r.index(), &query)?;
+    if tracks.is_empty() {
+        return Ok(failed(format, ERROR_NOT_FOUND, "Song not found"));
+    }
+
+    let mut songs = song_values(r, &tracks)?;
+
+    Ok(ok(format, json!({ "song": songs.remove(0) })))
+}
+
+fn rest_search3(r: &ApiRequest, format: Format) -> Result<Response<Body>, Error> {
+    // Clients commonly send `""` to list everything
+    let search = r
+        .query
+        .get_str("query")
+        .unwrap_or_default()
+        .trim_matches('"');
+
+    let range_query = |search: &str, count_key: &str, offset_key: &str| {
+        let mut query = HttpQuery::new();
+        query.set("search", search);
+        query.set(
+            "limit",
+            &r.query.get_i64(count_key).unwrap_or(20).to_string(),
+        );
+        query.set(
+            "offset",
+            &r.query.get_i64(offset_key).unwrap_or(0).to_string(),
+        );
+        query
+    };
+
+    let index = r.index();
+
+    let (_, artists) =
+        query::query_artists(&index, &range_query(search, "artistCount", "artistOffset"))?;
+    let (_, albums) =
+        query::query_albums(&index, &range_query(search, "albumCount", "albumOffset"))?;
+    let (_, tracks) = query::query_tracks(&index, &range_query(search, "songCount", "songOffset"))?;
+
+    let album_counts = album_counts(r)?;
+
+    Ok(ok(
+        format,
+        json!({
+            "searchResult3": {
+                "artist": artists.iter().map(|a| artist_value(a, &album_counts)).collect::<Vec<_>>(),
+                "album": albums.iter().map(album_value).collect::<Vec<_>>(),
+                "song": song_values(r, &tracks)?,
+            }
+        }),
+    ))
+}
+
+async fn rest_get_lyrics(r: &ApiRequest, format: Format) -> Result<Response<Body>, Error> {
+    let mut query = HttpQuery::new();
+    query.set("limit", "1");
+
+    if let Some(artist) = r.query.get_str("artist") {
+        query.set("artist_name", artist);
+    }
+
+    if let Some(title) = r.query.get_str("title") {
+        query.set("title", title);
+    }
+
+    let (track, fs_path) = {
+        let index = r.index();
+
+        let (_, tracks) = query::query_tracks(&index, &query)?;
+        let track = match tracks.first() {
+            Some(t) => index.track(t.track_id)?,
+            None => None,
+        };
+
+        let fs_path = match &track {
+            Some(t) => index.track_fs_path(t)?,
+            None => None,
+        };
+
+        (track, fs_path)
+    };
+
+    let (track, fs_path) = match (track, fs_path) {
+        (Some(t), Some(p)) => (t, p),
+        _ => return Ok(ok(format, json!({ "lyrics": {} }))),
+    };
+
+    let lyrics = match r.index().track_lyrics(track.track_id)? {
+        Some(l) => Some(l),
+        None => http_api::fetch_track_lyrics(&r.musicd, &track, &fs_path).await?,
+    };
+
+    Ok(ok(
+        format,
+        json!({
+            "lyrics": {
+                "artist": track.artist_name,
+                "title": track.title,
+                "value": lyrics.and_then(|l| l.lyrics).unwrap_or_default(),
+            }
+        }),
+    ))
+}
+
+fn rest_stream(r: &mut ApiRequest, format: Format) -> Result<Response<Body>, Error> {
+    let track_id = match get_id(r) {
+        Some(id) => id,
+        None => return Ok(failed(format, ERROR_MISSING_PARAMETER, "Missing id")),
+    };
+
+    let mut query = HttpQuery::new();
+    query.set("track_id", &track_id.to_string());
+
+    if let Some(format) = r.query.get_str("format") {
+        if r.musicd.config.profile(format).is_some() {
+            query.set("profile", format);
+        } else if audio_stream::find_codec(format).is_some() {
+            query.set("codec", format);
+        }
+    }
+
+    if let Some(start) = r.query.get_i64("timeOffset") {
+        query.set("start", &start.to_string());
+    }
+
+    r.query = query;
+
+    http_api::api_audio_stream(r)
+}
+
+fn rest_get_cover_art(r: &mut ApiRequest, format: Format) -> Result<Response<Body>, Error> {
+    let image_id = match get_id(r) {
+        Some(id) => id,
+        None => return Ok(failed(format, ERROR_MISSING_PARAMETER, "Missing id")),
+    };
+
+    let mut query = HttpQuery::new();
+    query.set("image_id", &image_id.to_string());
+
+    if let Some(size) = r.query.get_i64("size") {
+        query.set("size", &size.to_string());
+    }
+
+    r.query = query;
+
+    http_api::api_image_file(r)
+}
+
+#[test]
+fn test_write_xml_element() {
+    let mut xml = String::new();
+
+    write_xml_element(
+        &mut xml,
+        "subsonic-response",
+        &json!({
+            "status": "ok",
+            "album": { "id": "1", "name": "A & B", "song": [{ "id": "2" }, { "id": "3" }] },
+            "lyrics": { "artist": "C", "value": "<text>" },
+        }),
+    );
+
+    assert_eq!(
+        xml,
+        "<subsonic-response status=\"ok\">\
+        <album id=\"1\" name=\"A &amp; B\"><song id=\"2\"/><song id=\"3\"/></album>\
+        <lyrics artist=\"C\">&lt;text&gt;</lyrics>\
+        </subsonic-response>"
+    );
+}
@@ -0,0 +1,254 @@
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use inotify::{Event, EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::Musicd;
+
+/// Changes are collected until there have been none for this long, so that copying an album
+/// results in a single scan
+const DEBOUNCE: Duration = Duration::from_secs(3);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Interval of full scans when the watch limit has been hit and some directories can't be
+/// watched
+const FALLBACK_SCAN_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+struct Watch {
+    /// Index path, beginning with the root name
+    path: PathBuf,
+    fs_path: PathBuf,
+}
+
+struct Watcher {
+    musicd: Arc<Musicd>,
+    inotify: Inotify,
+    watches: HashMap<WatchDescriptor, Watch>,
+    /// Set when the inotify watch limit has been hit
+    limited: bool,
+    changed: BTreeSet<PathBuf>,
+    full_scan: bool,
+    last_event: Instant,
+    last_full_scan: Instant,
+}
+
+/// Starts watching all roots for changes in a background thread, scanning changed directories
+/// as needed.
+pub fn start(musicd: Arc<Musicd>) {
+    let inotify = match Inotify::init() {
+        Ok(i) => i,
+        Err(e) => {
+            error!("can't initialize inotify: {}", e);
+            return;
+        }
+    };
+
+    std::thread::spawn(move || {
+        let mut watcher = Watcher {
+            musicd,
+            inotify,
+            watches: HashMap::new(),
+            limited: false,
+            changed: BTreeSet::new(),
+            full_scan: false,
+            last_event: Instant::now(),
+            last_full_scan: Instant::now(),
+        };
+
+        watcher.run();
+    });
+}
+
+impl Watcher {
+    fn run(&mut self) {
+        let roots: Vec<(PathBuf, PathBuf)> = self
+            .musicd
+            .index()
+            .roots()
+            .iter()
+            .map(|r| (PathBuf::from(&r.name), r.path.to_path_buf()))
+            .collect();
+
+        for (path, fs_path) in roots {
+            self.add_watches(&path, &fs_path);
+        }
+
+        info!("watching {} directories", self.watches.len());
+
+        let mut buffer = [0u8; 4096];
+
+        loop {
+            let events = match self.inotify.read_events(&mut buffer) {
+                Ok(e) => e,
+                Err(e) => {
+                    error!("can't read events, stopping: {}", e);
+                    return;
+                }
+            };
+
+            let mut received = false;
+
+            for event in events {
+                self.process_event(&event);
+                received = true;
+            }
+
+            if received {
+                continue;
+            }
+
+            self.process_changes();
+
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn add_watches(&mut self, path: &Path, fs_path: &Path) {
+        if self.limited {
+            return;
+        }
+
+        let mask = WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::ATTRIB
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::ONLYDIR;
+
+        match self.inotify.add_watch(fs_path, mask) {
+            Ok(wd) => {
+                self.watches.insert(
+                    wd,
+                    Watch {
+                        path: path.to_path_buf(),
+                        fs_path: fs_path.to_path_buf(),
+                    },
+                );
+            }
+            Err(e) => {
+                if e.raw_os_error() == Some(libc::ENOSPC) {
+                    warn!(
+                        "watch limit hit after {} directories, falling back to full scans, raise fs.inotify.max_user_watches to avoid",
+                        self.watches.len()
+                    );
+
+                    self.limited = true;
+                } else {
+                    error!("can't watch '{}': {}", fs_path.to_string_lossy(), e);
+                }
+
+                return;
+            }
+        }
+
+        let entries = match fs::read_dir(fs_path) {
+            Ok(e) => e,
+            Err(e) => {
+                error!("can't read '{}': {}", fs_path.to_string_lossy(), e);
+                return;
+            }
+        };
+
+        for entry in entries.flatten() {
+            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                self.add_watches(&path.join(entry.file_name()), &entry.path());
+            }
+        }
+    }
+
+    fn remove_watches(&mut self, path: &Path) {
+        let descriptors: Vec<WatchDescriptor> = self
+            .watches
+            .iter()
+            .filter(|(_, w)| w.path.starts_with(path))
+            .map(|(wd, _)| wd.clone())
+            .collect();
+
+        for wd in descriptors {
+            self.watches.remove(&wd);
+            let _ = self.inotify.rm_watch(wd);
+        }
+    }
+
+    fn process_event(&mut self, event: &Event<&OsStr>) {
+        self.last_event = Instant::now();
+
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            warn!("event queue overflow, changes lost, queueing full scan");
+            self.full_scan = true;
+            return;
+        }
+
+        if event.mask.contains(EventMask::IGNORED) {
+            self.watches.remove(&event.wd);
+            return;
+        }
+
+        let (path, fs_path) = match (self.watches.get(&event.wd), event.name) {
+            (Some(watch), Some(name)) => (watch.path.join(name), watch.fs_path.join(name)),
+            _ => return,
+        };
+
+        trace!("{:?} '{}'", event.mask, path.to_string_lossy());
+
+        if event.mask.contains(EventMask::ISDIR) {
+            if event
+                .mask
+                .intersects(EventMask::DELETE | EventMask::MOVED_FROM)
+            {
+                self.remove_watches(&path);
+            } else if event
+                .mask
+                .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+            {
+                self.add_watches(&path, &fs_path);
+            }
+        }
+
+        self.changed.insert(path);
+    }
+
+    fn process_changes(&mut self) {
+        if self.limited && self.last_full_scan.elapsed() >= FALLBACK_SCAN_INTERVAL {
+            self.full_scan = true;
+        }
+
+        if !self.full_scan && self.changed.is_empty() {
+            return;
+        }
+
+        if self.last_event.elapsed() < DEBOUNCE || self.musicd.scan_thread.is_running() {
+            return;
+        }
+
+        if self.full_scan {
+            if self
+                .musicd
+                .scan_thread
+                .start(self.musicd.index(), self.musicd.store())
+            {
+                self.full_scan = false;
+                self.changed.clear();
+                self.last_full_scan = Instant::now();
+            }
+        } else {
+            debug!("{} changed paths, scanning", self.changed.len());
+
+            let paths: Vec<PathBuf> = self.changed.iter().cloned().collect();
+
+            if self
+                .musicd
+                .scan_thread
+                .start_paths(self.musicd.index(), self.musicd.store(), paths)
+            {
+                self.changed.clear();
+            }
+        }
+    }
+}